@@ -0,0 +1,64 @@
+/*
+ * Runtime linked into native Kaleidoscope executables, providing the library functions that the
+ * JIT otherwise finds in the compiler itself.
+ */
+#include <stdio.h>
+#include <stdlib.h>
+
+double putchard(double x) {
+  putchar((char) x);
+  fflush(stdout);
+  return x;
+}
+
+/* Prints the shortest decimal form that reads back as `x`, like the JIT's `printd`. */
+double printd(double x) {
+  char buf[32];
+
+  for (int precision = 1; precision <= 17; precision++) {
+    snprintf(buf, sizeof buf, "%.*g", precision, x);
+    if (strtod(buf, NULL) == x) {
+      break;
+    }
+  }
+
+  puts(buf);
+  return x;
+}
+
+/* Prints a string literal. */
+double printstr(const char *str) {
+  fputs(str, stdout);
+  fflush(stdout);
+  return 0;
+}
+
+/* Reports an out-of-bounds `a[i]`, as the JIT's `index_error` does, and aborts: there is no caller
+   to hand the error to. */
+void __kaleidoscope_index_error(double index, double len) {
+  fprintf(stderr, "runtime error: index %g is out of bounds for an array of length %g\n", index, len);
+  abort();
+}
+
+/* Reports an integer division by zero, or one that overflows, as the JIT's `division_error` does,
+   and aborts. */
+void __kaleidoscope_division_error(long long lhs, long long rhs) {
+  if (rhs == 0)
+    fprintf(stderr, "runtime error: integer division of %lld by zero\n", lhs);
+  else
+    fprintf(stderr, "runtime error: integer division of %lld by %lld overflows\n", lhs, rhs);
+  abort();
+}
+
+/* Reports an `alloc` that can't be satisfied, as the JIT's `length_error` does, and aborts. */
+void __kaleidoscope_length_error(double len) {
+  fprintf(stderr, "runtime error: cannot allocate an array of length %g\n", len);
+  abort();
+}
+
+/* Reports a struct or closure record that can't be allocated, as the JIT's `alloc_error` does, and
+   aborts. */
+void __kaleidoscope_alloc_error(double size) {
+  fprintf(stderr, "runtime error: cannot allocate %g bytes\n", size);
+  abort();
+}
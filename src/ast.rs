@@ -1,25 +1,215 @@
 pub type Name = String;
 
-#[derive(Clone, Debug,PartialEq)]
-pub enum Expr {
+// Byte range of the source text an expression was parsed from.
+//
+// Spans never take part in equality, so trees built by hand compare equal to parsed ones.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Span {
+  pub start: usize,
+  pub end: usize
+}
+
+impl PartialEq for Span {
+  fn eq(&self, _other: &Span) -> bool {
+    true
+  }
+}
+
+impl Span {
+  pub fn new(start: usize, end: usize) -> Span {
+    Span { start, end }
+  }
+
+  // Smallest span covering both `self` and `other`.
+  pub fn to(self, other: Span) -> Span {
+    Span::new(self.start.min(other.start), self.end.max(other.end))
+  }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Expr {
+  pub kind: ExprKind,
+  pub span: Span
+}
+
+impl Expr {
+  pub fn new(kind: ExprKind, span: Span) -> Expr {
+    Expr { kind, span }
+  }
+
+  // Calls `f` on this expression, then on each of its subexpressions, depth first and in source
+  // order. Type inference lists the types of a definition's expressions in this order.
+  pub fn walk<'a>(&'a self, f: &mut dyn FnMut(&'a Expr)) {
+    f(self);
+
+    match &self.kind {
+      ExprKind::Float(_) | ExprKind::Str(_) | ExprKind::Var(_) | ExprKind::Struct(..) | ExprKind::Extern(..) => {},
+      ExprKind::BinOp(_, left, right) | ExprKind::While(left, right) | ExprKind::Index(left, right) => {
+        left.walk(f);
+        right.walk(f);
+      },
+      ExprKind::UnaryOp(_, operand) | ExprKind::Alloc(operand) | ExprKind::Len(operand) | ExprKind::Field(operand, _) |
+      ExprKind::Lambda(_, operand) | ExprKind::Function(_, _, _, operand) => operand.walk(f),
+      ExprKind::Call(_, exprs) | ExprKind::Block(exprs) | ExprKind::Array(exprs) => {
+        for expr in exprs {
+          expr.walk(f);
+        }
+      },
+      ExprKind::Apply(callee, args) => {
+        callee.walk(f);
+        for arg in args {
+          arg.walk(f);
+        }
+      },
+      ExprKind::IfExpr(cond, consequence, alternative) => {
+        cond.walk(f);
+        consequence.walk(f);
+        alternative.walk(f);
+      },
+      ExprKind::ForInExpr(_, initial_val, end_cond, step, body) => {
+        initial_val.walk(f);
+        end_cond.walk(f);
+        if let Some(step) = step {
+          step.walk(f);
+        }
+        body.walk(f);
+      },
+      ExprKind::VarIn(bindings, body) => {
+        for (_, initializer) in bindings {
+          if let Some(init) = initializer {
+            init.walk(f);
+          }
+        }
+        body.walk(f);
+      },
+      ExprKind::Construct(_, values) => {
+        for (_, value) in values {
+          value.walk(f);
+        }
+      }
+    }
+  }
+}
+
+// Expressions synthesized by the compiler rather than parsed get an empty span.
+impl From<ExprKind> for Expr {
+  fn from(kind: ExprKind) -> Expr {
+    Expr::new(kind, Span::default())
+  }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ExprKind {
   Float(f64),
+  // A string literal. At runtime it is a pointer to the string's bytes, NUL-terminated.
+  Str(String),
   Var(Name),
   BinOp(Op, Box<Expr>, Box<Expr>),
+  UnaryOp(UnaryOp, Box<Expr>),
+  // `f(x)`: calls the function `f`, or, if a variable named `f` is in scope, the function value it
+  // holds. A function named on its own, as in `apply(f, x)`, is a value like any other.
   Call(Name, Program),
-  Function(Name, Vec<Name>, Box<Expr>),
+  // `e(x)`: calls the function value that `e` evaluates to, as in `pick(1)(x)`
+  Apply(Box<Expr>, Program),
+  // `\x y -> body`: an anonymous function. It captures the values that the variables of the
+  // enclosing function it uses have when it is evaluated, and can't assign to them.
+  Lambda(Vec<Name>, Box<Expr>),
+  Function(Name, Vec<Name>, Annotations, Box<Expr>),
   IfExpr(Box<Expr>, Box<Expr>, Box<Expr>),
-  ForInExpr(Name, Box<Expr>, Box<Expr>, Box<Expr>, Box<Expr>),
-  Extern(Name, Vec<Name>)
+  // `for i = start, cond, step in body`: the step is optional and defaults to 1
+  ForInExpr(Name, Box<Expr>, Box<Expr>, Option<Box<Expr>>, Box<Expr>),
+  // `while cond do body`: runs the body for as long as the condition holds, checking it first
+  While(Box<Expr>, Box<Expr>),
+  // `var a = 1, b in body`: binds mutable locals, zero-initialised unless given a value
+  VarIn(Vec<(Name, Option<Expr>)>, Box<Expr>),
+  // `{ e1; e2; ...; en }`: evaluates each expression in turn, giving the value of the last
+  Block(Program),
+  // `[e1, e2, ..., en]`: a new array holding the values of the expressions
+  Array(Program),
+  // `alloc(n)`: a new array of `n` zeros
+  Alloc(Box<Expr>),
+  // `a[i]`: the element of an array at index `i`, counting from 0, which must be in bounds. Also
+  // the destination of `a[i] = v`.
+  Index(Box<Expr>, Box<Expr>),
+  // `len(a)`: the number of elements of an array
+  Len(Box<Expr>),
+  // `Complex { re: 1, im: 0 }`: a new struct with a value for each of its fields
+  Construct(Name, Vec<(Name, Expr)>),
+  // `c.re`: a field of a struct, whichever struct `c`'s type is. Also the destination of `c.re = v`.
+  Field(Box<Expr>, Name),
+  // `struct Complex { re, im }`: declares a struct type and the names of its fields
+  Struct(Name, Vec<Name>),
+  Extern(Name, Vec<Name>, Annotations)
 }
 
 pub type Program = Vec<Expr>;
 
-#[derive(Clone, Debug,PartialEq)]
+// A type written out in an annotation.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TypeExpr {
+  // `number`, `str`, or a struct, with the types of its fields if given, as in `Pair<number, str>`
+  Named(Name, Vec<TypeExpr>),
+  // `[number]`
+  Array(Box<TypeExpr>),
+  // `(number, str) -> number`
+  Fn(Vec<TypeExpr>, Box<TypeExpr>)
+}
+
+// The types that a definition or `extern` was annotated with, as in `def f(x: number, y) -> str`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Annotations {
+  // The type of each parameter, by position, if any parameter has one
+  pub params: Vec<Option<TypeExpr>>,
+  pub ret: Option<TypeExpr>
+}
+
+impl Annotations {
+  // The type the parameter at `index` was annotated with.
+  pub fn param(&self, index: usize) -> Option<&TypeExpr> {
+    self.params.get(index).and_then(Option::as_ref)
+  }
+
+  // Every type annotated, parameters first.
+  pub fn types(&self) -> impl Iterator<Item = &TypeExpr> {
+    self.params.iter().flatten().chain(&self.ret)
+  }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum Op {
   Plus,
   Minus,
   Multiply,
   Divide,
+  // Remainder of truncated division, as C's `fmod`: it takes the sign of the left operand
+  Remainder,
+  // Comparisons evaluate to 1 or 0, or true or false as bools. All but `!=` are ordered, so they are
+  // false if either operand is NaN; `!=` is unordered and true in that case, so it is always the
+  // negation of `==`.
   LessThan,
-  GreaterThan
+  LessEqual,
+  GreaterThan,
+  GreaterEqual,
+  Equal,
+  NotEqual,
+  // Short-circuiting: the right-hand side is only evaluated if the left doesn't decide the result
+  And,
+  Or,
+  // Stores the right-hand side into the variable on the left, evaluating to the stored value
+  Assign
+}
+
+impl Op {
+  pub fn is_comparison(&self) -> bool {
+    matches!(self, Op::LessThan | Op::LessEqual | Op::GreaterThan | Op::GreaterEqual | Op::Equal | Op::NotEqual)
+  }
+}
+
+// Built-in prefix operators. Other prefix operators are calls to functions defined with
+// `def unary<op>`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum UnaryOp {
+  // Logical not: 1 if the operand is 0 (or NaN), and 0 otherwise
+  Not,
+  Negate
 }
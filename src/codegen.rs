@@ -1,20 +1,34 @@
 use inkwell::builder::Builder;
 use inkwell::context::Context;
-use inkwell::module::Module;
+use inkwell::memory_buffer::MemoryBuffer;
+use inkwell::module::{Linkage, Module};
 use inkwell::passes::PassManager;
-use inkwell::types::BasicTypeEnum;
-use inkwell::values::{BasicValue, BasicValueEnum, FloatValue, FunctionValue, PointerValue};
-use inkwell::execution_engine::{ExecutionEngine, JitFunction};
-use inkwell::{OptimizationLevel, FloatPredicate};
-
-use std::collections::HashMap;
+use inkwell::types::{BasicType, BasicTypeEnum, FunctionType, StructType};
+use inkwell::values::{BasicValue, BasicValueEnum, FloatValue, FunctionValue, IntValue, PointerValue};
+use inkwell::execution_engine::ExecutionEngine;
+use inkwell::targets::{CodeModel, FileType, InitializationConfig, RelocMode, Target, TargetMachine};
+use inkwell::{AddressSpace, FloatPredicate, IntPredicate};
+
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
-
-use crate::ast::{Expr, Name, Op, Program};
+use std::ffi::CStr;
+use std::fmt;
+use std::os::raw::c_char;
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::ast::{Expr, ExprKind, Name, Op, Program, Span, UnaryOp};
+use crate::diagnostic;
+use crate::host::{self, HostFn, HostType};
+use crate::infer::{self, ProgramTypes, Signature, Type};
+use crate::jit::{CallError, Func0, JitFn};
+use crate::optimize::OptConfig;
+use crate::sema::{self, Structs};
 
 use std::io::Write;
 
-/**
+/*
  * Library functions.
  */
 
@@ -39,10 +53,105 @@ pub extern fn printd(x: f64) -> f64 {
     x
 }
 
+/**
+ * Prints a string without a trailing newline.
+ *
+ * # Safety
+ *
+ * `s` must point to a string compiled from a literal. `extern printstr(s)` is only given strings, as
+ * its signature says.
+ */
+#[no_mangle]
+pub unsafe extern fn printstr(s: *const c_char) -> f64 {
+    print_flush!("{}", CStr::from_ptr(s).to_string_lossy());
+    0.0
+}
+
+/**
+ * An error that stopped compiled code while it ran, such as an out-of-bounds index. The code returns
+ * at once, through every function it was in, and the Rust code that called it gets the error.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct RuntimeError {
+    pub message: String
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "runtime error: {}", self.message)
+    }
+}
+
+impl Error for RuntimeError {}
+
+/**
+ * Where JIT-compiled code records the runtime error that stopped it, for the Rust code that called
+ * it. The code is given its address as a constant. The flag comes first, so that testing it after
+ * a call takes a single load.
+ */
+#[repr(C)]
+pub(crate) struct Trap {
+    raised: Cell<bool>,
+    error: RefCell<Option<RuntimeError>>
+}
+
+impl Trap {
+    pub(crate) fn new() -> Trap {
+        Trap { raised: Cell::new(false), error: RefCell::new(None) }
+    }
+
+    pub(crate) fn raise(&self, message: String) {
+        self.error.replace(Some(RuntimeError { message }));
+        self.raised.set(true);
+    }
+
+    // The outcome of a call from Rust into compiled code that returned `value`: the runtime error
+    // that stopped it instead, if any. The trap is then reset for the next call.
+    pub(crate) fn finish(&self, value: f64) -> Result<f64, RuntimeError> {
+        self.raised.set(false);
+
+        match self.error.take() {
+            Some(err) => Err(err),
+            None => Ok(value)
+        }
+    }
+}
+
+// Stops compiled code at an out-of-bounds `a[i]`, rather than let it touch memory outside the array.
+extern fn index_error(trap: *const Trap, index: f64, len: f64) {
+    let trap = unsafe { &*trap };
+    trap.raise(format!("index {} is out of bounds for an array of length {}", index, len));
+}
+
+// Stops compiled code at an integer division by zero, or one whose result doesn't fit.
+extern fn division_error(trap: *const Trap, lhs: i64, rhs: i64) {
+    let trap = unsafe { &*trap };
+    if rhs == 0 {
+        trap.raise(format!("integer division of {} by zero", lhs));
+    } else {
+        trap.raise(format!("integer division of {} by {} overflows", lhs, rhs));
+    }
+}
+
+// Stops compiled code at an `alloc` of a negative, NaN or too large length, or one that ran out of
+// memory.
+extern fn length_error(trap: *const Trap, len: f64) {
+    let trap = unsafe { &*trap };
+    trap.raise(format!("cannot allocate an array of length {}", len));
+}
+
+// Stops compiled code when a struct or closure record of `size` bytes couldn't be allocated.
+extern fn alloc_error(trap: *const Trap, size: f64) {
+    let trap = unsafe { &*trap };
+    trap.raise(format!("cannot allocate {} bytes", size));
+}
+
 // Adding the functions above to a global array,
 // so Rust compiler won't remove them.
 #[used]
 static EXTERNAL_FNS: [extern fn(f64) -> f64; 2] = [putchard, printd];
+#[used]
+static EXTERNAL_UNSAFE_FNS: [unsafe extern fn(*const c_char) -> f64; 1] = [printstr];
 
 /// Convenience type alias for functions.
 ///
@@ -50,22 +159,392 @@ static EXTERNAL_FNS: [extern fn(f64) -> f64; 2] = [putchard, printd];
 /// do `unsafe` operations internally.
 type MainFunc = unsafe extern "C" fn() -> f64;
 
-pub struct CodeGen<'a, 'ctx> {
+/**
+ * When a `for` loop checks its end condition.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LoopCheck {
+    // As in C, and the default: the condition is checked against the current value of the variable
+    // before each run of the body, which may not run at all.
+    BeforeBody,
+    // As in the LLVM tutorial: the body runs, then the condition is checked against the variable
+    // before it is incremented. The body therefore always runs at least once, and once more after
+    // the variable reaches its end, e.g. four times for `for i = 0, i < 3`.
+    AfterBody
+}
+
+/**
+ * Errors raised while compiling a program.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum CompileError {
+    UnknownVariable { name: Name, span: Span },
+    UnknownFunction { name: Name, span: Span },
+    ArityMismatch { name: Name, expected: usize, found: usize, span: Span },
+    Redefinition { name: Name, span: Span },
+    InvalidAssignment { span: Span },
+    NestedDefinition { span: Span },
+    // A definition or `extern` whose parameter count differs from an earlier declaration's
+    SignatureMismatch { name: Name, declared: usize, found: usize, span: Span },
+    // An `extern` with no registered host function, when those are required
+    UnresolvedExtern { name: Name, span: Span },
+    UnknownStruct { name: Name, span: Span },
+    // A type annotation naming neither a built-in type nor a struct
+    UnknownType { name: Name, span: Span },
+    // A type annotation giving a struct a type argument per field, but not as many as it has
+    TypeArityMismatch { name: Name, expected: usize, found: usize, span: Span },
+    // A field that the struct constructed has none of, or, without a struct, that no struct has
+    UnknownField { struct_name: Option<Name>, field: Name, span: Span },
+    // A field accessed on a value of a type other than a struct
+    FieldOfNonStruct { ty: Type, field: Name, span: Span },
+    // A field accessed on a value whose type was left open, which several structs have
+    AmbiguousField { field: Name, structs: Vec<Name>, span: Span },
+    // A field left out of a struct constructor
+    MissingField { struct_name: Name, field: Name, span: Span },
+    // A field declared or given a value twice
+    DuplicateField { field: Name, span: Span },
+    // An assignment, in a lambda, to a variable it captured
+    CapturedAssignment { name: Name, span: Span },
+    // A value used at a type it doesn't have
+    TypeMismatch { expected: Type, found: Type, span: Span },
+    // A `var` without an initial value, of a type that 0 isn't a value of
+    UninitializedVariable { name: Name, ty: Type, span: Span },
+    // An `alloc` of an array whose elements are of a type that 0 isn't a value of
+    UninitializedElements { ty: Type, span: Span },
+    // LLVM's verifier rejected the code generated for a function
+    InvalidFunction { name: Name, llvm_message: String },
+    // LLVM failed outside of any particular function, e.g. while emitting an object file
+    Backend { message: String }
+}
+
+impl CompileError {
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            CompileError::UnknownVariable { span, .. } |
+            CompileError::UnknownFunction { span, .. } |
+            CompileError::ArityMismatch { span, .. } |
+            CompileError::Redefinition { span, .. } |
+            CompileError::InvalidAssignment { span } |
+            CompileError::NestedDefinition { span } |
+            CompileError::SignatureMismatch { span, .. } |
+            CompileError::UnresolvedExtern { span, .. } |
+            CompileError::UnknownStruct { span, .. } |
+            CompileError::UnknownType { span, .. } |
+            CompileError::TypeArityMismatch { span, .. } |
+            CompileError::UnknownField { span, .. } |
+            CompileError::FieldOfNonStruct { span, .. } |
+            CompileError::AmbiguousField { span, .. } |
+            CompileError::MissingField { span, .. } |
+            CompileError::DuplicateField { span, .. } |
+            CompileError::CapturedAssignment { span, .. } |
+            CompileError::TypeMismatch { span, .. } |
+            CompileError::UninitializedVariable { span, .. } |
+            CompileError::UninitializedElements { span, .. } => Some(*span),
+            CompileError::InvalidFunction { .. } |
+            CompileError::Backend { .. } => None
+        }
+    }
+
+    // Renders the error with its location in `file`, and the offending source underlined.
+    pub fn render(&self, file: &str, source: &str) -> String {
+        match self.span() {
+            Some(span) => {
+                let (line, column) = diagnostic::line_col(source, span.start);
+                format!("{}:{}:{}: error: {}\n{}", file, line, column, self, diagnostic::excerpt(source, span))
+            },
+            None => format!("{}: error: {}", file, self)
+        }
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompileError::UnknownVariable { name, .. } => write!(f, "unknown variable `{}`", name),
+            CompileError::UnknownFunction { name, .. } => write!(f, "unknown function `{}`", name),
+            CompileError::ArityMismatch { name, expected, found, .. } =>
+                write!(f, "`{}` takes {} argument(s) but {} were given", name, expected, found),
+            CompileError::Redefinition { name, .. } => write!(f, "redefinition of `{}`", name),
+            CompileError::InvalidAssignment { .. } => write!(f, "destination of `=` must be a variable, an array element or a field"),
+            CompileError::NestedDefinition { .. } =>
+                write!(f, "functions, `extern` and `struct` declarations can only appear at the top level"),
+            CompileError::SignatureMismatch { name, declared, found, .. } =>
+                write!(f, "`{}` was declared with {} parameter(s), but has {} here", name, declared, found),
+            CompileError::UnresolvedExtern { name, .. } =>
+                write!(f, "no host function is registered for `extern {}`", name),
+            CompileError::UnknownStruct { name, .. } => write!(f, "unknown struct `{}`", name),
+            CompileError::UnknownType { name, .. } => write!(f, "unknown type `{}`", name),
+            CompileError::TypeArityMismatch { name, expected, found, .. } =>
+                write!(f, "`{}` takes {} type argument(s) but {} were given", name, expected, found),
+            CompileError::UnknownField { struct_name: Some(struct_name), field, .. } =>
+                write!(f, "struct `{}` has no field `{}`", struct_name, field),
+            CompileError::UnknownField { struct_name: None, field, .. } => write!(f, "no struct has a field `{}`", field),
+            CompileError::FieldOfNonStruct { ty, field, .. } => write!(f, "`{}` is not a struct, so it has no field `{}`", ty, field),
+            CompileError::AmbiguousField { field, structs, .. } => {
+                let mut names: Vec<String> = structs.iter().map(|name| format!("`{}`", name)).collect();
+                let last = names.pop().unwrap_or_default();
+                let names = if names.is_empty() { last } else { format!("{} or {}", names.join(", "), last) };
+                write!(f, "`.{}` may be a field of {}; annotate the type", field, names)
+            },
+            CompileError::MissingField { struct_name, field, .. } =>
+                write!(f, "missing field `{}` of struct `{}`", field, struct_name),
+            CompileError::DuplicateField { field, .. } => write!(f, "field `{}` appears more than once", field),
+            CompileError::CapturedAssignment { name, .. } =>
+                write!(f, "cannot assign to `{}`, which the lambda captured by value", name),
+            CompileError::TypeMismatch { expected, found, .. } => write!(f, "expected `{}`, found `{}`", expected, found),
+            CompileError::UninitializedVariable { name, ty, .. } =>
+                write!(f, "`{}` has type `{}`, so it must be given an initial value", name, ty),
+            CompileError::UninitializedElements { ty, .. } =>
+                write!(f, "`alloc` fills arrays with zeros, so their elements can't have type `{}`", ty),
+            CompileError::InvalidFunction { name, llvm_message } =>
+                write!(f, "invalid code generated for `{}`: {}", name, llvm_message),
+            CompileError::Backend { message } => write!(f, "{}", message)
+        }
+    }
+}
+
+impl Error for CompileError {}
+
+impl From<String> for CompileError {
+    fn from(message: String) -> CompileError {
+        CompileError::Backend { message }
+    }
+}
+
+impl From<&str> for CompileError {
+    fn from(message: &str) -> CompileError {
+        CompileError::Backend { message: message.to_string() }
+    }
+}
+
+/**
+ * A program that `CodeGen::check_program` found no errors in, along with the types inferred for it,
+ * ready for `CodeGen::compile_program`.
+ */
+pub struct CheckedProgram<'p> {
+    program: &'p Program,
+    types: ProgramTypes
+}
+
+/**
+ * A function definition, kept so that a generic one can be compiled for each instance of it.
+ */
+struct Definition {
+    name: Name,
+    params: Vec<Name>,
+    body: Expr,
+    // Types of the expressions of `body`, in `Expr::walk` order, in terms of the variables of
+    // `signature`
+    types: Vec<Type>,
+    signature: Signature,
+    // See `CodeGen::callees`
+    callees: HashMap<Name, String>
+}
+
+impl Definition {
+    // The type of each expression of the body, in the instance whose type variables take the types
+    // `args`.
+    fn expr_types(&self, args: &[Type]) -> HashMap<*const Expr, Type> {
+        let mut types = self.types.iter();
+        let mut expr_types = HashMap::new();
+
+        self.body.walk(&mut |expr| {
+            let ty = types.next().expect("every expression has an inferred type");
+            expr_types.insert(expr as *const Expr, ty.substitute(args));
+        });
+
+        expr_types
+    }
+}
+
+// The symbol of the instance of the function `symbol` whose type variables take the types `args`,
+// e.g. `first<number, str>`.
+fn instance_symbol(symbol: &str, args: &[Type]) -> String {
+    if args.is_empty() {
+        symbol.to_owned()
+    } else {
+        let args: Vec<String> = args.iter().map(Type::to_string).collect();
+        format!("{}<{}>", symbol, args.join(", "))
+    }
+}
+
+// The type of values of the host type `ty`.
+fn host_value_type(ty: HostType) -> Type {
+    match ty {
+        HostType::Number => Type::Number,
+        HostType::Int => Type::Int,
+        HostType::Bool => Type::Bool,
+        HostType::Str => Type::Str
+    }
+}
+
+// The address of the compiler's own implementation of the runtime function `name`, if `signature`
+// is that of one, as an `extern` declaring it gets. See `Signature::runtime`.
+fn runtime_fn_address(name: &str, signature: &Signature) -> Option<usize> {
+    match name {
+        "printstr" if Signature::runtime(name, 1).as_ref() == Some(signature) => Some(printstr as usize),
+        _ => None
+    }
+}
+
+// The type of the elements of arrays of type `ty`.
+fn element_type(ty: &Type) -> Type {
+    match ty {
+        Type::Array(element) => (**element).clone(),
+        _ => unreachable!("only arrays have elements")
+    }
+}
+
+pub struct CodeGen<'ctx> {
     pub context: &'ctx Context,
     pub module: Module<'ctx>,
     pub builder: Builder<'ctx>,
-    pub fpm: &'a PassManager<FunctionValue<'ctx>>,
+    pub fpm: PassManager<FunctionValue<'ctx>>,
     pub execution_engine: ExecutionEngine<'ctx>,
 
-    variables: HashMap<String, PointerValue<'ctx>>,
-    fn_value_opt: Option<FunctionValue<'ctx>>
+    variables: HashMap<String, (PointerValue<'ctx>, Type)>,
+    fn_value_opt: Option<FunctionValue<'ctx>>,
+
+    // Type of every expression of the function being compiled, in the instance being compiled
+    expr_types: HashMap<*const Expr, Type>,
+
+    // Symbol of each function that the function being compiled calls, as it was when it was defined
+    callees: HashMap<Name, String>,
+
+    // Parameters of every function declared so far in any module, so that functions from earlier
+    // modules can be declared in, and called from, later ones
+    prototypes: HashMap<Name, Vec<Name>>,
+
+    // Inferred type of every function declared so far
+    signatures: HashMap<Name, Signature>,
+
+    // Structs declared so far. See `struct_type` for their LLVM types.
+    structs: Structs,
+
+    // Number of top-level expressions compiled so far, used to name their anonymous functions
+    anon_expr_count: usize,
+
+    // Number of lambdas compiled so far, used to name their functions
+    lambda_count: usize,
+
+    // Number of modules handed to the execution engine so far, used to name new ones
+    module_count: usize,
+
+    // Rust functions that `extern` declarations are bound to, latest registration last. Shared so
+    // their addresses, which compiled thunks hold on to, never change, and so that handles from
+    // `get_fn` can keep them alive after the `CodeGen` is gone.
+    host_fns: Vec<(Name, Rc<HostFn>)>,
+
+    // Whether every `extern` must have a registered host function, rather than being left for the
+    // JIT to resolve against the symbols of the process
+    require_host_fns: bool,
+
+    // Passes run over compiled code, and the level native code is generated at
+    opt: OptConfig,
+
+    // Whether a runtime error stops compiled code and returns it to the Rust code that called it,
+    // through `trap`, as the JIT needs. Native executables leave errors to the runtime, which aborts.
+    trap_runtime_errors: bool,
+    // Shared so that handles from `get_fn` can read it after the `CodeGen` is gone
+    trap: Rc<Trap>,
+
+    // Functions given a body so far, as opposed to only declared by an `extern`
+    defined: HashSet<Name>,
+
+    // Whether a function may be defined again, replacing its body, as in the REPL. Otherwise that is
+    // a `Redefinition` error.
+    allow_redefinition: bool,
+
+    // LLVM symbol of each function's latest body, which differs from its name once redefined. Each
+    // body gets a fresh symbol, since the execution engine can't tell apart functions of the same
+    // name in different modules.
+    symbols: HashMap<Name, String>,
+
+    // Number of redefinitions so far, used to name their symbols
+    redefinition_count: usize,
+
+    // Inferred type of the function held by each symbol
+    symbol_signatures: HashMap<String, Signature>,
+
+    // Generic functions by symbol, compiled for each instance a call needs, and the instances compiled
+    // so far. See `instance_symbol`.
+    definitions: HashMap<String, Rc<Definition>>,
+    instances: HashSet<String>,
+
+    // When `for` loops check their end condition
+    loop_check: LoopCheck
 }
-impl<'a, 'ctx> CodeGen<'a, 'ctx> {
-    // Gets a defined function given its name.
-    // Directly from https://github.com/TheDan64/inkwell/blob/master/examples/kaleidoscope/main.rs
-    #[inline]
-    fn get_function(&self, name: &str) -> Option<FunctionValue<'ctx>> {
-        self.module.get_function(name)
+impl<'ctx> CodeGen<'ctx> {
+    // The LLVM symbol holding the latest body of the function `name`.
+    pub(crate) fn symbol<'a>(&'a self, name: &'a str) -> &'a str {
+        self.symbols.get(name).map_or(name, String::as_str)
+    }
+
+    // The function that a call to `name`, at the function type `ty`, goes to, or None if there is no
+    // such function. A generic function's instance for `ty` is compiled the first time one is needed,
+    // into the current module.
+    fn function(&mut self, name: &str, ty: &Type) -> Result<Option<FunctionValue<'ctx>>, CompileError> {
+        let symbol = self.callees.get(name).cloned().unwrap_or_else(|| self.symbol(name).to_owned());
+        let signature = match self.symbol_signatures.get(&symbol) {
+            Some(signature) => signature.clone(),
+            None => return Ok(None)
+        };
+
+        let args = match ty {
+            Type::Fn(params, ret) => signature.instance(params, ret),
+            _ => None
+        }.ok_or_else(|| CompileError::Backend { message: format!("`{}` has no instance of type `{}`", name, ty) })?;
+
+        let instance = instance_symbol(&symbol, &args);
+        if let Some(fun) = self.module.get_function(&instance) {
+            return Ok(Some(fun));
+        }
+
+        match self.definitions.get(&symbol).cloned() {
+            Some(definition) if !self.instances.contains(&instance) => {
+                let fun = self.compile_body(&instance, &definition, &args)?;
+                self.instances.insert(instance);
+                Ok(Some(fun))
+            },
+            // compiled already, maybe in an earlier module
+            _ => {
+                let (param_types, ret) = signature.instantiate(&args);
+                let params = self.prototypes.get(name).cloned().unwrap_or_default();
+                Ok(Some(self.declare_fn(&instance, &params, &param_types, &ret)))
+            }
+        }
+    }
+
+    // The symbols of the functions that `body` calls or uses by name, as they are now, so that an
+    // instance of it compiled later still uses those.
+    fn callees(&self, body: &Expr) -> HashMap<Name, String> {
+        let mut callees = HashMap::new();
+
+        body.walk(&mut |expr| match &expr.kind {
+            ExprKind::Call(name, _) | ExprKind::Var(name) => {
+                let symbol = self.symbol(name);
+
+                if self.symbol_signatures.contains_key(symbol) {
+                    callees.insert(name.to_owned(), symbol.to_owned());
+                }
+            },
+            _ => {}
+        });
+
+        callees
+    }
+
+    // Checks that a function or `extern` named `name` takes as many parameters as any earlier
+    // declaration says.
+    fn check_signature(&self, name: &str, params: &[Name], span: Span) -> Result<(), CompileError> {
+        let declared = self.prototypes.get(name).map(Vec::len)
+            .or_else(|| self.module.get_function(name).map(|fun| fun.count_params() as usize));
+
+        match declared {
+            Some(declared) if declared != params.len() =>
+                Err(CompileError::SignatureMismatch { name: name.to_owned(), declared, found: params.len(), span }),
+            _ => Ok(())
+        }
     }
 
     // Returns the `FunctionValue` representing the function currently being compiled.
@@ -75,9 +554,10 @@ impl<'a, 'ctx> CodeGen<'a, 'ctx> {
         self.fn_value_opt.unwrap()
     }
 
-    // Creates a new stack allocation instruction in the entry block of the function.
+    // Creates a new stack allocation instruction in the entry block of the function, for a variable
+    // of type `ty`.
     // Directly from https://github.com/TheDan64/inkwell/blob/master/examples/kaleidoscope/main.rs
-    fn create_entry_block_alloca(&self, name: &str) -> PointerValue<'ctx> {
+    fn create_entry_block_alloca(&self, name: &str, ty: &Type) -> PointerValue<'ctx> {
         let builder = self.context.create_builder();
 
         let entry = self.fn_value().get_first_basic_block().unwrap();
@@ -87,48 +567,118 @@ impl<'a, 'ctx> CodeGen<'a, 'ctx> {
             None => builder.position_at_end(entry)
         }
 
-        builder.build_alloca(self.context.f64_type(), name)
+        builder.build_alloca(self.llvm_type(ty), name)
+    }
+
+    // The type inferred for `expr`, in the instance of the function being compiled.
+    fn type_of(&self, expr: &Expr) -> Type {
+        self.expr_types.get(&(expr as *const Expr)).cloned().expect("every expression has an inferred type")
     }
 
-    fn compile_expr(&mut self, expr: &Expr) -> Result<FloatValue<'ctx>, &'static str> {
-        match &*expr {
-            Expr::Float(nb) => Ok(self.context.f64_type().const_float(*nb)),
+    fn compile_expr(&mut self, expr: &Expr) -> Result<BasicValueEnum<'ctx>, CompileError> {
+        match &expr.kind {
+            ExprKind::Float(nb) => match self.type_of(expr) {
+                Type::Int => Ok(self.context.i64_type().const_int(*nb as i64 as u64, true).into()),
+                _ => Ok(self.context.f64_type().const_float(*nb).into())
+            },
+
+            ExprKind::Str(ref value) => {
+                // a constant global holding the bytes, passed around by address
+                let global = self.builder.build_global_string_ptr(value, "str");
+
+                Ok(global.as_pointer_value().into())
+            },
 
-            Expr::Var(ref name) => {
+            ExprKind::Var(ref name) => {
                 match self.variables.get(name.as_str()) {
-                    Some(var) => Ok(self.builder.build_load(*var, name.as_str()).into_float_value()),
-                    None => Err("Could not find a matching variable.")
+                    Some((var, _)) => Ok(self.builder.build_load(*var, name.as_str())),
+                    // otherwise it names a function, whose value calls its latest body
+                    None => {
+                        let ty = self.type_of(expr);
+
+                        match self.function(name, &ty)? {
+                            Some(fun) => Ok(self.function_value(fun, &ty).into()),
+                            None => Err(CompileError::UnknownVariable { name: name.to_owned(), span: expr.span })
+                        }
+                    }
+                }
+            },
+
+            ExprKind::BinOp(Op::Assign, ref target, ref value) => {
+                match &target.kind {
+                    ExprKind::Var(ref var_name) => {
+                        let value = self.compile_expr(value)?;
+
+                        match self.variables.get(var_name.as_str()) {
+                            Some((var, _)) => {
+                                self.builder.build_store(*var, value);
+                                Ok(value)
+                            },
+                            None => Err(CompileError::UnknownVariable { name: var_name.to_owned(), span: target.span })
+                        }
+                    },
+                    ExprKind::Index(ref array, ref index) => {
+                        let array = self.compile_expr(array)?.into_pointer_value();
+                        let index = self.compile_expr(index)?;
+                        let value = self.compile_expr(value)?;
+
+                        let element = self.compile_element_ptr(array, index);
+                        self.builder.build_store(element, value);
+
+                        Ok(value)
+                    },
+                    ExprKind::Field(ref object, ref field) => {
+                        let object_type = self.type_of(object);
+                        let object = self.compile_expr(object)?.into_pointer_value();
+                        let value = self.compile_expr(value)?;
+
+                        let field_ptr = self.compile_field_ptr(object, &object_type, field, target.span)?;
+                        self.builder.build_store(field_ptr, value);
+
+                        Ok(value)
+                    },
+                    _ => Err(CompileError::InvalidAssignment { span: target.span })
                 }
             },
 
-            Expr::BinOp(ref op, ref left, ref right) => {
+            ExprKind::BinOp(Op::And, ref left, ref right) => self.compile_logical_op(&Op::And, left, right, &self.type_of(expr)),
+            ExprKind::BinOp(Op::Or, ref left, ref right) => self.compile_logical_op(&Op::Or, left, right, &self.type_of(expr)),
+
+            // both operands have the same type
+            ExprKind::BinOp(ref op, ref left, ref right) => {
+                let operand_type = self.type_of(left);
                 let lhs = self.compile_expr(left)?;
                 let rhs = self.compile_expr(right)?;
 
-                match &*op {
-                    Op::Plus        => Ok(self.builder.build_float_add(lhs, rhs, "tmpadd")),
-                    Op::Minus       => Ok(self.builder.build_float_sub(lhs, rhs, "tmpsub")),
-                    Op::Divide      => Ok(self.builder.build_float_div(lhs, rhs, "tmpdiv")),
-                    Op::Multiply    => Ok(self.builder.build_float_mul(lhs, rhs, "tmpmul")),
-                    Op::LessThan    => Ok({
-                        let cmp = self.builder.build_float_compare(FloatPredicate::ULT, lhs, rhs, "tmpcmp");
-                        self.builder.build_unsigned_int_to_float(cmp, self.context.f64_type(), "tmpbool")
-                    }),
-                    Op::GreaterThan => Ok({
-                        let cmp = self.builder.build_float_compare(FloatPredicate::ULT, rhs, lhs, "tmpcmp");
-                        self.builder.build_unsigned_int_to_float(cmp, self.context.f64_type(), "tmpbool")
-                    }),
-                    _ => Err("Unsupported binary operation")
+                if op.is_comparison() {
+                    let cmp = self.compile_comparison(op, lhs, rhs, &operand_type);
+                    Ok(self.truth_value(cmp, &self.type_of(expr)))
+                } else if operand_type == Type::Int {
+                    Ok(self.compile_int_arithmetic(op, lhs.into_int_value(), rhs.into_int_value()).into())
+                } else {
+                    Ok(self.compile_float_arithmetic(op, lhs.into_float_value(), rhs.into_float_value()).into())
+                }
+            },
+
+            ExprKind::UnaryOp(UnaryOp::Not, ref operand) => {
+                // 0 and NaN are false, as in `if`
+                let truth = self.compile_truth(operand)?;
+                let not = self.builder.build_not(truth, "nottmp");
+
+                Ok(self.truth_value(not, &self.type_of(expr)))
+            },
+
+            ExprKind::UnaryOp(UnaryOp::Negate, ref operand) => {
+                match self.compile_expr(operand)? {
+                    BasicValueEnum::IntValue(operand) => Ok(self.builder.build_int_neg(operand, "tmpneg").into()),
+                    operand => Ok(self.builder.build_float_neg(operand.into_float_value(), "tmpneg").into())
                 }
             },
 
-            Expr::IfExpr(ref cond, ref consequence, ref alternative) => {
+            ExprKind::IfExpr(ref cond, ref consequence, ref alternative) => {
                 let parent = self.fn_value();
 
-                // create condition by comparing without 0.0 and returning an int
-                let zero_const = self.context.f64_type().const_float(0.0);
-                let cond = self.compile_expr(cond)?;
-                let cond = self.builder.build_float_compare(FloatPredicate::ONE, cond, zero_const, "ifcond");
+                let cond = self.compile_truth(cond)?;
 
                 // build branch
                 let then_bb = self.context.append_basic_block(parent, "then");
@@ -154,72 +704,129 @@ impl<'a, 'ctx> CodeGen<'a, 'ctx> {
                 // emit merge block
                 self.builder.position_at_end(cont_bb);
 
-                let phi = self.builder.build_phi(self.context.f64_type(), "iftmp");
+                let phi = self.builder.build_phi(self.llvm_type(&self.type_of(expr)), "iftmp");
 
                 phi.add_incoming(&[
                     (&then_val, then_bb),
                     (&else_val, else_bb)
                 ]);
 
-                Ok(phi.as_basic_value().into_float_value())
+                Ok(phi.as_basic_value())
+            },
+
+            ExprKind::Call(ref fn_name, ref args) if self.variables.contains_key(fn_name.as_str()) => {
+                let (var, ty) = self.variables[fn_name.as_str()].clone();
+                let callee = self.builder.build_load(var, fn_name.as_str()).into_pointer_value();
+
+                self.compile_indirect_call(callee, &ty, args)
             },
 
-            Expr::Call(ref fn_name, ref args) => {
-                match self.get_function(fn_name.as_str()) {
+            ExprKind::Apply(ref callee, ref args) => {
+                let ty = self.type_of(callee);
+                let callee = self.compile_expr(callee)?.into_pointer_value();
+
+                self.compile_indirect_call(callee, &ty, args)
+            },
+
+            ExprKind::Lambda(ref params, ref body) => {
+                let ty = self.type_of(expr);
+                Ok(self.compile_lambda(params, body, &ty)?.into())
+            },
+
+            ExprKind::Call(ref fn_name, ref args) => {
+                // the instance called is the one for the types of the arguments and the result
+                let arg_types = args.iter().map(|arg| self.type_of(arg)).collect();
+                let ty = Type::Fn(arg_types, Box::new(self.type_of(expr)));
+
+                match self.function(fn_name, &ty)? {
                     Some(fun) => {
-                        let mut compiled_args = Vec::with_capacity(args.len());
+                        if fun.count_params() as usize != args.len() {
+                            return Err(CompileError::ArityMismatch {
+                                name: fn_name.to_owned(),
+                                expected: fun.count_params() as usize,
+                                found: args.len(),
+                                span: expr.span
+                            });
+                        }
+
+                        let mut argsv: Vec<BasicValueEnum> = Vec::with_capacity(args.len());
 
                         for arg in args {
-                            compiled_args.push(self.compile_expr(arg)?);
+                            argsv.push(self.compile_expr(arg)?);
                         }
 
-                        let argsv: Vec<BasicValueEnum> = compiled_args.iter().by_ref().map(|&val| val.into()).collect();
+                        let value = match self.builder.build_call(fun, argsv.as_slice(), "tmp").try_as_basic_value().left() {
+                            Some(value) => value,
+                            None => return Err(CompileError::Backend { message: format!("call to `{}` produced no value", fn_name) })
+                        };
+                        self.compile_trap_check();
 
-                        match self.builder.build_call(fun, argsv.as_slice(), "tmp").try_as_basic_value().left() {
-                            Some(value) => Ok(value.into_float_value()),
-                            None => Err("Invalid call produced.")
-                        }
+                        Ok(value)
                     },
-                    None => Err("Unknown function.")
+                    None => Err(CompileError::UnknownFunction { name: fn_name.to_owned(), span: expr.span })
                 }
             },
 
-            Expr::ForInExpr(ref var_name, ref initial_val, ref end_cond, ref step, ref body) => {
+            ExprKind::ForInExpr(ref var_name, ref initial_val, ref end_cond, ref step, ref body) => {
                 let parent = self.fn_value();
 
-                let start_alloca = self.create_entry_block_alloca(var_name);
+                let var_type = self.type_of(initial_val);
+                let start_alloca = self.create_entry_block_alloca(var_name, &var_type);
                 let start = self.compile_expr(initial_val)?;
 
                 self.builder.build_store(start_alloca, start);
 
-                // go from current block to loop block
-                let loop_bb = self.context.append_basic_block(parent, "loop");
+                let old_val = self.variables.remove(var_name.as_str());
 
-                self.builder.build_unconditional_branch(loop_bb);
-                self.builder.position_at_end(loop_bb);
+                self.variables.insert(var_name.to_owned(), (start_alloca, var_type.clone()));
 
-                let old_val = self.variables.remove(var_name.as_str());
+                let loop_bb = self.context.append_basic_block(parent, "loop");
+                let after_bb = self.context.append_basic_block(parent, "afterloop");
 
-                self.variables.insert(var_name.to_owned(), start_alloca);
+                // with the condition checked first, it gets a block of its own that the loop returns to
+                let cond_bb = match self.loop_check {
+                    LoopCheck::BeforeBody => {
+                        let cond_bb = self.context.prepend_basic_block(loop_bb, "loopcond");
 
-                // emit body
-                self.compile_expr(body)?;
+                        self.builder.build_unconditional_branch(cond_bb);
+                        self.builder.position_at_end(cond_bb);
 
-                // emit step
-                let step = self.compile_expr(step)?;
+                        let end_cond = self.compile_truth(end_cond)?;
+                        self.builder.build_conditional_branch(end_cond, loop_bb, after_bb);
 
-                // compile end condition
-                let end_cond = self.compile_expr(end_cond)?;
+                        Some(cond_bb)
+                    },
+                    LoopCheck::AfterBody => {
+                        self.builder.build_unconditional_branch(loop_bb);
+                        None
+                    }
+                };
 
-                let curr_var = self.builder.build_load(start_alloca, var_name);
-                let next_var = self.builder.build_float_add(curr_var.into_float_value(), step, "nextvar");
+                // emit body
+                self.builder.position_at_end(loop_bb);
+                self.compile_expr(body)?;
 
-                self.builder.build_store(start_alloca, next_var);
+                // emit step, 1 unless given
+                let step = match (step, var_type) {
+                    (Some(step), _) => self.compile_expr(step)?,
+                    (None, Type::Int) => self.context.i64_type().const_int(1, false).into(),
+                    (None, _) => self.context.f64_type().const_float(1.0).into()
+                };
+
+                match cond_bb {
+                    Some(cond_bb) => {
+                        self.compile_increment(start_alloca, var_name, step);
+                        self.builder.build_unconditional_branch(cond_bb);
+                    },
+                    None => {
+                        // the condition sees the variable before it is incremented
+                        let end_cond = self.compile_truth(end_cond)?;
 
-                let end_cond = self.builder.build_float_compare(FloatPredicate::ONE, end_cond, self.context.f64_type().const_float(0.0), "loopcond");
-                let after_bb = self.context.append_basic_block(parent, "afterloop");
+                        self.compile_increment(start_alloca, var_name, step);
+                        self.builder.build_conditional_branch(end_cond, loop_bb, after_bb);
+                    }
+                }
 
-                self.builder.build_conditional_branch(end_cond, loop_bb, after_bb);
                 self.builder.position_at_end(after_bb);
 
                 self.variables.remove(var_name);
@@ -228,119 +835,1609 @@ impl<'a, 'ctx> CodeGen<'a, 'ctx> {
                     self.variables.insert(var_name.to_owned(), val);
                 }
 
-                Ok(self.context.f64_type().const_float(0.0))
+                Ok(self.context.f64_type().const_float(0.0).into())
             },
 
-            x => {
-                println!("This type of expr not yet supported: {:?}", x);
-                Err("Expr not yet supported")
-            }
-        }
-    }
+            ExprKind::While(ref cond, ref body) => {
+                let parent = self.fn_value();
 
-    fn compile_prototype(&self, name: &str, params: &Vec<Name>) -> Result<FunctionValue<'ctx>, String> {
-        // All functions return f64
-        let ret_type = self.context.f64_type();
+                let cond_bb = self.context.append_basic_block(parent, "whilecond");
+                let loop_bb = self.context.append_basic_block(parent, "whilebody");
+                let after_bb = self.context.append_basic_block(parent, "afterwhile");
 
-        // All arguments are of type f64
-        let args_types = std::iter::repeat(ret_type)
-            .take(params.len())
-            .map(|f| f.into())
-            .collect::<Vec<BasicTypeEnum>>();
-        let args_types = args_types.as_slice();
+                self.builder.build_unconditional_branch(cond_bb);
+                self.builder.position_at_end(cond_bb);
 
-        let fn_type = self.context.f64_type().fn_type(args_types, false);
-        let fn_val = self.module.add_function(name, fn_type, None);
+                let cond = self.compile_truth(cond)?;
+                self.builder.build_conditional_branch(cond, loop_bb, after_bb);
 
-        // set arguments names
-        for (i, arg) in fn_val.get_param_iter().enumerate() {
-            arg.into_float_value().set_name(params[i].as_str());
-        }
+                self.builder.position_at_end(loop_bb);
+                self.compile_expr(body)?;
+                self.builder.build_unconditional_branch(cond_bb);
 
-        // finally return built prototype
-        Ok(fn_val)
-    }
+                self.builder.position_at_end(after_bb);
 
-    // Compiles the specified `Function` into an LLVM `FunctionValue`.
-    fn compile_fn(&mut self, name: &str, params: &Vec<Name>, expr: &Box<Expr>) -> Result<FunctionValue, String> {
-        let function = self.compile_prototype(&name, &params)?;
-        let entry = self.context.append_basic_block(function, "entry");
+                Ok(self.context.f64_type().const_float(0.0).into())
+            },
 
-        self.builder.position_at_end(entry);
+            ExprKind::Array(ref elements) => {
+                let mut values = Vec::with_capacity(elements.len());
 
-        // update fn field
-        self.fn_value_opt = Some(function);
+                for element in elements {
+                    values.push(self.compile_expr(element)?);
+                }
 
-        // build variables map
-        self.variables.reserve(params.len());
+                let len = self.context.i64_type().const_int(values.len() as u64, false).into();
+                let array = self.compile_alloc(len, &element_type(&self.type_of(expr)));
 
-        for (i, arg) in function.get_param_iter().enumerate() {
-            let arg_name = params[i].as_str();
-            let alloca = self.create_entry_block_alloca(arg_name);
+                for (i, value) in values.into_iter().enumerate() {
+                    let element = self.element_ptr(array, self.context.i64_type().const_int(i as u64, false));
+                    self.builder.build_store(element, value);
+                }
 
-            self.builder.build_store(alloca, arg);
+                Ok(array.into())
+            },
 
-            self.variables.insert(params[i].clone(), alloca);
-        }
+            ExprKind::Alloc(ref len) => {
+                let len = self.compile_expr(len)?;
 
-        // compile body
-        let body = self.compile_expr(expr.as_ref())?;
+                Ok(self.compile_alloc(len, &element_type(&self.type_of(expr))).into())
+            },
 
-        self.builder.build_return(Some(&body));
+            ExprKind::Index(ref array, ref index) => {
+                let array = self.compile_expr(array)?.into_pointer_value();
+                let index = self.compile_expr(index)?;
+                let element = self.compile_element_ptr(array, index);
 
-        // return the whole thing after verification and optimization
-        if function.verify(true) {
-            self.fpm.run_on(&function);
+                Ok(self.builder.build_load(element, "elem"))
+            },
 
-            Ok(function)
-        } else {
-            unsafe {
-                function.delete();
-            }
+            ExprKind::Len(ref array) => {
+                let array = self.compile_expr(array)?.into_pointer_value();
+                let len = self.compile_length(array);
 
-            Err("Invalid generated function.".to_string())
-        }
-    }
+                match self.type_of(expr) {
+                    Type::Int => Ok(len.into()),
+                    _ => Ok(self.builder.build_unsigned_int_to_float(len, self.context.f64_type(), "lenval").into())
+                }
+            },
+
+            ExprKind::Construct(ref name, ref values) => {
+                let struct_type = match self.type_of(expr) {
+                    Type::Struct(_, field_types) if self.structs.fields(name).is_some() => self.struct_type(name, &field_types),
+                    _ => return Err(CompileError::UnknownStruct { name: name.to_owned(), span: expr.span })
+                };
+                let fields = self.structs.fields(name).cloned().unwrap_or_default();
+
+                // fields are evaluated in the order they are given, then stored where declared
+                let mut compiled = Vec::with_capacity(values.len());
+                for (field, value) in values {
+                    match fields.iter().position(|f| f == field) {
+                        Some(index) => compiled.push((index, field, self.compile_expr(value)?)),
+                        None => return Err(CompileError::UnknownField {
+                            struct_name: Some(name.to_owned()),
+                            field: field.to_owned(),
+                            span: expr.span
+                        })
+                    }
+                }
+
+                let size = struct_type.size_of().expect("a struct of numbers and pointers has a size");
+                let block = self.compile_block(size);
+                let data = self.builder.build_pointer_cast(block, struct_type.ptr_type(AddressSpace::Generic), "struct");
+
+                for (index, field, value) in compiled {
+                    let field_ptr = self.builder.build_struct_gep(data, index as u32, field)
+                        .map_err(|_| CompileError::Backend { message: format!("no field `{}` in struct `{}`", field, name) })?;
+                    self.builder.build_store(field_ptr, value);
+                }
+
+                Ok(data.into())
+            },
+
+            ExprKind::Field(ref object, ref field) => {
+                let object_type = self.type_of(object);
+                let object = self.compile_expr(object)?.into_pointer_value();
+                let field_ptr = self.compile_field_ptr(object, &object_type, field, expr.span)?;
+
+                Ok(self.builder.build_load(field_ptr, field))
+            },
+
+            ExprKind::Block(ref exprs) => {
+                let mut value = self.context.f64_type().const_float(0.0).into();
+
+                for expr in exprs {
+                    value = self.compile_expr(expr)?;
+                }
+
+                Ok(value)
+            },
+
+            ExprKind::VarIn(ref bindings, ref body) => {
+                let mut old_bindings = Vec::with_capacity(bindings.len());
+
+                for (var_name, initializer) in bindings {
+                    // Compile the initializer before binding the name, so `var a = a in ...`
+                    // refers to the outer `a`. Without one, the variable is a number.
+                    let (init_val, var_type) = match initializer {
+                        Some(init) => (self.compile_expr(init)?, self.type_of(init)),
+                        None => (self.context.f64_type().const_float(0.0).into(), Type::Number)
+                    };
+
+                    let alloca = self.create_entry_block_alloca(var_name, &var_type);
+                    self.builder.build_store(alloca, init_val);
 
+                    old_bindings.push(self.variables.insert(var_name.to_owned(), (alloca, var_type)));
+                }
+
+                let body_val = self.compile_expr(body)?;
+
+                // restore shadowed variables, innermost binding first
+                for ((var_name, _), old_val) in bindings.iter().zip(old_bindings).rev() {
+                    match old_val {
+                        Some(val) => self.variables.insert(var_name.to_owned(), val),
+                        None => self.variables.remove(var_name.as_str())
+                    };
+                }
 
-    pub fn jit_compile_main(&self) -> Option<JitFunction<MainFunc>> {
-      self.module.print_to_stderr();
+                Ok(body_val)
+            },
 
-      unsafe { self.execution_engine.get_function("main").ok() }
+            ExprKind::Function(..) | ExprKind::Extern(..) | ExprKind::Struct(..) =>
+                Err(CompileError::NestedDefinition { span: expr.span })
+        }
     }
 
-    pub fn compile_program(&mut self, exprs: &Program) -> Result<(), String> {
-        for expr in exprs {
-            match expr {
-                Expr::Function(name, params, expr) => {
-                    self.compile_fn(&name, &params, &expr)?;
-                },
-                Expr::Extern(name, params) => {
-                    self.compile_prototype(&name, &params)?;
-                },
-                _ => {
-                    return Err("Only functions and `extern` declarations can be at the outer level".to_string());
+    // Declares the runtime function `name` in the current module, unless it already is. The JIT
+    // binds it to `host_fn` if given, and otherwise looks the symbol up in the process.
+    fn runtime_fn(&self, name: &str, fn_type: FunctionType<'ctx>, host_fn: Option<usize>) -> FunctionValue<'ctx> {
+        match self.module.get_function(name) {
+            Some(fun) => fun,
+            None => {
+                let fun = self.module.add_function(name, fn_type, Some(Linkage::External));
+
+                if let Some(address) = host_fn {
+                    self.execution_engine.add_global_mapping(&fun, address);
                 }
+                fun
             }
         }
+    }
 
-        Ok(())
+    // Declares the runtime function `name`, which reports an error given arguments of `param_types`.
+    // Trapping, it is bound to `error_fn`, which takes the trap first; otherwise the runtime of a
+    // native executable provides it.
+    fn error_fn(&self, name: &str, param_types: &[BasicTypeEnum<'ctx>], error_fn: usize) -> FunctionValue<'ctx> {
+        let void_type = self.context.void_type();
+
+        if self.trap_runtime_errors {
+            let i8_ptr_type = self.context.i8_type().ptr_type(AddressSpace::Generic);
+            let param_types: Vec<BasicTypeEnum> = std::iter::once(i8_ptr_type.into()).chain(param_types.iter().cloned()).collect();
+
+            self.runtime_fn(name, void_type.fn_type(&param_types, false), Some(error_fn))
+        } else {
+            self.runtime_fn(name, void_type.fn_type(param_types, false), None)
+        }
     }
 
-    pub fn mk_compiler(
-        context: &'ctx Context,
-        pass_manager: &'a PassManager<FunctionValue<'ctx>>,
-        module: Box<Module<'ctx>>
-    ) -> Result<CodeGen<'a, 'ctx>, Box<dyn Error>> {
-      let execution_engine = module.create_jit_execution_engine(OptimizationLevel::None)?;
-      Ok(CodeGen {
-          context: &context,
-          module: *module,
-          builder: context.create_builder(),
-          fpm: &pass_manager,
-          execution_engine: execution_engine,
-          fn_value_opt: None,
-          variables: HashMap::new()
-      })
+    // Carries on only if `ok` holds, and otherwise calls `error_fn` (see `error_fn`) with `args`.
+    // Trapping, that raises a runtime error, and the current function returns at once. Otherwise the
+    // runtime reports the error and aborts, so the call never returns.
+    fn compile_check(&self, ok: IntValue<'ctx>, error_fn: FunctionValue<'ctx>, args: &[BasicValueEnum<'ctx>]) {
+        let parent = self.fn_value();
+        let fail_bb = self.context.append_basic_block(parent, "checkfail");
+        let cont_bb = self.context.append_basic_block(parent, "checkok");
+
+        self.builder.build_conditional_branch(ok, cont_bb, fail_bb);
+
+        self.builder.position_at_end(fail_bb);
+        if self.trap_runtime_errors {
+            let args: Vec<BasicValueEnum> = std::iter::once(self.trap_ptr().into()).chain(args.iter().cloned()).collect();
+
+            self.builder.build_call(error_fn, &args, "");
+            self.compile_trap_return();
+        } else {
+            self.builder.build_call(error_fn, args, "");
+            self.builder.build_unreachable();
+        }
+
+        self.builder.position_at_end(cont_bb);
+    }
+
+    // The address of the trap, as the `i8*` its flag is read through.
+    fn trap_ptr(&self) -> PointerValue<'ctx> {
+        let i8_ptr_type = self.context.i8_type().ptr_type(AddressSpace::Generic);
+
+        self.context.i64_type().const_int(Rc::as_ptr(&self.trap) as u64, false).const_to_pointer(i8_ptr_type)
+    }
+
+    // Trapping, returns at once from the current function if the call just made was stopped by a
+    // runtime error, so that the error reaches the Rust code that called into compiled code.
+    fn compile_trap_check(&self) {
+        if !self.trap_runtime_errors {
+            return;
+        }
+
+        let raised = self.builder.build_load(self.trap_ptr(), "raised").into_int_value();
+        let trapped = self.builder.build_int_compare(IntPredicate::NE, raised, self.context.i8_type().const_zero(), "trapped");
+
+        let parent = self.fn_value();
+        let trap_bb = self.context.append_basic_block(parent, "trap");
+        let cont_bb = self.context.append_basic_block(parent, "notrap");
+
+        self.builder.build_conditional_branch(trapped, trap_bb, cont_bb);
+
+        self.builder.position_at_end(trap_bb);
+        self.compile_trap_return();
+
+        self.builder.position_at_end(cont_bb);
+    }
+
+    // Returns from the current function, stopped by a runtime error, with the zero of its return
+    // type in place of a value. Nothing looks at it.
+    fn compile_trap_return(&self) {
+        let zero: BasicValueEnum = match self.fn_value().get_type().get_return_type() {
+            Some(BasicTypeEnum::FloatType(float_type)) => float_type.const_zero().into(),
+            Some(BasicTypeEnum::IntType(int_type)) => int_type.const_zero().into(),
+            Some(BasicTypeEnum::PointerType(ptr_type)) => ptr_type.const_null().into(),
+            _ => unreachable!("values are numbers, ints, bools or pointers")
+        };
+
+        self.builder.build_return(Some(&zero));
+    }
+
+    // Allocates an array of `len` zeros of type `element`, `len` a number, truncated, or an int. An
+    // array is a block on the heap, its length followed by its elements (see `array_type`), and is
+    // never freed.
+    fn compile_alloc(&self, len: BasicValueEnum<'ctx>, element: &Type) -> PointerValue<'ctx> {
+        let f64_type = self.context.f64_type();
+        let i32_type = self.context.i32_type();
+        let i64_type = self.context.i64_type();
+        let i8_ptr_type = self.context.i8_type().ptr_type(AddressSpace::Generic);
+        let array_ptr_type = self.array_type(element).ptr_type(AddressSpace::Generic);
+
+        let length_error_fn = self.error_fn("__kaleidoscope_length_error", &[f64_type.into()], length_error as usize);
+        let calloc = self.runtime_fn("calloc", i8_ptr_type.fn_type(&[i64_type.into(), i64_type.into()], false), None);
+
+        // beyond 2^53 not every length is a double, and converting NaN is undefined
+        let (valid, len_value) = match len {
+            BasicValueEnum::IntValue(len) => {
+                let not_negative = self.builder.build_int_compare(IntPredicate::SGE, len, i64_type.const_int(0, false), "lenmin");
+                let not_huge = self.builder.build_int_compare(IntPredicate::SLE, len, i64_type.const_int(1 << 53, false), "lenmax");
+
+                (self.builder.build_and(not_negative, not_huge, "lenok"), self.builder.build_signed_int_to_float(len, f64_type, "lenval"))
+            },
+            len => {
+                let len = len.into_float_value();
+                let not_negative = self.builder.build_float_compare(FloatPredicate::OGE, len, f64_type.const_float(0.0), "lenmin");
+                let not_huge = self.builder.build_float_compare(FloatPredicate::OLE, len, f64_type.const_float(9007199254740992.0), "lenmax");
+
+                (self.builder.build_and(not_negative, not_huge, "lenok"), len)
+            }
+        };
+        self.compile_check(valid, length_error_fn, &[len_value.into()]);
+
+        // the size is the offset of the element past the last, in an array at address 0
+        let count = match len {
+            BasicValueEnum::IntValue(len) => len,
+            _ => self.builder.build_float_to_unsigned_int(len_value, i64_type, "count")
+        };
+        let end = unsafe {
+            self.builder.build_gep(array_ptr_type.const_null(), &[i32_type.const_int(0, false), i32_type.const_int(1, false), count], "end")
+        };
+        let size = self.builder.build_ptr_to_int(end, i64_type, "size");
+
+        let block = self.builder.build_call(calloc, &[i64_type.const_int(1, false).into(), size.into()], "block")
+            .try_as_basic_value()
+            .left()
+            .expect("calloc returns a pointer")
+            .into_pointer_value();
+
+        let allocated = self.builder.build_not(self.builder.build_is_null(block, "failed"), "allocated");
+        self.compile_check(allocated, length_error_fn, &[len_value.into()]);
+
+        let array = self.builder.build_pointer_cast(block, array_ptr_type, "array");
+        let len_ptr = self.builder.build_struct_gep(array, 0, "lenptr").expect("an array starts with its length");
+        self.builder.build_store(len_ptr, count);
+
+        array
+    }
+
+    // Allocates a zeroed block of `size` bytes on the heap, which is never freed, after checking
+    // that there was memory left for it.
+    fn compile_block(&self, size: IntValue<'ctx>) -> PointerValue<'ctx> {
+        let f64_type = self.context.f64_type();
+        let i64_type = self.context.i64_type();
+        let i8_ptr_type = self.context.i8_type().ptr_type(AddressSpace::Generic);
+
+        let alloc_error_fn = self.error_fn("__kaleidoscope_alloc_error", &[f64_type.into()], alloc_error as usize);
+        let calloc = self.runtime_fn("calloc", i8_ptr_type.fn_type(&[i64_type.into(), i64_type.into()], false), None);
+
+        let block = self.builder.build_call(calloc, &[i64_type.const_int(1, false).into(), size.into()], "block")
+            .try_as_basic_value()
+            .left()
+            .expect("calloc returns a pointer")
+            .into_pointer_value();
+
+        let allocated = self.builder.build_not(self.builder.build_is_null(block, "failed"), "allocated");
+        self.compile_check(allocated, alloc_error_fn, &[self.builder.build_unsigned_int_to_float(size, f64_type, "size").into()]);
+
+        block
+    }
+
+    // The length of `array`.
+    fn compile_length(&self, array: PointerValue<'ctx>) -> IntValue<'ctx> {
+        let len_ptr = self.builder.build_struct_gep(array, 0, "lenptr").expect("an array starts with its length");
+
+        self.builder.build_load(len_ptr, "len").into_int_value()
+    }
+
+    // The address of the element of `array` at `index`, which must be in bounds.
+    fn element_ptr(&self, array: PointerValue<'ctx>, index: IntValue<'ctx>) -> PointerValue<'ctx> {
+        let i32_type = self.context.i32_type();
+
+        unsafe { self.builder.build_in_bounds_gep(array, &[i32_type.const_int(0, false), i32_type.const_int(1, false), index], "elemptr") }
+    }
+
+    // The address of the element of `array` at `index`, a number or an int, after checking that the
+    // index is in bounds. A fractional index is truncated.
+    fn compile_element_ptr(&self, array: PointerValue<'ctx>, index: BasicValueEnum<'ctx>) -> PointerValue<'ctx> {
+        let f64_type = self.context.f64_type();
+        let i64_type = self.context.i64_type();
+
+        let index_error_fn = self.error_fn("__kaleidoscope_index_error", &[f64_type.into(), f64_type.into()], index_error as usize);
+
+        let len = self.compile_length(array);
+        let len_value = self.builder.build_unsigned_int_to_float(len, f64_type, "lenval");
+
+        let (in_bounds, index_value) = match index {
+            BasicValueEnum::IntValue(index) => {
+                let not_negative = self.builder.build_int_compare(IntPredicate::SGE, index, i64_type.const_int(0, false), "indexmin");
+                let below_len = self.builder.build_int_compare(IntPredicate::SLT, index, len, "indexmax");
+
+                (self.builder.build_and(not_negative, below_len, "inbounds"), self.builder.build_signed_int_to_float(index, f64_type, "indexval"))
+            },
+            index => {
+                let index = index.into_float_value();
+
+                // both comparisons are false for NaN
+                let not_negative = self.builder.build_float_compare(FloatPredicate::OGE, index, f64_type.const_float(0.0), "indexmin");
+                let below_len = self.builder.build_float_compare(FloatPredicate::OLT, index, len_value, "indexmax");
+
+                (self.builder.build_and(not_negative, below_len, "inbounds"), index)
+            }
+        };
+        self.compile_check(in_bounds, index_error_fn, &[index_value.into(), len_value.into()]);
+
+        let index = match index {
+            BasicValueEnum::IntValue(index) => index,
+            _ => self.builder.build_float_to_unsigned_int(index_value, i64_type, "index")
+        };
+
+        self.element_ptr(array, index)
+    }
+
+    // The address of `field` in `object`, a struct of type `object_type`.
+    fn compile_field_ptr(&self, object: PointerValue<'ctx>, object_type: &Type, field: &str, span: Span) -> Result<PointerValue<'ctx>, CompileError> {
+        let index = match object_type {
+            Type::Struct(name, _) => self.structs.fields(name).and_then(|fields| fields.iter().position(|f| f == field)),
+            _ => None
+        };
+
+        match index {
+            Some(index) => self.builder.build_struct_gep(object, index as u32, field)
+                .map_err(|_| CompileError::Backend { message: format!("no field `{}` in `{}`", field, object_type) }),
+            None => Err(CompileError::UnknownField { struct_name: None, field: field.to_owned(), span })
+        }
+    }
+
+    // Calls the function value `callee`, of type `callee_type`. A function value is the address of a
+    // closure record on the heap: the address of its code, followed by the values of any variables it
+    // captured. The code takes the closure record, followed by the arguments. Type inference made
+    // sure there are as many of those as the function has parameters.
+    fn compile_indirect_call(&mut self, callee: PointerValue<'ctx>, callee_type: &Type, args: &Program) -> Result<BasicValueEnum<'ctx>, CompileError> {
+        let i8_ptr_type = self.context.i8_type().ptr_type(AddressSpace::Generic);
+
+        let mut argsv: Vec<BasicValueEnum> = Vec::with_capacity(args.len() + 1);
+        argsv.push(callee.into());
+        for arg in args {
+            argsv.push(self.compile_expr(arg)?);
+        }
+
+        let fn_ptr_type = self.code_type(callee_type).ptr_type(AddressSpace::Generic);
+
+        let record = self.builder.build_pointer_cast(callee, i8_ptr_type.ptr_type(AddressSpace::Generic), "record");
+        let code = self.builder.build_load(record, "code").into_pointer_value();
+        let fn_ptr = self.builder.build_pointer_cast(code, fn_ptr_type, "fnptr");
+
+        let value = match self.builder.build_call(fn_ptr, argsv.as_slice(), "tmp").try_as_basic_value().left() {
+            Some(value) => value,
+            None => return Err(CompileError::Backend { message: "indirect call produced no value".to_string() })
+        };
+        self.compile_trap_check();
+
+        Ok(value)
+    }
+
+    // The value of the function `fun`, of type `ty`: a constant closure record, capturing nothing,
+    // whose code calls `fun` with the arguments it is given. Each module taking the value gets its
+    // own.
+    fn function_value(&self, fun: FunctionValue<'ctx>, ty: &Type) -> PointerValue<'ctx> {
+        let symbol = fun.get_name().to_string_lossy().into_owned();
+        let record_name = format!("{}.value", symbol);
+        let i8_ptr_type = self.context.i8_type().ptr_type(AddressSpace::Generic);
+
+        let record = match self.module.get_global(&record_name) {
+            Some(record) => record,
+            None => {
+                // the adapter drops the closure record it is passed first
+                let adapter = self.module.add_function(&format!("{}.adapter", symbol), self.code_type(ty), Some(Linkage::Private));
+
+                let builder = self.context.create_builder();
+                builder.position_at_end(self.context.append_basic_block(adapter, "entry"));
+
+                let args: Vec<BasicValueEnum> = adapter.get_param_iter().skip(1).collect();
+                let result = builder.build_call(fun, &args, "tmp").try_as_basic_value().left().expect("functions return a value");
+                builder.build_return(Some(&result));
+
+                let record = self.module.add_global(i8_ptr_type, None, &record_name);
+                record.set_initializer(&adapter.as_global_value().as_pointer_value().const_cast(i8_ptr_type));
+                record.set_constant(true);
+                record.set_linkage(Linkage::Private);
+                record
+            }
+        };
+
+        record.as_pointer_value().const_cast(i8_ptr_type)
+    }
+
+    // Compiles a lambda, of type `ty`, into a function of its own, and returns its value: a closure
+    // record holding the function's address and the values of the variables the lambda captures,
+    // which are copied into variables of the function when it is called. See
+    // `compile_indirect_call`.
+    fn compile_lambda(&mut self, params: &[Name], body: &Expr, ty: &Type) -> Result<PointerValue<'ctx>, CompileError> {
+        let i8_ptr_type = self.context.i8_type().ptr_type(AddressSpace::Generic);
+
+        // names used but not bound in the lambda are captured if they are variables here, and
+        // otherwise refer to functions
+        let captures: Vec<(Name, PointerValue<'ctx>, Type)> = sema::captured_variables(params, body).into_iter()
+            .filter_map(|name| self.variables.get(&name).map(|(var, var_type)| (name.to_owned(), *var, var_type.clone())))
+            .collect();
+
+        let mut field_types: Vec<BasicTypeEnum> = vec![i8_ptr_type.into()];
+        field_types.extend(captures.iter().map(|(_, _, var_type)| self.llvm_type(var_type)));
+        let record_type = self.context.struct_type(&field_types, false);
+
+        let name = format!("__lambda_{}", self.lambda_count);
+        self.lambda_count += 1;
+
+        let function = self.module.add_function(&name, self.code_type(ty), None);
+
+        // the body is compiled in between, so the enclosing function's state is put aside
+        let outer_block = self.builder.get_insert_block().expect("a lambda is compiled inside a function");
+        let outer_fn = self.fn_value_opt;
+        let outer_variables = std::mem::take(&mut self.variables);
+
+        let compiled = self.compile_lambda_body(function, record_type, &captures, params, ty, body);
+
+        self.variables = outer_variables;
+        self.fn_value_opt = outer_fn;
+        self.builder.position_at_end(outer_block);
+
+        if let Err(err) = compiled {
+            unsafe {
+                function.delete();
+            }
+            return Err(err);
+        }
+
+        let size = record_type.size_of().expect("a record of numbers and pointers has a size");
+        let block = self.compile_block(size);
+        let record = self.builder.build_pointer_cast(block, record_type.ptr_type(AddressSpace::Generic), "record");
+
+        let code = function.as_global_value().as_pointer_value().const_cast(i8_ptr_type);
+        let code_ptr = self.builder.build_struct_gep(record, 0, "codeptr")
+            .map_err(|_| CompileError::Backend { message: "closure record has no code".to_string() })?;
+        self.builder.build_store(code_ptr, code);
+
+        for (i, (var_name, var, _)) in captures.iter().enumerate() {
+            let value = self.builder.build_load(*var, var_name);
+            let slot = self.builder.build_struct_gep(record, i as u32 + 1, var_name)
+                .map_err(|_| CompileError::Backend { message: format!("closure record has no slot for `{}`", var_name) })?;
+            self.builder.build_store(slot, value);
+        }
+
+        Ok(block)
+    }
+
+    // Compiles the body of a lambda of type `ty` into `function`, whose first parameter is its closure
+    // record, of type `record_type`.
+    fn compile_lambda_body(&mut self, function: FunctionValue<'ctx>, record_type: StructType<'ctx>, captures: &[(Name, PointerValue<'ctx>, Type)], params: &[Name], ty: &Type, body: &Expr) -> Result<(), CompileError> {
+        let param_types = match ty {
+            Type::Fn(param_types, _) => param_types.clone(),
+            _ => unreachable!("a lambda is a function")
+        };
+        let entry = self.context.append_basic_block(function, "entry");
+
+        self.builder.position_at_end(entry);
+        self.fn_value_opt = Some(function);
+
+        let mut args = function.get_param_iter();
+        let closure = args.next().expect("a lambda takes its closure record").into_pointer_value();
+
+        for ((param, param_type), arg) in params.iter().zip(param_types).zip(args) {
+            let alloca = self.create_entry_block_alloca(param, &param_type);
+
+            self.builder.build_store(alloca, arg);
+            self.variables.insert(param.to_owned(), (alloca, param_type));
+        }
+
+        // captured values are copied out of the record, so the body reads them like any variable
+        let record = self.builder.build_pointer_cast(closure, record_type.ptr_type(AddressSpace::Generic), "record");
+
+        for (i, (var_name, _, var_type)) in captures.iter().enumerate() {
+            let slot = self.builder.build_struct_gep(record, i as u32 + 1, var_name)
+                .map_err(|_| CompileError::Backend { message: format!("closure record has no slot for `{}`", var_name) })?;
+            let alloca = self.create_entry_block_alloca(var_name, var_type);
+
+            self.builder.build_store(alloca, self.builder.build_load(slot, var_name));
+            self.variables.insert(var_name.to_owned(), (alloca, var_type.clone()));
+        }
+
+        let body = self.compile_expr(body)?;
+        self.builder.build_return(Some(&body));
+
+        self.verify_fn(&function.get_name().to_string_lossy(), function)
+    }
+
+    // Checks the function `name` just compiled into `function`, and optimizes it.
+    fn verify_fn(&self, name: &str, function: FunctionValue<'ctx>) -> Result<(), CompileError> {
+        if function.verify(true) {
+            self.fpm.run_on(&function);
+            Ok(())
+        } else {
+            let llvm_message = match self.module.verify() {
+                Err(message) => message.to_string(),
+                Ok(()) => "function failed verification".to_string()
+            };
+
+            Err(CompileError::InvalidFunction { name: name.to_owned(), llvm_message })
+        }
+    }
+
+    // Compiles a condition into an `i1` that is true unless the value is 0 or NaN.
+    fn compile_truth(&mut self, cond: &Expr) -> Result<IntValue<'ctx>, CompileError> {
+        let ty = self.type_of(cond);
+        let value = self.compile_expr(cond)?;
+
+        Ok(self.truth(value, &ty))
+    }
+
+    // Whether `value`, of type `ty`, holds as a condition: a number or an int does unless it is 0 or
+    // NaN, and a bool if it is true.
+    fn truth(&self, value: BasicValueEnum<'ctx>, ty: &Type) -> IntValue<'ctx> {
+        match ty {
+            Type::Number => {
+                let zero_const = self.context.f64_type().const_float(0.0);
+                self.builder.build_float_compare(FloatPredicate::ONE, value.into_float_value(), zero_const, "cond")
+            },
+            Type::Int => {
+                let zero_const = self.context.i64_type().const_int(0, false);
+                self.builder.build_int_compare(IntPredicate::NE, value.into_int_value(), zero_const, "cond")
+            },
+            Type::Bool => value.into_int_value(),
+            _ => unreachable!("only numbers, ints and bools are conditions")
+        }
+    }
+
+    // The `i1` `truth` as a value of type `ty`: 1 or 0 as a number or an int, or itself as a bool.
+    fn truth_value(&self, truth: IntValue<'ctx>, ty: &Type) -> BasicValueEnum<'ctx> {
+        match ty {
+            Type::Number => self.builder.build_unsigned_int_to_float(truth, self.context.f64_type(), "tmpbool").into(),
+            Type::Int => self.builder.build_int_z_extend(truth, self.context.i64_type(), "tmpint").into(),
+            Type::Bool => truth.into(),
+            _ => unreachable!("comparisons and logic evaluate to numbers, ints or bools")
+        }
+    }
+
+    // Adds `step` to the loop variable stored in `alloca`, a number or an int as `step` is.
+    fn compile_increment(&self, alloca: PointerValue<'ctx>, var_name: &str, step: BasicValueEnum<'ctx>) {
+        let curr_var = self.builder.build_load(alloca, var_name);
+
+        match step {
+            BasicValueEnum::IntValue(step) => {
+                let next_var = self.builder.build_int_add(curr_var.into_int_value(), step, "nextvar");
+                self.builder.build_store(alloca, next_var);
+            },
+            step => {
+                let next_var = self.builder.build_float_add(curr_var.into_float_value(), step.into_float_value(), "nextvar");
+                self.builder.build_store(alloca, next_var);
+            }
+        }
+    }
+
+    // Compares two values of type `ty`, numbers, ints or bools, giving whether `op` holds.
+    fn compile_comparison(&self, op: &Op, lhs: BasicValueEnum<'ctx>, rhs: BasicValueEnum<'ctx>, ty: &Type) -> IntValue<'ctx> {
+        if *ty == Type::Number {
+            // see `Op` for why `!=` alone is unordered
+            let predicate = match op {
+                Op::LessThan     => FloatPredicate::OLT,
+                Op::LessEqual    => FloatPredicate::OLE,
+                Op::GreaterThan  => FloatPredicate::OGT,
+                Op::GreaterEqual => FloatPredicate::OGE,
+                Op::Equal        => FloatPredicate::OEQ,
+                Op::NotEqual     => FloatPredicate::UNE,
+                _ => unreachable!("not a comparison")
+            };
+
+            self.builder.build_float_compare(predicate, lhs.into_float_value(), rhs.into_float_value(), "tmpcmp")
+        } else {
+            let predicate = match op {
+                Op::LessThan     => IntPredicate::SLT,
+                Op::LessEqual    => IntPredicate::SLE,
+                Op::GreaterThan  => IntPredicate::SGT,
+                Op::GreaterEqual => IntPredicate::SGE,
+                Op::Equal        => IntPredicate::EQ,
+                Op::NotEqual     => IntPredicate::NE,
+                _ => unreachable!("not a comparison")
+            };
+
+            self.builder.build_int_compare(predicate, lhs.into_int_value(), rhs.into_int_value(), "tmpcmp")
+        }
+    }
+
+    fn compile_float_arithmetic(&self, op: &Op, lhs: FloatValue<'ctx>, rhs: FloatValue<'ctx>) -> FloatValue<'ctx> {
+        match op {
+            Op::Plus      => self.builder.build_float_add(lhs, rhs, "tmpadd"),
+            Op::Minus     => self.builder.build_float_sub(lhs, rhs, "tmpsub"),
+            Op::Divide    => self.builder.build_float_div(lhs, rhs, "tmpdiv"),
+            Op::Multiply  => self.builder.build_float_mul(lhs, rhs, "tmpmul"),
+            Op::Remainder => self.builder.build_float_rem(lhs, rhs, "tmprem"),
+            _ => unreachable!("assignments, comparisons and logical operators are compiled separately")
+        }
+    }
+
+    // Arithmetic on ints wraps around, except that division by zero, or of the smallest int by -1,
+    // stops the program with an error rather than being undefined.
+    fn compile_int_arithmetic(&self, op: &Op, lhs: IntValue<'ctx>, rhs: IntValue<'ctx>) -> IntValue<'ctx> {
+        match op {
+            Op::Plus      => self.builder.build_int_add(lhs, rhs, "tmpadd"),
+            Op::Minus     => self.builder.build_int_sub(lhs, rhs, "tmpsub"),
+            Op::Multiply  => self.builder.build_int_mul(lhs, rhs, "tmpmul"),
+            Op::Divide    => {
+                self.compile_division_check(lhs, rhs);
+                self.builder.build_int_signed_div(lhs, rhs, "tmpdiv")
+            },
+            Op::Remainder => {
+                self.compile_division_check(lhs, rhs);
+                self.builder.build_int_signed_rem(lhs, rhs, "tmprem")
+            },
+            _ => unreachable!("assignments, comparisons and logical operators are compiled separately")
+        }
+    }
+
+    // Checks that dividing `lhs` by `rhs`, both ints, is defined.
+    fn compile_division_check(&self, lhs: IntValue<'ctx>, rhs: IntValue<'ctx>) {
+        let i64_type = self.context.i64_type();
+
+        let division_error_fn = self.error_fn("__kaleidoscope_division_error", &[i64_type.into(), i64_type.into()], division_error as usize);
+
+        let nonzero = self.builder.build_int_compare(IntPredicate::NE, rhs, i64_type.const_int(0, false), "nonzero");
+        let min = self.builder.build_int_compare(IntPredicate::EQ, lhs, i64_type.const_int(i64::MIN as u64, true), "lhsmin");
+        let minus_one = self.builder.build_int_compare(IntPredicate::EQ, rhs, i64_type.const_int(-1i64 as u64, true), "rhsminusone");
+        let overflows = self.builder.build_and(min, minus_one, "overflows");
+        let defined = self.builder.build_and(nonzero, self.builder.build_not(overflows, "fits"), "defined");
+
+        self.compile_check(defined, division_error_fn, &[lhs.into(), rhs.into()]);
+    }
+
+    // Compiles `left && right` or `left || right` into a branch around `right`, which is only
+    // evaluated if `left` doesn't decide the result. Both evaluate to 1 or 0 of type `ty`, or true or
+    // false as bools.
+    fn compile_logical_op(&mut self, op: &Op, left: &Expr, right: &Expr, ty: &Type) -> Result<BasicValueEnum<'ctx>, CompileError> {
+        let parent = self.fn_value();
+
+        let lhs = self.compile_truth(left)?;
+        let lhs_bb = self.builder.get_insert_block().unwrap();
+
+        let rhs_bb = self.context.append_basic_block(parent, "logicrhs");
+        let cont_bb = self.context.append_basic_block(parent, "logiccont");
+
+        // a false `&&` or a true `||` skips the right-hand side
+        match op {
+            Op::And => self.builder.build_conditional_branch(lhs, rhs_bb, cont_bb),
+            _ => self.builder.build_conditional_branch(lhs, cont_bb, rhs_bb)
+        };
+
+        self.builder.position_at_end(rhs_bb);
+        let rhs = self.compile_truth(right)?;
+        self.builder.build_unconditional_branch(cont_bb);
+
+        let rhs_bb = self.builder.get_insert_block().unwrap();
+
+        self.builder.position_at_end(cont_bb);
+
+        let phi = self.builder.build_phi(self.context.bool_type(), "logictmp");
+        phi.add_incoming(&[
+            (&lhs, lhs_bb),
+            (&rhs, rhs_bb)
+        ]);
+
+        Ok(self.truth_value(phi.as_basic_value().into_int_value(), ty))
+    }
+
+    // The LLVM type of values of type `ty`: a double for a number, an `i64` for an int and an `i1` for
+    // a bool, and otherwise a pointer, to the bytes of a string, to an array (see `array_type`), to
+    // the fields of a struct, or to the closure record of a function (see `compile_indirect_call`).
+    fn llvm_type(&self, ty: &Type) -> BasicTypeEnum<'ctx> {
+        match ty {
+            Type::Number => self.context.f64_type().into(),
+            Type::Int => self.context.i64_type().into(),
+            Type::Bool => self.context.bool_type().into(),
+            Type::Str | Type::Fn(..) => self.context.i8_type().ptr_type(AddressSpace::Generic).into(),
+            Type::Array(element) => self.array_type(element).ptr_type(AddressSpace::Generic).into(),
+            Type::Struct(name, fields) => self.struct_type(name, fields).ptr_type(AddressSpace::Generic).into(),
+            Type::Var(_) => unreachable!("generic functions are only compiled for the types they are used at")
+        }
+    }
+
+    // The LLVM type of arrays of `element`s: their length, then the elements.
+    fn array_type(&self, element: &Type) -> StructType<'ctx> {
+        self.context.struct_type(&[self.context.i64_type().into(), self.llvm_type(element).array_type(0).into()], false)
+    }
+
+    // The LLVM type of `name` structs whose fields have the types `fields`. Each instance of a generic
+    // struct has its own, named after it.
+    fn struct_type(&self, name: &Name, fields: &[Type]) -> StructType<'ctx> {
+        let type_name = Type::Struct(name.to_owned(), fields.to_vec()).to_string();
+
+        match self.module.get_struct_type(&type_name) {
+            Some(struct_type) => struct_type,
+            None => {
+                let struct_type = self.context.opaque_struct_type(&type_name);
+                let field_types: Vec<BasicTypeEnum> = fields.iter().map(|field| self.llvm_type(field)).collect();
+
+                struct_type.set_body(&field_types, false);
+                struct_type
+            }
+        }
+    }
+
+    // The LLVM type of the code of closures of the function type `ty`, which takes the closure record
+    // before the arguments.
+    fn code_type(&self, ty: &Type) -> FunctionType<'ctx> {
+        match ty {
+            Type::Fn(params, ret) => {
+                let mut param_types: Vec<BasicTypeEnum> = vec![self.context.i8_type().ptr_type(AddressSpace::Generic).into()];
+                param_types.extend(params.iter().map(|param| self.llvm_type(param)));
+
+                self.llvm_type(ret).fn_type(&param_types, false)
+            },
+            _ => unreachable!("only functions are called")
+        }
+    }
+
+    // Declares the function `symbol`, taking `params` of types `param_types` and returning a `ret`, in
+    // the current module, or reuses its earlier declaration there, e.g. by an `extern`.
+    fn declare_fn(&self, symbol: &str, params: &[Name], param_types: &[Type], ret: &Type) -> FunctionValue<'ctx> {
+        let fun = match self.module.get_function(symbol) {
+            Some(fun) => fun,
+            None => {
+                let param_types: Vec<BasicTypeEnum> = param_types.iter().map(|ty| self.llvm_type(ty)).collect();
+                self.module.add_function(symbol, self.llvm_type(ret).fn_type(&param_types, false), None)
+            }
+        };
+
+        // set arguments names
+        for (arg, param) in fun.get_param_iter().zip(params) {
+            match arg {
+                BasicValueEnum::FloatValue(arg) => arg.set_name(param),
+                BasicValueEnum::IntValue(arg) => arg.set_name(param),
+                BasicValueEnum::PointerValue(arg) => arg.set_name(param),
+                _ => {}
+            }
+        }
+
+        fun
+    }
+
+    // Compiles the definition `name`, of the function type `signature`, whose expressions have the
+    // types `types` in terms of it. A generic one is compiled for each instance as calls need it. An
+    // earlier `extern` declaring it is reused. Redefining a function compiles it under a new symbol,
+    // if allowed at all.
+    fn compile_fn(&mut self, name: &str, params: &Vec<Name>, body: &Expr, types: Vec<Type>, signature: Signature, span: Span) -> Result<(), CompileError> {
+        self.check_signature(name, params, span)?;
+
+        let symbol = if !self.defined.contains(name) {
+            name.to_owned()
+        } else if self.allow_redefinition {
+            self.redefinition_count += 1;
+            format!("{}.{}", name, self.redefinition_count)
+        } else {
+            return Err(CompileError::Redefinition { name: name.to_owned(), span });
+        };
+
+        // calls from here on, including recursive ones in the body, go to the new symbol
+        let old_symbol = self.symbols.insert(name.to_owned(), symbol.clone());
+        let old_signature = self.symbol_signatures.insert(symbol.clone(), signature.clone());
+
+        let definition = Rc::new(Definition {
+            name: name.to_owned(),
+            params: params.to_owned(),
+            body: body.clone(),
+            types,
+            signature,
+            callees: self.callees(body)
+        });
+
+        if !definition.signature.vars.is_empty() {
+            self.definitions.insert(symbol, definition);
+        } else if let Err(err) = self.compile_body(&symbol, &definition, &[]) {
+            match old_signature {
+                Some(signature) => self.symbol_signatures.insert(symbol, signature),
+                None => self.symbol_signatures.remove(&symbol)
+            };
+            match old_symbol {
+                Some(symbol) => self.symbols.insert(name.to_owned(), symbol),
+                None => self.symbols.remove(name)
+            };
+
+            return Err(err);
+        }
+
+        self.defined.insert(name.to_owned());
+        Ok(())
+    }
+
+    // Compiles the instance of `definition` whose type variables take the types `args` into the
+    // function `symbol`, reusing an earlier declaration of it, and discarding it if that fails. Any
+    // function being compiled meanwhile is carried on with afterwards.
+    fn compile_body(&mut self, symbol: &str, definition: &Definition, args: &[Type]) -> Result<FunctionValue<'ctx>, CompileError> {
+        let (param_types, ret) = definition.signature.instantiate(args);
+
+        let declared = self.module.get_function(symbol).is_some();
+        let function = self.declare_fn(symbol, &definition.params, &param_types, &ret);
+
+        let outer_block = self.builder.get_insert_block();
+        let outer_fn = self.fn_value_opt;
+        let outer_variables = std::mem::take(&mut self.variables);
+        let outer_types = std::mem::replace(&mut self.expr_types, definition.expr_types(args));
+        let outer_callees = std::mem::replace(&mut self.callees, definition.callees.clone());
+
+        let compiled = self.compile_fn_body(function, definition, &param_types, &ret);
+
+        self.callees = outer_callees;
+        self.expr_types = outer_types;
+        self.variables = outer_variables;
+        self.fn_value_opt = outer_fn;
+
+        if let Some(block) = outer_block {
+            self.builder.position_at_end(block);
+        }
+
+        match compiled {
+            Ok(()) => Ok(function),
+            Err(err) => {
+                // keep a declaration made before, which calls may already rely on
+                if declared {
+                    for block in function.get_basic_blocks() {
+                        unsafe {
+                            block.delete().ok();
+                        }
+                    }
+                } else {
+                    unsafe {
+                        function.delete();
+                    }
+                }
+
+                Err(err)
+            }
+        }
+    }
+
+    fn compile_fn_body(&mut self, function: FunctionValue<'ctx>, definition: &Definition, param_types: &[Type], ret: &Type) -> Result<(), CompileError> {
+        let entry = self.context.append_basic_block(function, "entry");
+
+        self.builder.position_at_end(entry);
+
+        // update fn field
+        self.fn_value_opt = Some(function);
+
+        for ((param, ty), arg) in definition.params.iter().zip(param_types).zip(function.get_param_iter()) {
+            let alloca = self.create_entry_block_alloca(param, ty);
+
+            self.builder.build_store(alloca, arg);
+
+            self.variables.insert(param.to_owned(), (alloca, ty.clone()));
+        }
+
+        let body = self.compile_expr(&definition.body)?;
+
+        // top-level expressions return a number whatever their type: an int or a bool converted, and
+        // 0 for anything else
+        let f64_type = self.context.f64_type();
+        let body = match (ret, self.type_of(&definition.body)) {
+            (Type::Number, Type::Int) => self.builder.build_signed_int_to_float(body.into_int_value(), f64_type, "retval").into(),
+            (Type::Number, Type::Bool) => self.builder.build_unsigned_int_to_float(body.into_int_value(), f64_type, "retval").into(),
+            (Type::Number, Type::Number) => body,
+            (Type::Number, _) => f64_type.const_float(0.0).into(),
+            _ => body
+        };
+
+        self.builder.build_return(Some(&body));
+
+        self.verify_fn(&definition.name, function)
+    }
+
+    // Declares an `extern`, defining it as a thunk into the host function of the same name if one is
+    // registered and the function has no body yet. The host function must take and return values of
+    // the types the `extern` declares.
+    fn compile_extern(&mut self, name: &str, params: &[Name], signature: &Signature, span: Span) -> Result<(), CompileError> {
+        self.check_signature(name, params, span)?;
+
+        let host_fn = self.host_fns.iter().rev().find(|(host_name, _)| host_name == name).map(|(_, host_fn)| host_fn);
+
+        match host_fn {
+            Some(host_fn) if host_fn.arity() != params.len() =>
+                Err(CompileError::ArityMismatch { name: name.to_owned(), expected: host_fn.arity(), found: params.len(), span }),
+            Some(host_fn) if !self.defined.contains(name) => {
+                let host_types = host_fn.params().iter().cloned().chain(std::iter::once(host_fn.ret()));
+                let declared_types = signature.params.iter().chain(std::iter::once(&signature.ret));
+
+                for (host_type, ty) in host_types.map(host_value_type).zip(declared_types) {
+                    if host_type != *ty {
+                        return Err(CompileError::TypeMismatch { expected: host_type, found: ty.clone(), span });
+                    }
+                }
+
+                self.compile_host_thunk(name, params, signature, host_fn);
+                self.defined.insert(name.to_owned());
+                Ok(())
+            },
+            // an earlier definition stands
+            _ if self.defined.contains(name) => Ok(()),
+            _ => {
+                let function = self.declare_fn(name, params, &signature.params, &signature.ret);
+
+                // the compiler provides the runtime functions itself
+                if let Some(address) = runtime_fn_address(name, signature) {
+                    self.execution_engine.add_global_mapping(&function, address);
+                    self.defined.insert(name.to_owned());
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // Defines `name` to store its arguments in an array of 64-bit slots and pass that, along with the
+    // addresses of the trap and `host_fn`, to `host::call_host_fn`, which returns the result in a slot
+    // too. That one is bound through the execution engine's global mapping rather than looked up by
+    // symbol.
+    fn compile_host_thunk(&self, name: &str, params: &[Name], signature: &Signature, host_fn: &HostFn) {
+        let i64_type = self.context.i64_type();
+        let i8_ptr_type = self.context.i8_type().ptr_type(AddressSpace::Generic);
+        let i64_ptr_type = i64_type.ptr_type(AddressSpace::Generic);
+
+        let fn_type = i64_type.fn_type(&[i8_ptr_type.into(), i8_ptr_type.into(), i64_ptr_type.into()], false);
+        let call_host_fn = self.runtime_fn("__call_host_fn", fn_type, Some(host::call_host_fn as usize));
+
+        let function = self.declare_fn(name, params, &signature.params, &signature.ret);
+        let entry = self.context.append_basic_block(function, "entry");
+
+        self.builder.position_at_end(entry);
+
+        let args = self.builder.build_array_alloca(i64_type, i64_type.const_int(params.len() as u64, false), "args");
+
+        for (i, arg) in function.get_param_iter().enumerate() {
+            let arg_ptr = unsafe { self.builder.build_gep(args, &[i64_type.const_int(i as u64, false)], "argptr") };
+            self.builder.build_store(arg_ptr, self.host_slot(arg));
+        }
+
+        let host_fn_ptr = i64_type.const_int(host_fn as *const HostFn as u64, false).const_to_pointer(i8_ptr_type);
+        let slot = self.builder.build_call(call_host_fn, &[self.trap_ptr().into(), host_fn_ptr.into(), args.into()], "tmp")
+            .try_as_basic_value()
+            .left()
+            .expect("host functions return a slot")
+            .into_int_value();
+
+        self.builder.build_return(Some(&self.host_slot_value(slot, &signature.ret)));
+    }
+
+    // The 64-bit slot holding `value`, a number, an int, a bool or a string, for a host function to
+    // read. See `host::HostValue::from_slot`.
+    fn host_slot(&self, value: BasicValueEnum<'ctx>) -> IntValue<'ctx> {
+        let i64_type = self.context.i64_type();
+
+        match value {
+            BasicValueEnum::FloatValue(value) => self.builder.build_bitcast(value, i64_type, "slot").into_int_value(),
+            BasicValueEnum::IntValue(value) => self.builder.build_int_z_extend_or_bit_cast(value, i64_type, "slot"),
+            BasicValueEnum::PointerValue(value) => self.builder.build_ptr_to_int(value, i64_type, "slot"),
+            _ => unreachable!("host functions only take numbers, ints, bools and strings")
+        }
+    }
+
+    // The value of type `ty`, a number, an int or a bool, that a host function returned in `slot`.
+    fn host_slot_value(&self, slot: IntValue<'ctx>, ty: &Type) -> BasicValueEnum<'ctx> {
+        match ty {
+            Type::Number => self.builder.build_bitcast(slot, self.context.f64_type(), "hostval"),
+            Type::Int => slot.into(),
+            Type::Bool => self.builder.build_int_truncate(slot, self.context.bool_type(), "hostval").into(),
+            _ => unreachable!("host functions only return numbers, ints and bools")
+        }
+    }
+
+    // Binds `extern` declarations named `name`, compiled from now on, to `host_fn`.
+    pub fn register_host_fn(&mut self, name: &str, host_fn: HostFn) {
+        self.host_fns.push((name.to_owned(), Rc::new(host_fn)));
+    }
+
+    // Every host function compiled code may call, for handles to compiled functions to hold on to.
+    pub(crate) fn host_fns(&self) -> Vec<Rc<HostFn>> {
+        self.host_fns.iter().map(|(_, host_fn)| Rc::clone(host_fn)).collect()
+    }
+
+    pub(crate) fn trap(&self) -> Rc<Trap> {
+        Rc::clone(&self.trap)
+    }
+
+    // Chooses whether a runtime error in code compiled from now on is returned to the Rust code that
+    // called it, as it is by default, or left to the runtime of a native executable, which reports it
+    // and aborts. The checks that return errors cost a little after every call.
+    pub fn trap_runtime_errors(&mut self, trap: bool) {
+        self.trap_runtime_errors = trap;
+    }
+
+    // Chooses when `for` loops compiled from now on check their end condition.
+    pub fn set_loop_check(&mut self, loop_check: LoopCheck) {
+        self.loop_check = loop_check;
+    }
+
+    // Lets functions be defined again, replacing their earlier body for code compiled from then on.
+    pub fn allow_redefinition(&mut self, allow: bool) {
+        self.allow_redefinition = allow;
+    }
+
+    // Makes an `extern` with no registered host function, nor a definition in the same program, a
+    // compile error.
+    pub fn require_host_fns(&mut self, require: bool) {
+        self.require_host_fns = require;
+    }
+
+    // The program's `main`, if it defines one, which must take no parameters and return a number.
+    pub fn jit_compile_main(&mut self) -> Result<Option<Func0<'ctx>>, CallError> {
+        match self.get_fn::<Func0>("main") {
+            Err(CallError::UnknownFunction(_)) => Ok(None),
+            main_fn => main_fn.map(Some)
+        }
+    }
+
+    // Gets a handle for calling the compiled function `name` from Rust, e.g. `get_fn::<Func2>("f")`,
+    // after checking that it takes as many arguments as the handle passes. Only functions taking and
+    // returning numbers can be called so; a generic one is called at its instance for numbers.
+    pub fn get_fn<F: JitFn<'ctx>>(&mut self, name: &str) -> Result<F, CallError> {
+        let function = self.numeric_function(name)?;
+        let symbol = function.get_name().to_string_lossy().into_owned();
+
+        F::lookup(self, name, &symbol, function.count_params() as usize)
+    }
+
+    // The function `name` at the instance taking and returning numbers only, declared in the current
+    // module. An instance not compiled yet is compiled into a fresh module, as the current one may
+    // already be running.
+    pub(crate) fn numeric_function(&mut self, name: &str) -> Result<FunctionValue<'ctx>, CallError> {
+        let symbol = self.symbol(name).to_owned();
+        let signature = self.symbol_signatures.get(&symbol).cloned()
+            .ok_or_else(|| CallError::UnknownFunction(name.to_owned()))?;
+
+        let params = vec![Type::Number; signature.params.len()];
+        let args = signature.instance(&params, &Type::Number)
+            .ok_or_else(|| CallError::NotNumeric { name: name.to_owned(), signature: signature.to_string() })?;
+        let ty = Type::Fn(params, Box::new(Type::Number));
+
+        if self.definitions.contains_key(&symbol) && !self.instances.contains(&instance_symbol(&symbol, &args)) {
+            self.start_module().map_err(|err| CallError::Jit(err.to_string()))?;
+        }
+
+        self.function(name, &ty)
+            .map_err(|err| CallError::Jit(err.to_string()))?
+            .ok_or_else(|| CallError::UnknownFunction(name.to_owned()))
+    }
+
+    // Runs the anonymous function compiled for a top-level expression, returning its value, or the
+    // runtime error that stopped it.
+    pub fn jit_eval_anon_expr(&self, name: &str) -> Result<f64, CallError> {
+        let anon_fn = unsafe { self.execution_engine.get_function::<MainFunc>(name) }
+            .map_err(|err| CallError::Jit(format!("{:?}", err)))?;
+
+        self.trap.finish(unsafe { anon_fn.call() }).map_err(CallError::Runtime)
+    }
+
+    // The type inferred for the function `name`.
+    pub fn signature(&self, name: &str) -> Option<&Signature> {
+        self.signatures.get(name)
+    }
+
+    // Checks `program` against the functions and structs declared so far, without generating any
+    // code, and reports every problem found: names, arities and fields first, then types. See
+    // `sema::check_program` and `infer::infer_program`. The program must then be compiled before
+    // any other.
+    pub fn check_program<'p>(&self, program: &'p Program) -> Result<CheckedProgram<'p>, Vec<CompileError>> {
+        let declared = self.prototypes.iter()
+            .map(|(name, params)| (name.to_owned(), params.len()))
+            .collect();
+
+        sema::check_program(program, &declared, &self.structs)?;
+        let types = infer::infer_program(program, &self.signatures, &self.defined, &self.structs)?;
+
+        Ok(CheckedProgram { program, types })
+    }
+
+    // Compiles every definition in a checked program, using the types inferred while checking it.
+    // Top-level expressions are wrapped into anonymous functions named `__anon_expr_N`, whose names
+    // are returned in source order so they can be evaluated in that order.
+    pub fn compile_program(&mut self, checked: CheckedProgram) -> Result<Vec<String>, CompileError> {
+        let exprs = checked.program;
+        let mut anon_exprs = Vec::new();
+
+        if self.require_host_fns {
+            self.check_host_fns(exprs, &checked.types.signatures)?;
+        }
+        let mut signatures = checked.types.signatures.into_iter();
+        let mut expr_types = checked.types.exprs.into_iter();
+
+        for outer_expr in exprs {
+            let types = expr_types.next().expect("every item has its types");
+
+            match &outer_expr.kind {
+                ExprKind::Function(name, params, _, expr) => {
+                    let signature = signatures.next().expect("every definition has a signature");
+
+                    self.compile_fn(name, params, expr, types, signature.clone(), outer_expr.span)?;
+                    self.prototypes.insert(name.to_owned(), params.to_owned());
+                    self.signatures.insert(name.to_owned(), signature);
+                },
+                ExprKind::Extern(name, params, _) => {
+                    let signature = signatures.next().expect("every declaration has a signature");
+
+                    // an earlier definition keeps its own signature
+                    if !self.defined.contains(name) {
+                        self.signatures.insert(name.to_owned(), signature.clone());
+                        self.symbol_signatures.insert(name.to_owned(), signature.clone());
+                    }
+                    self.compile_extern(name, params, &signature, outer_expr.span)?;
+                    self.prototypes.insert(name.to_owned(), params.to_owned());
+                },
+                ExprKind::Struct(name, fields) => {
+                    // values of a struct may outlive its declaration, so even the REPL can only
+                    // repeat it unchanged
+                    if self.structs.fields(name).is_some_and(|declared| declared != fields || !self.allow_redefinition) {
+                        return Err(CompileError::Redefinition { name: name.to_owned(), span: outer_expr.span });
+                    }
+                    self.structs.declare(name, fields);
+                },
+                _ => {
+                    let name = format!("__anon_expr_{}", self.anon_expr_count);
+                    self.anon_expr_count += 1;
+
+                    // whatever its type, the value is returned as a double
+                    let signature = Signature { vars: Vec::new(), params: Vec::new(), ret: Type::Number };
+
+                    self.compile_fn(&name, &Vec::new(), outer_expr, types, signature, outer_expr.span)?;
+                    anon_exprs.push(name);
+                }
+            }
+        }
+
+        // function passes already ran as each function was compiled
+        if let Some(mpm) = self.opt.module_pass_manager() {
+            mpm.run_on(&self.module);
+        }
+
+        Ok(anon_exprs)
+    }
+
+    // Checks that every `extern` in `exprs`, whose definitions and declarations have the signatures
+    // `signatures`, will be bound to a registered host function or a runtime one, or has a definition
+    // before or in the program. Done before anything is compiled, so that a program failing it
+    // declares nothing that later ones could call.
+    fn check_host_fns(&self, exprs: &Program, signatures: &[Signature]) -> Result<(), CompileError> {
+        let mut signatures = signatures.iter();
+
+        for item in exprs {
+            match &item.kind {
+                ExprKind::Function(..) => {
+                    signatures.next();
+                },
+                ExprKind::Extern(name, ..) => {
+                    let signature = signatures.next().expect("every declaration has a signature");
+                    let resolved = self.defined.contains(name)
+                        || self.host_fns.iter().any(|(host_name, _)| host_name == name)
+                        || runtime_fn_address(name, signature).is_some()
+                        || exprs.iter().any(|item| matches!(&item.kind, ExprKind::Function(fn_name, ..) if fn_name == name));
+
+                    if !resolved {
+                        return Err(CompileError::UnresolvedExtern { name: name.to_owned(), span: item.span });
+                    }
+                },
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    // Gives the module a C `main` for native executables, which evaluates the given top-level
+    // expressions in order and then runs the program's own `main`, if any. That one is renamed to
+    // `__kaleidoscope_main` first, since its `double` return type doesn't fit a C entry point.
+    pub fn build_entry_point(&mut self, anon_exprs: &[String]) -> Result<(), CompileError> {
+        let mut callees: Vec<FunctionValue> = Vec::with_capacity(anon_exprs.len() + 1);
+
+        for name in anon_exprs {
+            callees.push(self.module.get_function(name).ok_or("Unknown top-level expression.")?);
+        }
+
+        if let Some(main_fn) = self.module.get_function("main") {
+            main_fn.as_global_value().as_pointer_value().set_name("__kaleidoscope_main");
+            callees.push(main_fn);
+        }
+
+        let i32_type = self.context.i32_type();
+        let entry_fn = self.module.add_function("main", i32_type.fn_type(&[], false), None);
+        let entry = self.context.append_basic_block(entry_fn, "entry");
+
+        self.builder.position_at_end(entry);
+
+        for callee in callees {
+            self.builder.build_call(callee, &[], "tmp");
+        }
+
+        self.builder.build_return(Some(&i32_type.const_int(0, false)));
+
+        Ok(())
+    }
+
+    // Creates a target machine for the host, at the configured optimization level, and sets the
+    // module up for it.
+    fn host_target_machine(&self) -> Result<TargetMachine, CompileError> {
+        Target::initialize_native(&InitializationConfig::default())?;
+
+        let triple = TargetMachine::get_default_triple();
+        let target = Target::from_triple(&triple).map_err(|e| e.to_string())?;
+        let target_machine = target.create_target_machine(
+            &triple,
+            &TargetMachine::get_host_cpu_name().to_string(),
+            &TargetMachine::get_host_cpu_features().to_string(),
+            self.opt.level,
+            RelocMode::PIC,
+            CodeModel::Default
+        ).ok_or("Unable to create a target machine for the host")?;
+
+        self.module.set_triple(&triple);
+        self.module.set_data_layout(&target_machine.get_target_data().get_data_layout());
+
+        Ok(target_machine)
+    }
+
+    // Compiles the module to native code for the host machine, as an object file or as assembly
+    // depending on `file_type`.
+    pub fn emit_machine_code(&self, file_type: FileType) -> Result<MemoryBuffer, CompileError> {
+        let target_machine = self.host_target_machine()?;
+
+        target_machine.write_to_memory_buffer(&self.module, file_type).map_err(|e| e.to_string().into())
+    }
+
+    // Compiles the module to a native object file for the host machine.
+    pub fn write_object_file(&self, path: &Path) -> Result<(), CompileError> {
+        let target_machine = self.host_target_machine()?;
+
+        target_machine.write_to_file(&self.module, FileType::Object, path).map_err(|e| e.to_string().into())
+    }
+
+    // Moves compilation on to a fresh module, added to the execution engine next to the earlier
+    // ones. Anything compiled from now on can still call the functions defined before.
+    pub fn start_module(&mut self) -> Result<(), CompileError> {
+        self.module_count += 1;
+
+        let module = self.context.create_module(&format!("module{}", self.module_count));
+        self.execution_engine.add_module(&module)
+            .map_err(|_| "Unable to add module to the execution engine")?;
+
+        self.fpm = self.opt.function_pass_manager(&module);
+        self.module = module;
+
+        Ok(())
+    }
+
+    pub fn mk_compiler(context: &'ctx Context, module_name: &str) -> Result<CodeGen<'ctx>, Box<dyn Error>> {
+      Self::mk_compiler_with(context, module_name, OptConfig::default())
+    }
+
+    // Like `mk_compiler`, optimizing as `opt` says rather than as `-O2` does.
+    pub fn mk_compiler_with(context: &'ctx Context, module_name: &str, opt: OptConfig) -> Result<CodeGen<'ctx>, Box<dyn Error>> {
+      let module = context.create_module(module_name);
+      let fpm = opt.function_pass_manager(&module);
+      let execution_engine = module.create_jit_execution_engine(opt.level)?;
+      Ok(CodeGen {
+          context,
+          module,
+          builder: context.create_builder(),
+          fpm,
+          execution_engine,
+          fn_value_opt: None,
+          variables: HashMap::new(),
+          expr_types: HashMap::new(),
+          callees: HashMap::new(),
+          prototypes: HashMap::new(),
+          signatures: HashMap::new(),
+          structs: Structs::default(),
+          anon_expr_count: 0,
+          lambda_count: 0,
+          module_count: 0,
+          host_fns: Vec::new(),
+          require_host_fns: false,
+          opt,
+          trap_runtime_errors: true,
+          trap: Rc::new(Trap::new()),
+          defined: HashSet::new(),
+          allow_redefinition: false,
+          symbols: HashMap::new(),
+          redefinition_count: 0,
+          symbol_signatures: HashMap::new(),
+          definitions: HashMap::new(),
+          instances: HashSet::new(),
+          loop_check: LoopCheck::BeforeBody
+      })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host::IntoHostFn;
+    use crate::parser::parse_program;
+
+    // Compiles `source` into a module of its own and evaluates its top-level expressions in order,
+    // returning the value of the last one.
+    fn eval(codegen: &mut CodeGen, source: &str) -> Result<f64, Vec<CompileError>> {
+        let program = parse_program(source).expect("test source parses");
+        let checked = codegen.check_program(&program)?;
+
+        codegen.start_module().map_err(|err| vec![err])?;
+        let anon_exprs = codegen.compile_program(checked).map_err(|err| vec![err])?;
+
+        Ok(anon_exprs.iter().fold(0.0, |_, anon_expr| codegen.jit_eval_anon_expr(anon_expr).expect("compiled code runs")))
+    }
+
+    // Evaluates `source` as `eval` does, expecting a runtime error to stop it, and returns the error.
+    fn runtime_error(codegen: &mut CodeGen, source: &str) -> RuntimeError {
+        let program = parse_program(source).expect("test source parses");
+        let checked = codegen.check_program(&program).expect("test source checks");
+
+        codegen.start_module().unwrap();
+        let anon_exprs = codegen.compile_program(checked).unwrap();
+
+        match anon_exprs.iter().map(|anon_expr| codegen.jit_eval_anon_expr(anon_expr)).find_map(Result::err) {
+            Some(CallError::Runtime(err)) => err,
+            res => panic!("expected a runtime error, got {:?}", res)
+        }
+    }
+
+    #[test]
+    fn compile_error_render_test() {
+        let source = "def f(x)\n  x + y";
+        let err = CompileError::UnknownVariable { name: "y".to_string(), span: Span::new(15, 16) };
+
+        assert_eq!(err.render("test.ks", source), "test.ks:2:7: error: unknown variable `y`\n  |\n2 |   x + y\n  |       ^");
+
+        let err = CompileError::ArityMismatch { name: "f".to_string(), expected: 1, found: 2, span: Span::new(0, 8) };
+        assert_eq!(err.to_string(), "`f` takes 1 argument(s) but 2 were given");
+    }
+
+    #[test]
+    fn assignment_test() {
+        let context = Context::create();
+        let mut codegen = CodeGen::mk_compiler(&context, "test").unwrap();
+
+        // `a = b = 2` assigns to `b`, then to `a`, and has the value assigned
+        assert_eq!(eval(&mut codegen, "var a = 1, b in { a = b = 2; a + b }").unwrap(), 4.0);
+        assert_eq!(eval(&mut codegen, "var a, b, c in a = b = c = 3").unwrap(), 3.0);
+    }
+
+    #[test]
+    fn strings_test() {
+        let context = Context::create();
+        let mut codegen = CodeGen::mk_compiler(&context, "test").unwrap();
+
+        codegen.register_host_fn("strlen", (|s: String| s.chars().count() as f64).into_host_fn());
+
+        assert_eq!(eval(&mut codegen, "extern strlen(s: str); strlen(\"tab\\there \\\"q\\\"\\n\")").unwrap(), 13.0);
+
+        // Strings can be stored, passed and returned like any other value
+        eval(&mut codegen, "def pick(c) if c then \"yes\" else \"no\"; def twice(s) strlen(s) * 2").unwrap();
+        assert_eq!(eval(&mut codegen, "var s = pick(0) in twice(s) + strlen(pick(1))").unwrap(), 7.0);
+        assert_eq!(eval(&mut codegen, "extern printstr(s); { printstr(\"\"); strlen(\"\") }").unwrap(), 0.0);
+
+        // The built-in `printstr` refuses anything but a string
+        assert!(eval(&mut codegen, "printstr(1)").is_err());
+    }
+
+    #[test]
+    fn type_inference_test() {
+        let context = Context::create();
+        let mut codegen = CodeGen::mk_compiler(&context, "test").unwrap();
+
+        eval(&mut codegen, "extern printstr(s); def id(x) x; def twice(x) id(x) * 2").unwrap();
+        assert_eq!(eval(&mut codegen, "printstr(id(\"\")); twice(id(21))").unwrap(), 42.0);
+        assert_eq!(codegen.signature("twice").map(|signature| signature.to_string()), Some("(number) -> number".to_string()));
+
+        match eval(&mut codegen, "twice(\"x\") + 1; id(1) + \"y\"") {
+            Err(errors) => {
+                let found: Vec<(String, usize)> = errors.iter().map(|err| (err.to_string(), err.span().unwrap().start)).collect();
+                assert_eq!(found, vec![
+                    ("expected `number`, found `str`".to_string(), 6),
+                    ("expected `number`, found `str`".to_string(), 24)
+                ]);
+            },
+            res => panic!("expected type errors, got {:?}", res)
+        }
+
+        // Redefining a function may change its type, for code compiled afterwards
+        codegen.allow_redefinition(true);
+        eval(&mut codegen, "def twice(x) x").unwrap();
+        assert_eq!(codegen.signature("twice").map(|signature| signature.to_string()), Some("('a) -> 'a".to_string()));
+    }
+
+    #[test]
+    fn ints_test() {
+        let context = Context::create();
+        let mut codegen = CodeGen::mk_compiler(&context, "test").unwrap();
+
+        eval(&mut codegen, "
+            def half(n: int) -> int n / 2;
+            def sum(a: [int]) var total = 0 in { for i = 0, i < len(a) in total = total + a[i]; total };
+            def positive(x: int) -> bool x > 0;
+            def sign(x: int) if positive(x) then 1 else if x == 0 then 0 else 0 - 1
+        ").unwrap();
+
+        assert_eq!(codegen.signature("sum").map(|signature| signature.to_string()), Some("([int]) -> int".to_string()));
+        assert_eq!(codegen.signature("sign").map(|signature| signature.to_string()), Some("(int) -> number".to_string()));
+
+        assert_eq!(eval(&mut codegen, "half(7) + half(0 - 7)").unwrap(), 0.0);
+        assert_eq!(eval(&mut codegen, "sum([1, 2, 3]) * 2").unwrap(), 12.0);
+        assert_eq!(eval(&mut codegen, "sign(5) + sign(0 - 5) + sign(0)").unwrap(), 0.0);
+        assert_eq!(eval(&mut codegen, "positive(3)").unwrap(), 1.0);
+        assert_eq!(eval(&mut codegen, "!positive(3) || positive(1)").unwrap(), 1.0);
+
+        // Numbers and ints don't mix
+        assert!(eval(&mut codegen, "half(1.5)").is_err());
+        assert!(eval(&mut codegen, "sum([1, 2.5])").is_err());
+    }
+
+    #[test]
+    fn arrays_test() {
+        let context = Context::create();
+        let mut codegen = CodeGen::mk_compiler(&context, "test").unwrap();
+
+        eval(&mut codegen, "
+            def sum(a) var total in { for i = 0, i < len(a) in total = total + a[i]; total };
+            def squares(n) var a = alloc(n) in { for i = 0, i < n in a[i] = i * i; a }
+        ").unwrap();
+
+        assert_eq!(eval(&mut codegen, "sum([1, 2, 3.5])").unwrap(), 6.5);
+        assert_eq!(eval(&mut codegen, "sum(squares(4)) + len(alloc(2.7)) + len([])").unwrap(), 16.0);
+        assert_eq!(eval(&mut codegen, "var grid = [[1, 2], [3, 4]] in { grid[1][0] = 10; grid[1][0] + grid[0][1] }").unwrap(), 12.0);
+    }
+
+    #[test]
+    fn structs_test() {
+        let context = Context::create();
+        let mut codegen = CodeGen::mk_compiler(&context, "test").unwrap();
+
+        eval(&mut codegen, "
+            struct Complex { re, im };
+            def mul(a b) Complex { re: a.re * b.re - a.im * b.im, im: a.re * b.im + a.im * b.re };
+            def norm(c) c.re * c.re + c.im * c.im
+        ").unwrap();
+
+        assert_eq!(eval(&mut codegen, "var i = Complex { im: 1, re: 0 } in mul(i, i).re").unwrap(), -1.0);
+        assert_eq!(eval(&mut codegen, "var c = Complex { re: 3, im: 0 } in { c.im = 4; norm(c) }").unwrap(), 25.0);
+
+        // Fields may hold any value, and structs may go into arrays
+        eval(&mut codegen, "struct Named { name, values }").unwrap();
+        assert_eq!(eval(&mut codegen, "[Named { name: \"a\", values: [1, 2] }][0].values[1]").unwrap(), 2.0);
+
+        // A field name several structs share is that of the struct its object is
+        eval(&mut codegen, "struct Cell { im, re }").unwrap();
+        assert_eq!(eval(&mut codegen, "Cell { re: 2, im: 5 }.re + norm(Complex { re: 1, im: 0 })").unwrap(), 3.0);
+        assert!(eval(&mut codegen, "def re(c) c.re").is_err());
+
+        match eval(&mut codegen, "Complex { re: 1 }") {
+            Err(errors) => assert_eq!(errors[0].to_string(), "missing field `im` of struct `Complex`"),
+            res => panic!("expected a missing field, got {:?}", res)
+        }
+        match eval(&mut codegen, "struct Complex { re, im }") {
+            Err(errors) => assert!(matches!(&errors[0], CompileError::Redefinition { name, .. } if name == "Complex")),
+            res => panic!("expected a redefinition error, got {:?}", res)
+        }
+
+        // The REPL may repeat a declaration, but not change it
+        codegen.allow_redefinition(true);
+        eval(&mut codegen, "struct Complex { re, im }").unwrap();
+        assert!(eval(&mut codegen, "struct Complex { im, re }").is_err());
+    }
+
+    #[test]
+    fn function_values_test() {
+        let context = Context::create();
+        let mut codegen = CodeGen::mk_compiler(&context, "test").unwrap();
+
+        eval(&mut codegen, "
+            def square(x) x * x;
+            def negate(x) -x;
+            def apply(f x) f(x);
+            def integrate(f a b n) var total, dx = (b - a) / n in { for i = 0, i < n in total = total + f(a + (i + 0.5) * dx) * dx; total };
+            def pick(c) if c then square else negate
+        ").unwrap();
+
+        // Midpoints 0.25 and 0.75
+        assert_eq!(eval(&mut codegen, "integrate(square, 0, 1, 2)").unwrap(), 0.3125);
+        assert_eq!(eval(&mut codegen, "var f = square in { f = pick(0); f(3) + pick(1)(2) }").unwrap(), 1.0);
+        assert_eq!(eval(&mut codegen, "[square, negate][1](apply(square, 2))").unwrap(), -4.0);
+
+        // A parameter only shadows functions in its own body
+        eval(&mut codegen, "def call_square(square) square(2)").unwrap();
+        assert_eq!(eval(&mut codegen, "square(3) + call_square(negate)").unwrap(), 7.0);
+
+        // Host functions are values too
+        codegen.register_host_fn("halve", (|x: f64| x / 2.0).into_host_fn());
+        assert_eq!(eval(&mut codegen, "extern halve(x); apply(halve, 9)").unwrap(), 4.5);
+    }
+
+    #[test]
+    fn closures_test() {
+        let context = Context::create();
+        let mut codegen = CodeGen::mk_compiler(&context, "test").unwrap();
+
+        eval(&mut codegen, "
+            def adder(k) \\x -> x + k;
+            def compose(f g) \\x -> f(g(x));
+            def integrate(f a b n) var total, dx = (b - a) / n in { for i = 0, i < n in total = total + f(a + (i + 0.5) * dx) * dx; total };
+            def square(x) x * x
+        ").unwrap();
+
+        assert_eq!(eval(&mut codegen, "adder(2)(3)").unwrap(), 5.0);
+        assert_eq!(eval(&mut codegen, "compose(adder(1), square)(3)").unwrap(), 10.0);
+        assert_eq!(eval(&mut codegen, "(\\ -> 7)()").unwrap(), 7.0);
+
+        // Midpoints 0.25 and 0.75 of 2x + 1
+        assert_eq!(eval(&mut codegen, "var k = 2 in integrate(\\x -> k * x + 1, 0, 1, 2)").unwrap(), 2.0);
+
+        // Captured values are copied when the lambda is made
+        assert_eq!(eval(&mut codegen, "var k = 1 in var f = \\x -> x + k in { k = 10; f(0) }").unwrap(), 1.0);
+        assert_eq!(eval(&mut codegen, "var a = 1, b = 2 in [\\ -> a, \\ -> b][1]()").unwrap(), 2.0);
+        assert_eq!(eval(&mut codegen, "var x = 1 in (\\y -> \\z -> x + y + z)(2)(3)").unwrap(), 6.0);
+    }
+
+    #[test]
+    fn runtime_errors_test() {
+        let context = Context::create();
+        let mut codegen = CodeGen::mk_compiler(&context, "test").unwrap();
+
+        assert_eq!(runtime_error(&mut codegen, "var a = [1, 2] in a[2] = 5").to_string(),
+                   "runtime error: index 2 is out of bounds for an array of length 2");
+        assert_eq!(runtime_error(&mut codegen, "alloc(3)[-1]").to_string(),
+                   "runtime error: index -1 is out of bounds for an array of length 3");
+        assert_eq!(runtime_error(&mut codegen, "alloc(0 - 2)").to_string(),
+                   "runtime error: cannot allocate an array of length -2");
+
+        // The error stops every function the code is in, not just the one it happened in
+        assert_eq!(runtime_error(&mut codegen, "def div(a: int, b: int) -> int a / b; def f(x: int) { div(7, x); 1 }; f(0)").to_string(),
+                   "runtime error: integer division of 7 by zero");
+        assert_eq!(runtime_error(&mut codegen, "var g = \\i -> [1][i] in g(1) + 2").to_string(),
+                   "runtime error: index 1 is out of bounds for an array of length 1");
+
+        // and is gone once reported
+        assert_eq!(eval(&mut codegen, "alloc(3)[2.5]").unwrap(), 0.0);
     }
 }
@@ -0,0 +1,30 @@
+use crate::ast::Span;
+
+// Returns the 1-based line and column of byte `offset` in `source`.
+pub fn line_col(source: &str, offset: usize) -> (usize, usize) {
+  let before = &source[..offset.min(source.len())];
+  let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+
+  (before.matches('\n').count() + 1, before[line_start..].chars().count() + 1)
+}
+
+// Renders the source line containing the start of `span`, with the span underlined by carets:
+//
+//    |
+//  3 | def foo(x y
+//    |            ^
+pub fn excerpt(source: &str, span: Span) -> String {
+  let start = span.start.min(source.len());
+  let (line, column) = line_col(source, start);
+
+  let line_start = source[..start].rfind('\n').map(|i| i + 1).unwrap_or(0);
+  let line_end = source[start..].find('\n').map(|i| start + i).unwrap_or(source.len());
+  let width = source[start..span.end.max(start).min(line_end)].chars().count().max(1);
+
+  let gutter = " ".repeat(line.to_string().len());
+
+  format!("{} |\n{} | {}\n{} | {}{}",
+          gutter,
+          line, &source[line_start..line_end],
+          gutter, " ".repeat(column - 1), "^".repeat(width))
+}
@@ -0,0 +1,397 @@
+use std::fmt;
+
+use inkwell::context::Context;
+
+use crate::codegen::{self, CodeGen, CompileError, LoopCheck, RuntimeError};
+use crate::host::{HostFn, IntoHostFn};
+use crate::jit::{CallError, JitFn};
+use crate::optimize::OptConfig;
+use crate::parser::{self, Operators, ParseError};
+
+/**
+ * Anything that can go wrong while evaluating source text.
+ */
+#[derive(Debug)]
+pub enum Error {
+  Parse(Vec<ParseError>),
+  // Problems found by `sema` before compiling
+  Check(Vec<CompileError>),
+  Compile(CompileError),
+  // The execution engine failed to set up, or to produce compiled code
+  Jit(String),
+  // Compiled code was stopped while it ran, e.g. by an out-of-bounds index
+  Runtime(RuntimeError)
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Error::Parse(errors) => {
+        let messages: Vec<String> = errors.iter().map(|err| err.to_string()).collect();
+        write!(f, "{}", messages.join("\n\n"))
+      },
+      Error::Check(errors) => {
+        let messages: Vec<String> = errors.iter().map(|err| err.to_string()).collect();
+        write!(f, "{}", messages.join("\n"))
+      },
+      Error::Compile(err) => write!(f, "{}", err),
+      Error::Jit(message) => write!(f, "JIT error: {}", message),
+      Error::Runtime(err) => write!(f, "{}", err)
+    }
+  }
+}
+
+impl std::error::Error for Error {}
+
+impl From<Vec<ParseError>> for Error {
+  fn from(errors: Vec<ParseError>) -> Error {
+    Error::Parse(errors)
+  }
+}
+
+impl From<CompileError> for Error {
+  fn from(err: CompileError) -> Error {
+    Error::Compile(err)
+  }
+}
+
+/**
+ * A JIT session, borrowing the LLVM `Context` it compiles into. Source passed to `eval_source` may
+ * use everything defined by earlier calls.
+ *
+ * `extern` declarations only resolve to functions registered with `register_fn`, besides the
+ * built-in `putchard`, `printd` and `printstr`.
+ */
+pub struct Engine<'ctx> {
+  codegen: CodeGen<'ctx>,
+  // Operators defined with `def binary<op>` by source given to this engine
+  operators: Operators
+}
+
+impl<'ctx> Engine<'ctx> {
+  pub fn new(context: &'ctx Context) -> Result<Engine<'ctx>, Error> {
+    Engine::with_opt(context, OptConfig::default())
+  }
+
+  // An engine optimizing as `opt` says, rather than as `-O2` does.
+  pub fn with_opt(context: &'ctx Context, opt: OptConfig) -> Result<Engine<'ctx>, Error> {
+    let mut codegen = CodeGen::mk_compiler_with(context, "engine", opt).map_err(|err| Error::Jit(err.to_string()))?;
+
+    codegen.require_host_fns(true);
+    codegen.register_host_fn("putchard", (|x: f64| codegen::putchard(x)).into_host_fn());
+    codegen.register_host_fn("printd", (|x: f64| codegen::printd(x)).into_host_fn());
+
+    Ok(Engine { codegen, operators: Operators::default() })
+  }
+
+  // Makes `f`, a closure over up to 8 `f64`s, `i64`s, `bool`s or `String`s returning an `f64`, `i64`
+  // or `bool`, callable from source that declares it with `extern name(...)` at the matching types.
+  // Registering a name again only affects later declarations.
+  pub fn register_fn<Args, F: IntoHostFn<Args>>(&mut self, name: &str, f: F) {
+    self.codegen.register_host_fn(name, f.into_host_fn());
+  }
+
+  // Like `register_fn`, for functions taking their `arity` arguments as a slice.
+  pub fn register_fn_slice<F>(&mut self, name: &str, arity: usize, f: F)
+  where F: Fn(&[f64]) -> f64 + 'static {
+    self.codegen.register_host_fn(name, HostFn::new(arity, f));
+  }
+
+  // Chooses when `for` loops in source evaluated from now on check their end condition.
+  pub fn set_loop_check(&mut self, loop_check: LoopCheck) {
+    self.codegen.set_loop_check(loop_check);
+  }
+
+  // Lets functions and structs be defined again by source evaluated from now on.
+  pub fn allow_redefinition(&mut self, allow: bool) {
+    self.codegen.allow_redefinition(allow);
+  }
+
+  pub fn codegen(&self) -> &CodeGen<'ctx> {
+    &self.codegen
+  }
+
+  // Gets a handle for calling a function defined by earlier source, such as `Func2` or `FuncN`.
+  pub fn get_fn<F: JitFn<'ctx>>(&mut self, name: &str) -> Result<F, CallError> {
+    self.codegen.get_fn(name)
+  }
+
+  // Compiles `source` and evaluates its top-level expressions in order, returning the value of the
+  // last one, or 0 if there are none.
+  pub fn eval_source(&mut self, source: &str) -> Result<f64, Error> {
+    let program = parser::parse_program_continued(source, &mut self.operators)?;
+    let checked = self.codegen.check_program(&program).map_err(Error::Check)?;
+
+    self.codegen.start_module()?;
+    let anon_exprs = self.codegen.compile_program(checked)?;
+
+    let mut value = 0.0;
+    for anon_expr in anon_exprs {
+      value = self.codegen.jit_eval_anon_expr(&anon_expr).map_err(|err| match err {
+        CallError::Runtime(err) => Error::Runtime(err),
+        _ => Error::Jit(format!("unable to compile `{}`", anon_expr))
+      })?;
+    }
+
+    Ok(value)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn eval_source_test() {
+    let context = Context::create();
+    let mut engine = Engine::new(&context).unwrap();
+
+    assert_eq!(engine.eval_source("1 + 2 * 3").unwrap(), 7.0);
+
+    // Definitions and operators carry over between calls
+    assert_eq!(engine.eval_source("def binary@ 50 (x y) x - y; def double(x) x * 2").unwrap(), 0.0);
+    assert_eq!(engine.eval_source("double(4 @ 1); double(21)").unwrap(), 42.0);
+
+    match engine.eval_source("double(y) + double(1, 2)") {
+      Err(Error::Check(errors)) => assert_eq!(errors.len(), 2),
+      res => panic!("expected errors from checking, got {:?}", res)
+    }
+    assert!(matches!(engine.eval_source("double(1"), Err(Error::Parse(_))));
+
+    // Engines don't share operators, and creating one leaves the others' alone
+    let mut other = Engine::new(&context).unwrap();
+    assert!(matches!(other.eval_source("4 @ 1"), Err(Error::Parse(_))));
+    assert_eq!(engine.eval_source("4 @ 1").unwrap(), 3.0);
+  }
+
+  #[test]
+  fn comparison_ops_test() {
+    let context = Context::create();
+    let mut engine = Engine::new(&context).unwrap();
+
+    assert_eq!(engine.eval_source("(1 <= 1) + (2 >= 3) + (2 == 2) + (2 != 2)").unwrap(), 2.0);
+    assert_eq!(engine.eval_source("-7 % 3 + -(1 - 3)").unwrap(), 1.0);
+
+    // Every comparison with NaN is false, except `!=`
+    engine.eval_source("def nan() 0 / 0").unwrap();
+    assert_eq!(engine.eval_source("(nan() < 1) + (nan() > 1) + (nan() <= 1) + (nan() >= 1) + (nan() == nan())").unwrap(), 0.0);
+    assert_eq!(engine.eval_source("nan() != nan()").unwrap(), 1.0);
+  }
+
+  #[test]
+  fn logical_ops_test() {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    let context = Context::create();
+    let mut engine = Engine::new(&context).unwrap();
+    let calls = Rc::new(Cell::new(0));
+    let counter = calls.clone();
+
+    engine.register_fn("touch", move |x: f64| { counter.set(counter.get() + 1); x });
+
+    // The right-hand side is skipped when the left decides the result
+    assert_eq!(engine.eval_source("extern touch(x); (0 && touch(1)) + (2 || touch(1)) + !0 + !5").unwrap(), 2.0);
+    assert_eq!(calls.get(), 0);
+
+    assert_eq!(engine.eval_source("(3 && touch(2)) + (0 || touch(0))").unwrap(), 1.0);
+    assert_eq!(calls.get(), 2);
+
+    // A `def unary!` from the tutorial replaces the built-in `!` in later input
+    assert_eq!(engine.eval_source("def unary!(v) if v then 0 else 10; !0 + !5").unwrap(), 10.0);
+    assert_eq!(engine.eval_source("!0").unwrap(), 10.0);
+  }
+
+  #[test]
+  fn loops_test() {
+    let context = Context::create();
+    let mut engine = Engine::new(&context).unwrap();
+
+    // By default the condition is checked before each run of the body
+    engine.eval_source("def count(n) var c in (for i = 0, i < n in c = c + 1) + c").unwrap();
+    assert_eq!(engine.eval_source("count(3) * 10 + count(0)").unwrap(), 30.0);
+    assert_eq!(engine.eval_source("var c in (for i = 10, i > 0, -2.5 in c = c + i) + c").unwrap(), 25.0);
+
+    // As in the tutorial, the body runs before the condition is first checked, and once more at the end
+    engine.set_loop_check(LoopCheck::AfterBody);
+    engine.eval_source("def count_last(n) var c in (for i = 0, i < n in c = c + 1) + c").unwrap();
+    assert_eq!(engine.eval_source("count_last(3) * 10 + count_last(0)").unwrap(), 41.0);
+    engine.set_loop_check(LoopCheck::BeforeBody);
+
+    assert_eq!(engine.eval_source("var i = 0, c in (while i < 3 do c = c + (i = i + 1)) + c").unwrap(), 6.0);
+    assert_eq!(engine.eval_source("var c in (while 0 do c = 1) + c").unwrap(), 0.0);
+  }
+
+  #[test]
+  fn block_test() {
+    let context = Context::create();
+    let mut engine = Engine::new(&context).unwrap();
+
+    engine.eval_source("def sum_to(n) var total in { for i = 1, i < n in total = total + i; total }").unwrap();
+    assert_eq!(engine.eval_source("sum_to(4)").unwrap(), 10.0);
+    assert_eq!(engine.eval_source("var x in { x = 2; x = x * 3; } + 1").unwrap(), 7.0);
+  }
+
+  #[test]
+  fn redefinition_test() {
+    let context = Context::create();
+    let mut engine = Engine::new(&context).unwrap();
+
+    // An `extern` may be followed by a matching definition, even in the same program
+    assert_eq!(engine.eval_source("extern twice(x); def quad(x) twice(twice(x)); def twice(x) x * 2; quad(3)").unwrap(), 12.0);
+
+    match engine.eval_source("def twice(x) x") {
+      Err(Error::Compile(CompileError::Redefinition { name, .. })) => assert_eq!(name, "twice"),
+      res => panic!("expected a redefinition error, got {:?}", res)
+    }
+    match engine.eval_source("extern quad(x y)") {
+      Err(Error::Compile(CompileError::SignatureMismatch { declared: 1, found: 2, .. })) => {},
+      res => panic!("expected a signature mismatch, got {:?}", res)
+    }
+
+    // As in the REPL, a new body only affects code compiled afterwards
+    engine.allow_redefinition(true);
+    assert_eq!(engine.eval_source("def twice(x) x * 20; twice(1)").unwrap(), 20.0);
+    assert_eq!(engine.eval_source("quad(1)").unwrap(), 4.0);
+  }
+
+  #[test]
+  fn get_fn_test() {
+    use crate::jit::{Func1, Func2, FuncN};
+
+    let context = Context::create();
+    let mut engine = Engine::new(&context).unwrap();
+    engine.eval_source("def score(x y) x * 10 + y; def clamp(x lo hi) if x < lo then lo else if x > hi then hi else x").unwrap();
+
+    let score = engine.get_fn::<Func2>("score").unwrap();
+    assert_eq!(score.call(4.0, 2.0), Ok(42.0));
+
+    let clamp = engine.get_fn::<FuncN>("clamp").unwrap();
+    assert_eq!(clamp.arity(), 3);
+    assert_eq!(clamp.call(&[7.0, 0.0, 5.0]), Ok(5.0));
+    assert!(clamp.call(&[7.0]).is_err());
+
+    // Functions defined after the handles were made are found too
+    engine.eval_source("def half(x) x / 2").unwrap();
+    assert_eq!(engine.get_fn::<Func1>("half").unwrap().call(score.call(1.0, 0.0).unwrap()), Ok(5.0));
+
+    assert_eq!(engine.get_fn::<Func1>("score").err(),
+               Some(CallError::ArityMismatch { name: "score".to_string(), expected: 2, found: 1 }));
+    assert_eq!(engine.get_fn::<FuncN>("nope").err(), Some(CallError::UnknownFunction("nope".to_string())));
+
+    // A generic function is called at numbers, and one of strings not at all
+    engine.eval_source("def pick(c a b) if c then a else b; def greet(s: str) 0").unwrap();
+    assert_eq!(engine.get_fn::<FuncN>("pick").unwrap().call(&[0.0, 1.0, 2.0]), Ok(2.0));
+    assert_eq!(engine.get_fn::<Func2>("greet").err(),
+               Some(CallError::NotNumeric { name: "greet".to_string(), signature: "(str) -> number".to_string() }));
+  }
+
+  #[test]
+  fn register_fn_test() {
+    let context = Context::create();
+    let mut engine = Engine::new(&context).unwrap();
+    let scale = 3.0;
+
+    engine.register_fn("clamp", |x: f64, lo: f64, hi: f64| x.max(lo).min(hi));
+    engine.register_fn("scale", move |x: f64| x * scale);
+    engine.register_fn_slice("sum4", 4, |args: &[f64]| args.iter().sum());
+
+    assert_eq!(engine.eval_source("extern clamp(x lo hi); clamp(7, 0, 5)").unwrap(), 5.0);
+    assert_eq!(engine.eval_source("extern scale(x); extern sum4(a b c d); scale(sum4(1, 2, 3, 4))").unwrap(), 30.0);
+    assert_eq!(engine.eval_source("clamp(-1, 0, 5)").unwrap(), 0.0);
+
+    // Handles keep working once the engine that made them is gone, host functions included
+    engine.eval_source("def scaled_sum(a b c d) scale(sum4(a, b, c, d))").unwrap();
+    let scaled_sum = engine.get_fn::<crate::jit::Func4>("scaled_sum").unwrap();
+
+    match engine.eval_source("extern sin(x)") {
+      Err(Error::Compile(CompileError::UnresolvedExtern { name, .. })) => assert_eq!(name, "sin"),
+      res => panic!("expected an unresolved extern error, got {:?}", res)
+    }
+    // nor is it declared afterwards
+    assert!(matches!(engine.eval_source("sin(1)"), Err(Error::Check(_))));
+    match engine.eval_source("extern scale(x y)") {
+      Err(Error::Compile(CompileError::ArityMismatch { expected: 1, found: 2, .. })) => {},
+      res => panic!("expected an arity mismatch, got {:?}", res)
+    }
+
+    // Other types must be declared as the host function takes and returns them
+    engine.register_fn("repeat", |s: String, n: i64| s.repeat(n as usize).len() as i64);
+    engine.register_fn("even", |n: i64| n % 2 == 0);
+    assert_eq!(engine.eval_source("extern repeat(s: str, n: int) -> int; extern even(n: int) -> bool; if even(repeat(\"ab\", 3)) then 1 else 2").unwrap(), 1.0);
+    assert!(matches!(engine.eval_source("extern repeat(s, n: int) -> int"), Err(Error::Compile(CompileError::TypeMismatch { .. }))));
+
+    drop(engine);
+    assert_eq!(scaled_sum.call(1.0, 2.0, 3.0, 4.0), Ok(30.0));
+  }
+
+  #[test]
+  fn runtime_error_test() {
+    use crate::jit::Func1;
+
+    let context = Context::create();
+    let mut engine = Engine::new(&context).unwrap();
+    let out_of_bounds = |index: usize| RuntimeError { message: format!("index {} is out of bounds for an array of length 2", index) };
+
+    // The error stops the code at once, however deep in calls it happens
+    engine.eval_source("def at(i) [1, 2][i]; def twice(i) at(i) + at(i)").unwrap();
+    match engine.eval_source("twice(2)") {
+      Err(Error::Runtime(err)) => assert_eq!(err, out_of_bounds(2)),
+      res => panic!("expected a runtime error, got {:?}", res)
+    }
+
+    // and the engine carries on as before
+    assert_eq!(engine.eval_source("twice(1)").unwrap(), 4.0);
+
+    let at = engine.get_fn::<Func1>("at").unwrap();
+    assert_eq!(at.call(3.0), Err(CallError::Runtime(out_of_bounds(3))));
+    assert_eq!(at.call(0.0), Ok(1.0));
+  }
+
+  #[test]
+  fn nested_runtime_error_test() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let context = Context::create();
+    let mut engine = Engine::new(&context).unwrap();
+    let recorded = Rc::new(RefCell::new(Vec::new()));
+    let recorder = recorded.clone();
+
+    engine.register_fn("record", move |x: f64| { recorder.borrow_mut().push(x); x });
+
+    // Nothing after the trap runs, neither in the functions it returns through nor at the top level
+    let res = engine.eval_source("
+      extern record(x);
+      def inner(i) { record(1); [1, 2][i]; record(2) };
+      def middle(i) { inner(i); record(3) };
+      middle(5); record(4)");
+
+    match res {
+      Err(Error::Runtime(err)) =>
+        assert_eq!(err.message, "index 5 is out of bounds for an array of length 2"),
+      res => panic!("expected a runtime error, got {:?}", res)
+    }
+    assert_eq!(*recorded.borrow(), vec![1.0]);
+  }
+
+  #[test]
+  fn host_fn_panic_test() {
+    let context = Context::create();
+    let mut engine = Engine::new(&context).unwrap();
+
+    engine.register_fn("checked_sqrt", |x: f64| {
+      assert!(x >= 0.0, "square root of {}", x);
+      x.sqrt()
+    });
+
+    // A panic in a registered function stops the code like any runtime error
+    engine.eval_source("extern checked_sqrt(x); def root_sum(a b) checked_sqrt(a) + checked_sqrt(b)").unwrap();
+    match engine.eval_source("root_sum(4, -1)") {
+      Err(Error::Runtime(err)) => assert_eq!(err.message, "host function panicked: square root of -1"),
+      res => panic!("expected a runtime error, got {:?}", res)
+    }
+
+    assert_eq!(engine.eval_source("root_sum(4, 9)").unwrap(), 5.0);
+  }
+}
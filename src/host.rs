@@ -0,0 +1,275 @@
+use std::any::Any;
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
+
+use crate::codegen::Trap;
+
+/**
+ * Types of the values that can be passed to a host function, and, but for strings, returned by one.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HostType {
+  Number,
+  Int,
+  Bool,
+  Str
+}
+
+/**
+ * A value passed to or returned by a host function.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum HostValue {
+  Number(f64),
+  Int(i64),
+  Bool(bool),
+  // A copy of a string compiled code passed
+  Str(String)
+}
+
+impl HostValue {
+  // Reads an argument of type `ty` from the 64 bits compiled code stored it in: the bits of a
+  // number, an int, 1 or 0 for a bool, or the address of a string's NUL-terminated bytes.
+  //
+  // Unsafe, as a string's address must be that of a string compiled code made.
+  unsafe fn from_slot(ty: HostType, slot: u64) -> HostValue {
+    match ty {
+      HostType::Number => HostValue::Number(f64::from_bits(slot)),
+      HostType::Int => HostValue::Int(slot as i64),
+      HostType::Bool => HostValue::Bool(slot != 0),
+      HostType::Str => HostValue::Str(CStr::from_ptr(slot as usize as *const c_char).to_string_lossy().into_owned())
+    }
+  }
+
+  // The 64 bits compiled code reads a returned value from, as `from_slot` reads arguments.
+  fn into_slot(self) -> u64 {
+    match self {
+      HostValue::Number(value) => value.to_bits(),
+      HostValue::Int(value) => value as u64,
+      HostValue::Bool(value) => value as u64,
+      HostValue::Str(_) => unreachable!("host functions don't return strings")
+    }
+  }
+}
+
+/**
+ * Rust types that host functions can take arguments of.
+ */
+pub trait HostArg {
+  const TYPE: HostType;
+
+  fn from_host_value(value: &HostValue) -> Self;
+}
+
+/**
+ * Rust types that host functions can return. Strings aren't among them, as compiled code has no way
+ * to free them.
+ */
+pub trait HostRet {
+  const TYPE: HostType;
+
+  fn into_host_value(self) -> HostValue;
+}
+
+macro_rules! impl_host_value {
+  ($ty:ty, $host_type:ident) => {
+    impl HostArg for $ty {
+      const TYPE: HostType = HostType::$host_type;
+
+      fn from_host_value(value: &HostValue) -> $ty {
+        match value {
+          HostValue::$host_type(value) => value.clone(),
+          _ => unreachable!("host function called with an argument of the wrong type")
+        }
+      }
+    }
+  }
+}
+
+impl_host_value!(f64, Number);
+impl_host_value!(i64, Int);
+impl_host_value!(bool, Bool);
+impl_host_value!(String, Str);
+
+impl HostRet for f64 {
+  const TYPE: HostType = HostType::Number;
+
+  fn into_host_value(self) -> HostValue {
+    HostValue::Number(self)
+  }
+}
+
+impl HostRet for i64 {
+  const TYPE: HostType = HostType::Int;
+
+  fn into_host_value(self) -> HostValue {
+    HostValue::Int(self)
+  }
+}
+
+impl HostRet for bool {
+  const TYPE: HostType = HostType::Bool;
+
+  fn into_host_value(self) -> HostValue {
+    HostValue::Bool(self)
+  }
+}
+
+// Calls the Rust function, given arguments of the types of its parameters.
+type HostCall = dyn Fn(&[HostValue]) -> HostValue;
+
+/**
+ * A Rust function callable from Kaleidoscope through an `extern` declaration of the same name, which
+ * must give it the same types.
+ */
+pub struct HostFn {
+  params: Vec<HostType>,
+  ret: HostType,
+  call: Box<HostCall>
+}
+
+impl HostFn {
+  // A host function taking its `arity` arguments, all numbers, as a slice.
+  pub fn new<F>(arity: usize, call: F) -> HostFn
+  where F: Fn(&[f64]) -> f64 + 'static {
+    HostFn {
+      params: vec![HostType::Number; arity],
+      ret: HostType::Number,
+      call: Box::new(move |args: &[HostValue]| {
+        let args: Vec<f64> = args.iter().map(f64::from_host_value).collect();
+        HostValue::Number(call(&args))
+      })
+    }
+  }
+
+  pub fn arity(&self) -> usize {
+    self.params.len()
+  }
+
+  // The types of the parameters, in order.
+  pub fn params(&self) -> &[HostType] {
+    &self.params
+  }
+
+  pub fn ret(&self) -> HostType {
+    self.ret
+  }
+}
+
+/**
+ * Closures that can become a `HostFn`: those taking up to 8 arguments of `HostArg` types and
+ * returning a `HostRet` type. `Args` is only there to tell the signatures apart, as
+ * `fn(f64, String) -> i64`.
+ */
+pub trait IntoHostFn<Args> {
+  fn into_host_fn(self) -> HostFn;
+}
+
+macro_rules! impl_into_host_fn {
+  ($($ty:ident $arg:ident),*) => {
+    impl<F, R, $($ty),*> IntoHostFn<fn($($ty),*) -> R> for F
+    where F: Fn($($ty),*) -> R + 'static, R: HostRet, $($ty: HostArg),* {
+      fn into_host_fn(self) -> HostFn {
+        HostFn {
+          params: vec![$($ty::TYPE),*],
+          ret: R::TYPE,
+          call: Box::new(move |args: &[HostValue]| match args {
+            [$($arg),*] => self($($ty::from_host_value($arg)),*).into_host_value(),
+            _ => unreachable!("host function called with the wrong number of arguments")
+          })
+        }
+      }
+    }
+  }
+}
+
+impl_into_host_fn!();
+impl_into_host_fn!(A a);
+impl_into_host_fn!(A a, B b);
+impl_into_host_fn!(A a, B b, C c);
+impl_into_host_fn!(A a, B b, C c, D d);
+impl_into_host_fn!(A a, B b, C c, D d, E e);
+impl_into_host_fn!(A a, B b, C c, D d, E e, G g);
+impl_into_host_fn!(A a, B b, C c, D d, E e, G g, H h);
+impl_into_host_fn!(A a, B b, C c, D d, E e, G g, H h, I i);
+
+/**
+ * Entry point from JIT-compiled code into a host function. Every `extern` with a registered
+ * implementation is compiled to a thunk that stores its arguments in an array of 64-bit slots and
+ * calls this, with the addresses of the trap and the `HostFn`. The value returned comes back in a
+ * slot too. See `HostValue::from_slot`.
+ *
+ * A panic in the host function can't unwind through compiled code, so it is caught here and raised
+ * as a runtime error instead, and 0 is returned.
+ *
+ * # Safety
+ *
+ * `trap` must point to a live `Trap`, `host_fn` to a live `HostFn`, and `args` to as many slots as
+ * it takes, each holding a value of the type of the matching parameter. A string's slot must hold
+ * the address of NUL-terminated bytes.
+ */
+pub(crate) unsafe extern "C" fn call_host_fn(trap: *const Trap, host_fn: *const HostFn, args: *const u64) -> u64 {
+  let host_fn = &*host_fn;
+  let slots = std::slice::from_raw_parts(args, host_fn.arity());
+
+  let res = panic::catch_unwind(AssertUnwindSafe(|| {
+    let args: Vec<HostValue> = host_fn.params.iter().zip(slots)
+      .map(|(ty, slot)| HostValue::from_slot(*ty, *slot))
+      .collect();
+
+    (host_fn.call)(&args).into_slot()
+  }));
+
+  res.unwrap_or_else(|payload| {
+    (*trap).raise(format!("host function panicked: {}", panic_message(&*payload)));
+    0
+  })
+}
+
+// The message a panic was started with, if it was given one.
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+  if let Some(message) = payload.downcast_ref::<&str>() {
+    message
+  } else if let Some(message) = payload.downcast_ref::<String>() {
+    message
+  } else {
+    "no message"
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn into_host_fn_test() {
+    let offset = 10.0;
+    let clamp = (|x: f64, lo: f64, hi: f64| x.max(lo).min(hi)).into_host_fn();
+    let shift = (move |x: f64| x + offset).into_host_fn();
+    let answer = (|| 42.0).into_host_fn();
+    let trap = Trap::new();
+    // the slots given always match the parameters
+    let call = |host_fn: &HostFn, slots: &[u64]| unsafe { call_host_fn(&trap, host_fn, slots.as_ptr()) };
+
+    assert_eq!((clamp.arity(), shift.arity(), answer.arity()), (3, 1, 0));
+    assert_eq!(call(&clamp, &[7.0f64.to_bits(), 0.0f64.to_bits(), 5.0f64.to_bits()]), 5.0f64.to_bits());
+    assert_eq!(call(&shift, &[1.5f64.to_bits()]), 11.5f64.to_bits());
+    assert_eq!(call(&answer, &[]), 42.0f64.to_bits());
+
+    // Strings arrive as copies, and ints and bools as themselves
+    let count = (|s: String, extra: i64| s.chars().count() as i64 + extra).into_host_fn();
+    let negate = (|b: bool| !b).into_host_fn();
+    let text = b"hi\0";
+
+    assert_eq!((count.params(), count.ret()), (&[HostType::Str, HostType::Int][..], HostType::Int));
+    assert_eq!(call(&count, &[text.as_ptr() as u64, -5i64 as u64]), -3i64 as u64);
+    assert_eq!(call(&negate, &[1]), 0);
+
+    // A panic is raised as a runtime error
+    let fail = (|x: f64| -> f64 { panic!("bad input {}", x) }).into_host_fn();
+
+    assert_eq!(call(&fail, &[2.0f64.to_bits()]), 0);
+    assert_eq!(trap.finish(0.0).map_err(|err| err.message), Err("host function panicked: bad input 2".to_string()));
+  }
+}
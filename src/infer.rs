@@ -0,0 +1,1101 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::ast::{Annotations, Expr, ExprKind, Name, Op, Program, Span, TypeExpr, UnaryOp};
+use crate::codegen::CompileError;
+use crate::sema::Structs;
+
+/**
+ * Types of values, as inferred from how they are used. Each is compiled to an LLVM type of its own: a
+ * number is a `double`, an int an `i64` and a bool an `i1`, and a string, an array, a struct or a
+ * function is a pointer to its data or closure record.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum Type {
+  Number,
+  Int,
+  Bool,
+  Str,
+  // An array of elements of the given type
+  Array(Box<Type>),
+  // A struct, generic over the types of its fields: those given are in the order they were
+  // declared in
+  Struct(Name, Vec<Type>),
+  // A function taking parameters of the given types, and returning the last
+  Fn(Vec<Type>, Box<Type>),
+  // A type not known yet or, in a generic function's signature, one of its type parameters
+  Var(u32)
+}
+
+impl Type {
+  // This type, with each type variable `Var(i)` in it replaced by `args[i]`.
+  pub fn substitute(&self, args: &[Type]) -> Type {
+    map_vars(self, &mut |var| args.get(var as usize).cloned().unwrap_or(Type::Var(var)))
+  }
+}
+
+impl fmt::Display for Type {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Type::Number => write!(f, "number"),
+      Type::Int => write!(f, "int"),
+      Type::Bool => write!(f, "bool"),
+      Type::Str => write!(f, "str"),
+      Type::Array(element) => write!(f, "[{}]", element),
+      Type::Struct(name, fields) if fields.is_empty() => write!(f, "{}", name),
+      Type::Struct(name, fields) => {
+        let fields: Vec<String> = fields.iter().map(Type::to_string).collect();
+        write!(f, "{}<{}>", name, fields.join(", "))
+      },
+      Type::Fn(params, ret) => {
+        let params: Vec<String> = params.iter().map(Type::to_string).collect();
+        write!(f, "({}) -> {}", params.join(", "), ret)
+      },
+      Type::Var(var) if *var < 26 => write!(f, "'{}", (b'a' + *var as u8) as char),
+      Type::Var(var) => write!(f, "'t{}", var)
+    }
+  }
+}
+
+/**
+ * The type of a function. It is generic over the type variables in `vars`, which its parameter and
+ * return types may use: each call can pick different types for them.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct Signature {
+  pub vars: Vec<u32>,
+  pub params: Vec<Type>,
+  pub ret: Type
+}
+
+impl Signature {
+  // The parameter and return types of the instance of this signature whose type variables take the
+  // types `args`.
+  pub fn instantiate(&self, args: &[Type]) -> (Vec<Type>, Type) {
+    (self.params.iter().map(|param| param.substitute(args)).collect(), self.ret.substitute(args))
+  }
+
+  // The types the type variables take in a call passing arguments of types `params` and returning
+  // `ret`, if the signature fits that call.
+  pub fn instance(&self, params: &[Type], ret: &Type) -> Option<Vec<Type>> {
+    let mut args = vec![None; self.vars.len()];
+    let fits = self.params.len() == params.len()
+      && self.params.iter().zip(params).all(|(pattern, ty)| match_type(pattern, ty, &mut args))
+      && match_type(&self.ret, ret, &mut args);
+
+    if fits {
+      args.into_iter().collect()
+    } else {
+      None
+    }
+  }
+
+  // The signature of the runtime function `name`, if it takes something other than numbers, as
+  // `printstr` only takes strings. An `extern` declaring it with `arity` parameters and no
+  // annotations gets it instead.
+  pub fn runtime(name: &str, arity: usize) -> Option<Signature> {
+    match (name, arity) {
+      ("printstr", 1) => Some(Signature { vars: Vec::new(), params: vec![Type::Str], ret: Type::Number }),
+      _ => None
+    }
+  }
+}
+
+impl fmt::Display for Signature {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let params: Vec<String> = self.params.iter().map(Type::to_string).collect();
+    write!(f, "({}) -> {}", params.join(", "), self.ret)
+  }
+}
+
+/**
+ * The type named by `name` in an annotation, if it is a built-in one: `number` (or `f64`), `int`,
+ * `bool` or `str`.
+ */
+pub fn builtin_type(name: &str) -> Option<Type> {
+  match name {
+    "number" | "f64" => Some(Type::Number),
+    "int" => Some(Type::Int),
+    "bool" => Some(Type::Bool),
+    "str" => Some(Type::Str),
+    _ => None
+  }
+}
+
+/**
+ * The types inferred for a program, one entry per item of it.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProgramTypes {
+  // Signature of each definition and `extern` declaration, in source order
+  pub signatures: Vec<Signature>,
+  // Type of every expression of each item, in the order `Expr::walk` visits them: those of a
+  // definition's body, in terms of its signature's type parameters, or those of a top-level
+  // expression. Declarations have none.
+  pub exprs: Vec<Vec<Type>>
+}
+
+/**
+ * Infers the signature of every function defined or declared by a program, and checks that every
+ * value is used at a type it has. Returns the types of the program's definitions, `extern`
+ * declarations and expressions, or every type error found.
+ *
+ * `declared` holds the signatures of functions declared by earlier programs, and `defined` those
+ * of them given a body. A definition of a function so far only declared by an `extern` must agree
+ * with that declaration; one replacing an earlier definition may change its type.
+ *
+ * Every function is generic over the types its uses leave open, so `def id(x) x` can be called
+ * with a number and with a string. So is every struct, over the types of its fields, which each
+ * construction picks. Programs that only use numbers infer as numbers throughout. Names, arities
+ * and fields should have been checked by `sema::check_program` first, with the same `structs`.
+ */
+pub fn infer_program(program: &Program, declared: &HashMap<Name, Signature>, defined: &HashSet<Name>, structs: &Structs) -> Result<ProgramTypes, Vec<CompileError>> {
+  let mut inferer = Inferer {
+    functions: declared.clone(),
+    defined: defined.clone(),
+    structs: structs.clone(),
+    bindings: Vec::new(),
+    classes: Vec::new(),
+    locals: Vec::new(),
+    uninitialized: Vec::new(),
+    fields: Vec::new(),
+    expr_types: HashMap::new(),
+    errors: Vec::new()
+  };
+  let mut signatures = Vec::new();
+  let mut exprs = Vec::new();
+
+  for item in program {
+    inferer.expr_types.clear();
+
+    match &item.kind {
+      ExprKind::Function(name, params, annotations, body) => {
+        let (signature, vars) = inferer.infer_fn(name, params, annotations, body, item.span);
+        signatures.push(signature);
+        exprs.push(inferer.expr_types(body, &vars));
+      },
+      ExprKind::Extern(name, params, annotations) => {
+        let signature = inferer.extern_signature(name, params.len(), annotations);
+
+        // a definition seen before takes precedence, as it does in codegen
+        if !inferer.defined.contains(name) {
+          inferer.functions.insert(name.to_owned(), signature.clone());
+        }
+        signatures.push(signature);
+        exprs.push(Vec::new());
+      },
+      ExprKind::Struct(name, fields) => {
+        inferer.structs.declare(name, fields);
+        exprs.push(Vec::new());
+      },
+      _ => {
+        inferer.locals.clear();
+        inferer.infer_expr(item);
+        inferer.resolve_fields();
+        inferer.check_uninitialized();
+        exprs.push(inferer.expr_types(item, &[]));
+      }
+    }
+  }
+
+  if inferer.errors.is_empty() {
+    Ok(ProgramTypes { signatures, exprs })
+  } else {
+    Err(inferer.errors)
+  }
+}
+
+// Rebuilds `ty` with each type variable in it replaced by `f` of it.
+fn map_vars(ty: &Type, f: &mut dyn FnMut(u32) -> Type) -> Type {
+  match ty {
+    Type::Var(var) => f(*var),
+    Type::Array(element) => Type::Array(Box::new(map_vars(element, f))),
+    Type::Struct(name, fields) => Type::Struct(name.to_owned(), fields.iter().map(|field| map_vars(field, f)).collect()),
+    Type::Fn(params, ret) => Type::Fn(params.iter().map(|param| map_vars(param, f)).collect(), Box::new(map_vars(ret, f))),
+    Type::Number | Type::Int | Type::Bool | Type::Str => ty.clone()
+  }
+}
+
+// Whether `ty` is `pattern` with each type variable `Var(i)` in it replaced by the same type, which
+// is recorded in `args[i]`.
+fn match_type(pattern: &Type, ty: &Type, args: &mut [Option<Type>]) -> bool {
+  match (pattern, ty) {
+    (Type::Var(var), _) => match &args[*var as usize] {
+      Some(arg) => arg == ty,
+      None => {
+        args[*var as usize] = Some(ty.clone());
+        true
+      }
+    },
+    (Type::Array(pattern), Type::Array(ty)) => match_type(pattern, ty, args),
+    (Type::Struct(pattern_name, patterns), Type::Struct(name, types)) =>
+      pattern_name == name && patterns.len() == types.len() && patterns.iter().zip(types).all(|(pattern, ty)| match_type(pattern, ty, args)),
+    (Type::Fn(patterns, pattern_ret), Type::Fn(types, ret)) =>
+      patterns.len() == types.len() && patterns.iter().zip(types).all(|(pattern, ty)| match_type(pattern, ty, args)) && match_type(pattern_ret, ret, args),
+    _ => pattern == ty
+  }
+}
+
+// Renumbers the type variables in `types` from 0, in order of appearance. Also returns the variables
+// renumbered, in that order.
+fn renumber(types: &[Type]) -> (Vec<Type>, Vec<u32>) {
+  let mut vars = Vec::new();
+  let renumbered = types.iter()
+    .map(|ty| map_vars(ty, &mut |var| {
+      let index = vars.iter().position(|v| *v == var).unwrap_or_else(|| {
+        vars.push(var);
+        vars.len() - 1
+      });
+      Type::Var(index as u32)
+    }))
+    .collect();
+
+  (renumbered, vars)
+}
+
+// Whether the type variable `var` appears in `ty`.
+fn occurs(var: u32, ty: &Type) -> bool {
+  match ty {
+    Type::Var(other) => *other == var,
+    Type::Array(element) => occurs(var, element),
+    Type::Struct(_, fields) => fields.iter().any(|field| occurs(var, field)),
+    Type::Fn(params, ret) => params.iter().any(|param| occurs(var, param)) || occurs(var, ret),
+    Type::Number | Type::Int | Type::Bool | Type::Str => false
+  }
+}
+
+/**
+ * The types a type variable may still become. Literals and operators only know their operands are
+ * some kind of number, or something that can be tested, until they are used. Those left open once a
+ * function is inferred are numbers.
+ */
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Class {
+  // `number` or `int`, as an arithmetic operand
+  Numeric,
+  // `number`, `int` or `bool`, as a condition
+  Truthy,
+  Any
+}
+
+impl Class {
+  fn admits(self, ty: &Type) -> bool {
+    matches!((self, ty), (Class::Any, _) | (_, Type::Number) | (_, Type::Int) | (Class::Truthy, Type::Bool))
+  }
+}
+
+// `object.field`, whose type is `ty`, and the type of `object`, at `object_span`.
+struct FieldAccess {
+  object: Type,
+  object_span: Span,
+  field: Name,
+  ty: Type,
+  span: Span
+}
+
+struct Inferer {
+  // Signature of every function declared so far. While a function's body is inferred, its own
+  // signature has no `vars`, so recursive calls share its types rather than pick new ones.
+  functions: HashMap<Name, Signature>,
+  // Functions given a body so far
+  defined: HashSet<Name>,
+  structs: Structs,
+  // The type each type variable has been unified with, if any, and the types it may become
+  bindings: Vec<Option<Type>>,
+  classes: Vec<Class>,
+  // Variables in scope with their types, innermost binding last
+  locals: Vec<(Name, Type)>,
+  // Variables bound by `var` without an initial value, and the element types of arrays made by
+  // `alloc` (with no name), whose zeros suit only numbers
+  uninitialized: Vec<(Option<Name>, Type, Span)>,
+  // Field accesses on objects not yet known to be of a struct type, since the last call to
+  // `resolve_fields`
+  fields: Vec<FieldAccess>,
+  // Type of each expression of the item being inferred, by address
+  expr_types: HashMap<*const Expr, Type>,
+  errors: Vec<CompileError>
+}
+
+impl Inferer {
+  fn fresh(&mut self) -> Type {
+    self.fresh_in(Class::Any)
+  }
+
+  // A new type variable, which may only become a type of `class`.
+  fn fresh_in(&mut self, class: Class) -> Type {
+    self.bindings.push(None);
+    self.classes.push(class);
+    Type::Var(self.bindings.len() as u32 - 1)
+  }
+
+  // Replaces every bound type variable in `ty` by the type it is bound to.
+  fn resolve(&self, ty: &Type) -> Type {
+    map_vars(ty, &mut |var| match &self.bindings[var as usize] {
+      Some(bound) => self.resolve(bound),
+      None => Type::Var(var)
+    })
+  }
+
+  // Makes `found`, the type of the expression at `span`, the same as `expected`, or reports that it
+  // can't be.
+  fn unify(&mut self, expected: &Type, found: &Type, span: Span) {
+    if !self.unify_types(expected, found) {
+      // type variables are numbered afresh, so messages don't depend on how many came before
+      let (mut types, _) = renumber(&[self.defaulted(expected), self.defaulted(found)]);
+      let found = types.pop().unwrap();
+      let expected = types.pop().unwrap();
+
+      self.errors.push(CompileError::TypeMismatch { expected, found, span });
+    }
+  }
+
+  fn unify_types(&mut self, a: &Type, b: &Type) -> bool {
+    match (self.resolve(a), self.resolve(b)) {
+      (Type::Var(a), Type::Var(b)) if a == b => true,
+      // the variable left keeps the narrower class of the two
+      (Type::Var(a), Type::Var(b)) => {
+        let class = self.classes[a as usize].min(self.classes[b as usize]);
+
+        self.bindings[a as usize] = Some(Type::Var(b));
+        self.classes[b as usize] = class;
+        true
+      },
+      // a type can't contain itself, as in `a[0] = a`
+      (Type::Var(var), ty) | (ty, Type::Var(var)) if !occurs(var, &ty) && self.classes[var as usize].admits(&ty) => {
+        self.bindings[var as usize] = Some(ty);
+        true
+      },
+      (Type::Array(a), Type::Array(b)) => self.unify_types(&a, &b),
+      (Type::Struct(a, a_fields), Type::Struct(b, b_fields)) if a == b && a_fields.len() == b_fields.len() =>
+        a_fields.iter().zip(&b_fields).all(|(a, b)| self.unify_types(a, b)),
+      (Type::Fn(a_params, a_ret), Type::Fn(b_params, b_ret)) if a_params.len() == b_params.len() =>
+        a_params.iter().zip(&b_params).all(|(a, b)| self.unify_types(a, b)) && self.unify_types(&a_ret, &b_ret),
+      (a, b) => a == b && !matches!(a, Type::Var(_))
+    }
+  }
+
+  // A copy of `signature` with its type parameters replaced by fresh type variables.
+  fn instantiate(&mut self, signature: &Signature) -> (Vec<Type>, Type) {
+    let fresh: HashMap<u32, Type> = signature.vars.iter().map(|var| (*var, self.fresh())).collect();
+    let mut substitute = |ty: &Type| map_vars(ty, &mut |var| fresh.get(&var).cloned().unwrap_or(Type::Var(var)));
+
+    (signature.params.iter().map(&mut substitute).collect(), substitute(&signature.ret))
+  }
+
+  // `ty` resolved, with the type variables only numbers or bools could take as numbers.
+  fn defaulted(&self, ty: &Type) -> Type {
+    map_vars(&self.resolve(ty), &mut |var| match self.classes[var as usize] {
+      Class::Any => Type::Var(var),
+      _ => Type::Number
+    })
+  }
+
+  // Makes a signature generic over every type its parameters and return type leave open, numbering
+  // those type parameters from 0. Also returns the type variables they stand for, in that order.
+  // Those only numbers or bools could take are numbers instead.
+  fn generalize(&mut self, params: &[Type], ret: &Type) -> (Signature, Vec<u32>) {
+    for ty in params.iter().chain(std::iter::once(ret)) {
+      let defaulted = self.defaulted(ty);
+      self.unify_types(ty, &defaulted);
+    }
+
+    let resolved: Vec<Type> = params.iter().chain(std::iter::once(ret)).map(|ty| self.resolve(ty)).collect();
+    let (mut types, vars) = renumber(&resolved);
+    let ret = types.pop().unwrap();
+
+    (Signature { vars: (0..vars.len() as u32).collect(), params: types, ret }, vars)
+  }
+
+  // The types of `root` and its subexpressions, in the order `Expr::walk` visits them, with the type
+  // variables in `vars` numbered by their position there. Types left open by everything else are
+  // numbers: nothing tells them apart.
+  fn expr_types(&self, root: &Expr, vars: &[u32]) -> Vec<Type> {
+    let mut types = Vec::new();
+
+    root.walk(&mut |expr| {
+      let ty = self.expr_types.get(&(expr as *const Expr)).map_or(Type::Number, |ty| self.resolve(ty));
+
+      types.push(map_vars(&ty, &mut |var| match vars.iter().position(|v| *v == var) {
+        Some(index) => Type::Var(index as u32),
+        None => Type::Number
+      }));
+    });
+
+    types
+  }
+
+  // The type named by the annotation `ty`. A struct not given the types of its fields is generic
+  // over them.
+  fn annotated(&mut self, ty: &TypeExpr) -> Type {
+    match ty {
+      TypeExpr::Named(name, args) => match builtin_type(name) {
+        Some(ty) => ty,
+        None if args.is_empty() => {
+          let arity = self.structs.fields(name).map_or(0, Vec::len);
+          Type::Struct(name.to_owned(), (0..arity).map(|_| self.fresh()).collect())
+        },
+        None => Type::Struct(name.to_owned(), args.iter().map(|arg| self.annotated(arg)).collect())
+      },
+      TypeExpr::Array(element) => Type::Array(Box::new(self.annotated(element))),
+      TypeExpr::Fn(params, ret) => Type::Fn(params.iter().map(|param| self.annotated(param)).collect(), Box::new(self.annotated(ret)))
+    }
+  }
+
+  // The type the parameter or return value annotated with `ty`, if any, has: a new type variable if
+  // there is no annotation.
+  fn annotated_or_fresh(&mut self, ty: Option<&TypeExpr>) -> Type {
+    match ty {
+      Some(ty) => self.annotated(ty),
+      None => self.fresh()
+    }
+  }
+
+  // The signature of an `extern` declaring a function `name` with `arity` parameters. Host functions
+  // can't be generic, so parameters and return values not annotated are numbers.
+  fn extern_signature(&mut self, name: &str, arity: usize, annotations: &Annotations) -> Signature {
+    if *annotations == Annotations::default() {
+      if let Some(signature) = Signature::runtime(name, arity) {
+        return signature;
+      }
+    }
+
+    let params: Vec<Type> = (0..arity).map(|index| annotations.param(index).map_or(Type::Number, |ty| self.annotated(ty))).collect();
+    let ret = annotations.ret.as_ref().map_or(Type::Number, |ty| self.annotated(ty));
+
+    self.generalize(&params, &ret).0
+  }
+
+  fn infer_fn(&mut self, name: &Name, params: &[Name], annotations: &Annotations, body: &Expr, span: Span) -> (Signature, Vec<u32>) {
+    let param_types: Vec<Type> = (0..params.len()).map(|index| self.annotated_or_fresh(annotations.param(index))).collect();
+    let ret = self.annotated_or_fresh(annotations.ret.as_ref());
+
+    // a definition must agree with an `extern` that declared it, which calls may already rely on
+    if let Some(declared) = self.functions.get(name).cloned() {
+      if !self.defined.contains(name) && declared.params.len() == params.len() {
+        let (declared_params, declared_ret) = self.instantiate(&declared);
+
+        for (declared, param) in declared_params.iter().zip(&param_types) {
+          self.unify(declared, param, span);
+        }
+        self.unify(&declared_ret, &ret, span);
+      }
+    }
+
+    self.functions.insert(name.to_owned(), Signature { vars: Vec::new(), params: param_types.clone(), ret: ret.clone() });
+    self.locals = params.iter().cloned().zip(param_types.iter().cloned()).collect();
+
+    let body_type = self.infer_expr(body);
+    self.unify(&ret, &body_type, body.span);
+    self.resolve_fields();
+    self.check_uninitialized();
+
+    let (signature, vars) = self.generalize(&param_types, &ret);
+    self.functions.insert(name.to_owned(), signature.clone());
+    self.defined.insert(name.to_owned());
+
+    (signature, vars)
+  }
+
+  // Resolves the field accesses whose objects' types weren't known when they were inferred, now
+  // that the rest of the function has been. Resolving one may tell the type of another's object.
+  fn resolve_fields(&mut self) {
+    loop {
+      let pending = std::mem::take(&mut self.fields);
+      let count = pending.len();
+      let unresolved: Vec<FieldAccess> = pending.into_iter().filter(|access| !self.resolve_field(access, false)).collect();
+
+      if unresolved.len() == count {
+        for access in &unresolved {
+          self.resolve_field(access, true);
+        }
+        return;
+      }
+      self.fields = unresolved;
+    }
+  }
+
+  // Gives `access` the type of its field, from the type of its object. If that isn't known, and
+  // the field belongs to several structs, returns false, unless `last`, in which case the access is
+  // reported as ambiguous. A field no struct has was reported by `sema::check_program`.
+  fn resolve_field(&mut self, access: &FieldAccess, last: bool) -> bool {
+    match self.resolve(&access.object) {
+      Type::Struct(name, field_types) => {
+        match self.structs.fields(&name).and_then(|fields| fields.iter().position(|f| *f == access.field)) {
+          Some(i) => self.unify(&field_types[i], &access.ty, access.span),
+          None => self.errors.push(CompileError::UnknownField { struct_name: Some(name), field: access.field.to_owned(), span: access.span })
+        }
+        true
+      },
+      // a type variable only numbers or bools could take isn't a struct either
+      Type::Var(var) if self.classes[var as usize] == Class::Any => {
+        let structs: Vec<Name> = self.structs.declaring(&access.field).into_iter().cloned().collect();
+
+        match structs.as_slice() {
+          [] => true,
+          // the only struct it can be
+          [name] => {
+            let field_count = self.structs.fields(name).map_or(0, Vec::len);
+            let field_types: Vec<Type> = (0..field_count).map(|_| self.fresh()).collect();
+
+            self.unify(&Type::Struct(name.to_owned(), field_types), &access.object, access.object_span);
+            self.resolve_field(access, last)
+          },
+          _ if last => {
+            self.errors.push(CompileError::AmbiguousField { field: access.field.to_owned(), structs, span: access.span });
+            true
+          },
+          _ => false
+        }
+      },
+      ty => {
+        let (mut types, _) = renumber(&[self.defaulted(&ty)]);
+
+        self.errors.push(CompileError::FieldOfNonStruct { ty: types.remove(0), field: access.field.to_owned(), span: access.object_span });
+        true
+      }
+    }
+  }
+
+  // Checks the variables declared without an initial value, and the arrays allocated, since the
+  // last call. Those whose type is still open are numbers, since they hold 0.
+  fn check_uninitialized(&mut self) {
+    for (name, ty, span) in std::mem::take(&mut self.uninitialized) {
+      match (renumber(&[self.defaulted(&ty)]).0.remove(0), name) {
+        (Type::Number, _) => {},
+        (Type::Var(_), _) => self.unify(&Type::Number, &ty, span),
+        (ty, Some(name)) => self.errors.push(CompileError::UninitializedVariable { name, ty, span }),
+        (ty, None) => self.errors.push(CompileError::UninitializedElements { ty, span })
+      }
+    }
+  }
+
+  // Infers an expression used as a number or an int, returning its type, or a type that is one of
+  // those if it isn't.
+  fn infer_numeric(&mut self, expr: &Expr) -> Type {
+    let ty = self.infer_expr(expr);
+    let numeric = self.fresh_in(Class::Numeric);
+
+    self.unify(&numeric, &ty, expr.span);
+    numeric
+  }
+
+  // Infers an expression used as a condition, which may be a number, an int or a bool.
+  fn infer_truthy(&mut self, expr: &Expr) {
+    let ty = self.infer_expr(expr);
+    let truthy = self.fresh_in(Class::Truthy);
+
+    self.unify(&truthy, &ty, expr.span);
+  }
+
+  fn infer_expr(&mut self, expr: &Expr) -> Type {
+    let ty = self.infer_kind(expr);
+
+    self.expr_types.insert(expr as *const Expr, ty.clone());
+    ty
+  }
+
+  fn infer_kind(&mut self, expr: &Expr) -> Type {
+    match &expr.kind {
+      // a whole number may also be an int
+      ExprKind::Float(nb) if nb.fract() == 0.0 && nb.abs() <= 9007199254740992.0 => self.fresh_in(Class::Numeric),
+      ExprKind::Float(_) => Type::Number,
+
+      ExprKind::Str(_) => Type::Str,
+
+      // a name not bound to a variable stands for a function, as a value
+      ExprKind::Var(name) => match self.local(name) {
+        Some(ty) => ty,
+        None => match self.functions.get(name).cloned() {
+          Some(signature) => {
+            let (params, ret) = self.instantiate(&signature);
+            Type::Fn(params, Box::new(ret))
+          },
+          None => self.fresh()
+        }
+      },
+
+      ExprKind::BinOp(Op::Assign, target, value) => {
+        let target_type = match &target.kind {
+          ExprKind::Var(name) => self.lookup(name),
+          ExprKind::Index(..) | ExprKind::Field(..) => self.infer_expr(target),
+          _ => self.fresh()
+        };
+        let value_type = self.infer_expr(value);
+
+        self.unify(&target_type, &value_type, value.span);
+        value_type
+      },
+
+      // logic takes anything that can be a condition, and evaluates to 1 or 0 of any such type
+      ExprKind::BinOp(Op::And, left, right) | ExprKind::BinOp(Op::Or, left, right) => {
+        self.infer_truthy(left);
+        self.infer_truthy(right);
+        self.fresh_in(Class::Truthy)
+      },
+
+      // comparisons take two operands of the same type, numbers or ints, or bools for `==` and
+      // `!=`; strings aren't compared by value
+      ExprKind::BinOp(op, left, right) if op.is_comparison() => {
+        let class = if *op == Op::Equal || *op == Op::NotEqual { Class::Truthy } else { Class::Numeric };
+        let operand_type = self.fresh_in(class);
+
+        let left_type = self.infer_expr(left);
+        self.unify(&operand_type, &left_type, left.span);
+        let right_type = self.infer_expr(right);
+        self.unify(&operand_type, &right_type, right.span);
+
+        self.fresh_in(Class::Truthy)
+      },
+
+      // arithmetic takes two numbers or two ints, and evaluates to the same
+      ExprKind::BinOp(_, left, right) => {
+        let ty = self.infer_numeric(left);
+        let right_type = self.infer_expr(right);
+
+        self.unify(&ty, &right_type, right.span);
+        ty
+      },
+
+      ExprKind::UnaryOp(UnaryOp::Not, operand) => {
+        self.infer_truthy(operand);
+        self.fresh_in(Class::Truthy)
+      },
+
+      ExprKind::UnaryOp(UnaryOp::Negate, operand) => self.infer_numeric(operand),
+
+      ExprKind::Call(name, args) if self.local(name).is_some() => {
+        let callee = self.local(name).unwrap();
+        self.infer_apply(&callee, args, expr.span)
+      },
+
+      ExprKind::Apply(callee, args) => {
+        let callee_type = self.infer_expr(callee);
+        self.infer_apply(&callee_type, args, callee.span)
+      },
+
+      ExprKind::Call(name, args) => {
+        let (params, ret) = match self.functions.get(name).cloned() {
+          Some(signature) => self.instantiate(&signature),
+          None => (Vec::new(), self.fresh())
+        };
+
+        for (i, arg) in args.iter().enumerate() {
+          let arg_type = self.infer_expr(arg);
+          if let Some(param) = params.get(i) {
+            self.unify(param, &arg_type, arg.span);
+          }
+        }
+
+        ret
+      },
+
+      // unlike a definition's, a lambda's type isn't generic: it is fixed by how it's used
+      ExprKind::Lambda(params, body) => {
+        let param_types: Vec<Type> = params.iter().map(|_| self.fresh()).collect();
+        let outer_len = self.locals.len();
+
+        self.locals.extend(params.iter().cloned().zip(param_types.iter().cloned()));
+        let ret = self.infer_expr(body);
+        self.locals.truncate(outer_len);
+
+        Type::Fn(param_types, Box::new(ret))
+      },
+
+      ExprKind::IfExpr(cond, consequence, alternative) => {
+        self.infer_truthy(cond);
+
+        let then_type = self.infer_expr(consequence);
+        let else_type = self.infer_expr(alternative);
+
+        self.unify(&then_type, &else_type, alternative.span);
+        then_type
+      },
+
+      // the loop variable is a number or an int, as its step is
+      ExprKind::ForInExpr(var_name, initial_val, end_cond, step, body) => {
+        let var_type = self.infer_numeric(initial_val);
+
+        self.locals.push((var_name.to_owned(), var_type.clone()));
+        self.infer_truthy(end_cond);
+        if let Some(step) = step {
+          let step_type = self.infer_expr(step);
+          self.unify(&var_type, &step_type, step.span);
+        }
+        self.infer_expr(body);
+        self.locals.pop();
+
+        Type::Number
+      },
+
+      ExprKind::While(cond, body) => {
+        self.infer_truthy(cond);
+        self.infer_expr(body);
+
+        Type::Number
+      },
+
+      ExprKind::VarIn(bindings, body) => {
+        let outer_len = self.locals.len();
+
+        for (var_name, initializer) in bindings {
+          let ty = match initializer {
+            Some(init) => self.infer_expr(init),
+            None => {
+              let ty = self.fresh();
+              self.uninitialized.push((Some(var_name.to_owned()), ty.clone(), expr.span));
+              ty
+            }
+          };
+          self.locals.push((var_name.to_owned(), ty));
+        }
+
+        let body_type = self.infer_expr(body);
+        self.locals.truncate(outer_len);
+
+        body_type
+      },
+
+      ExprKind::Array(elements) => {
+        let element_type = self.fresh();
+
+        for element in elements {
+          let ty = self.infer_expr(element);
+          self.unify(&element_type, &ty, element.span);
+        }
+
+        Type::Array(Box::new(element_type))
+      },
+
+      ExprKind::Alloc(len) => {
+        let element_type = self.fresh();
+
+        self.infer_numeric(len);
+        self.uninitialized.push((None, element_type.clone(), expr.span));
+        Type::Array(Box::new(element_type))
+      },
+
+      ExprKind::Index(array, index) => {
+        let element_type = self.fresh();
+        let array_type = self.infer_expr(array);
+
+        self.unify(&Type::Array(Box::new(element_type.clone())), &array_type, array.span);
+        self.infer_numeric(index);
+
+        element_type
+      },
+
+      ExprKind::Len(array) => {
+        let array_type = self.infer_expr(array);
+        let any_array = Type::Array(Box::new(self.fresh()));
+
+        self.unify(&any_array, &array_type, array.span);
+        self.fresh_in(Class::Numeric)
+      },
+
+      ExprKind::Construct(name, values) => {
+        let fields = self.structs.fields(name).cloned().unwrap_or_default();
+        let field_types: Vec<Type> = fields.iter().map(|_| self.fresh()).collect();
+
+        for (field, value) in values {
+          let value_type = self.infer_expr(value);
+          if let Some(i) = fields.iter().position(|f| f == field) {
+            self.unify(&field_types[i], &value_type, value.span);
+          }
+        }
+
+        Type::Struct(name.to_owned(), field_types)
+      },
+
+      // the field of whichever struct the object turns out to be, if that isn't known yet
+      ExprKind::Field(object, field) => {
+        let access = FieldAccess {
+          object: self.infer_expr(object),
+          object_span: object.span,
+          field: field.to_owned(),
+          ty: self.fresh(),
+          span: expr.span
+        };
+        let ty = access.ty.clone();
+
+        if !self.resolve_field(&access, false) {
+          self.fields.push(access);
+        }
+        ty
+      },
+
+      ExprKind::Block(exprs) => {
+        let mut ty = Type::Number;
+
+        for expr in exprs {
+          ty = self.infer_expr(expr);
+        }
+
+        ty
+      },
+
+      // reported by `sema::check_program`
+      ExprKind::Function(..) | ExprKind::Extern(..) | ExprKind::Struct(..) => Type::Number
+    }
+  }
+
+  fn local(&self, name: &Name) -> Option<Type> {
+    self.locals.iter().rev().find(|(local, _)| local == name).map(|(_, ty)| ty.clone())
+  }
+
+  fn lookup(&mut self, name: &Name) -> Type {
+    match self.local(name) {
+      Some(ty) => ty,
+      None => self.fresh()
+    }
+  }
+
+  // Infers a call through a function value of type `callee`, from the expression at `span`.
+  fn infer_apply(&mut self, callee: &Type, args: &Program, span: Span) -> Type {
+    let params: Vec<Type> = args.iter().map(|_| self.fresh()).collect();
+    let ret = self.fresh();
+
+    // unified first, so a mistyped argument is reported where it is
+    self.unify(&Type::Fn(params.clone(), Box::new(ret.clone())), callee, span);
+
+    for (param, arg) in params.iter().zip(args) {
+      let arg_type = self.infer_expr(arg);
+      self.unify(param, &arg_type, arg.span);
+    }
+
+    ret
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::parser::parse_program;
+
+  fn infer(source: &str) -> Result<Vec<String>, Vec<String>> {
+    let program = parse_program(source).unwrap();
+
+    infer_program(&program, &HashMap::new(), &HashSet::new(), &Structs::default())
+      .map(|types| types.signatures.iter().map(Signature::to_string).collect())
+      .map_err(|errors| errors.iter().map(CompileError::to_string).collect())
+  }
+
+  #[test]
+  fn infer_program_test() {
+    // Numeric programs infer as numbers, with parameters that are only passed on left generic
+    assert_eq!(infer("
+      extern putchard(c);
+      def fib(x) if x < 3 then 1 else fib(x - 1) + fib(x - 2);
+      def binary: 1 (x y) y;
+      def count(n) var c in (for i = 0, i < n in c = c + 1) : c
+    "), Ok(vec![
+      "(number) -> number".to_string(),
+      "(number) -> number".to_string(),
+      "('a, 'b) -> 'b".to_string(),
+      "(number) -> number".to_string()
+    ]));
+
+    // Generic functions can be used at several types
+    assert_eq!(infer("
+      def id(x) x;
+      def greet(name) { id(name); \"hello\" };
+      def first(a b) if 1 then a else b;
+      id(1) + 2; first(id(\"s\"), greet(0))
+    "), Ok(vec![
+      "('a) -> 'a".to_string(),
+      "('a) -> str".to_string(),
+      "('a, 'a) -> 'a".to_string()
+    ]));
+
+    // Arrays hold elements of a single type
+    assert_eq!(infer("
+      def sum(a) var total in { for i = 0, i < len(a) in total = total + a[i]; total };
+      def fill(a x) for i = 0, i < len(a) in a[i] = x;
+      def names() [\"a\", \"b\"];
+      sum([1, 2]) + fill(names(), \"c\") + fill(alloc(2), 1)
+    "), Ok(vec![
+      "([number]) -> number".to_string(),
+      "(['a], 'a) -> number".to_string(),
+      "() -> [str]".to_string()
+    ]));
+    assert_eq!(infer("[1, \"s\"]; def f(a) a[0] = a; alloc(2)[0][0]").unwrap_err(),
+               vec!["expected `number`, found `str`", "expected `'a`, found `['a]`",
+                    "`alloc` fills arrays with zeros, so their elements can't have type `['a]`"]);
+
+    // Structs are generic over the types of their fields
+    assert_eq!(infer("
+      struct Complex { re, im };
+      struct Pair { first, second };
+      def add(a b) Complex { re: a.re + b.re, im: a.im + b.im };
+      def swap(p) Pair { first: p.second, second: p.first };
+      def label(p) { p.first = \"one\"; p };
+      add(Complex { im: 1, re: 2 }, Complex { re: 0, im: 0 }).re;
+      swap(Pair { first: 1, second: [2] }).second
+    "), Ok(vec![
+      "(Complex<number, number>, Complex<number, number>) -> Complex<number, number>".to_string(),
+      "(Pair<'a, 'b>) -> Pair<'b, 'a>".to_string(),
+      "(Pair<str, 'a>) -> Pair<str, 'a>".to_string()
+    ]));
+
+    // A field is looked up in the struct its object's type is, which a field only one struct has
+    // tells
+    assert_eq!(infer("
+      struct P { x }; struct Q { x, y };
+      def g(q: Q) q.x;
+      def h(q) { q.x; q.y };
+      var q = Q { x: 1, y: \"s\" } in g(q) + P { x: 1 }.x + h(q)
+    ").unwrap_err(), vec!["expected `number`, found `str`"]);
+    assert_eq!(infer("
+      struct P { x }; struct Q { x, y };
+      def g(q: Q) q.x;
+      def h(q) { q.x; q.y };
+      def k(a: Q, b) { a.x = b.y; a }
+    "), Ok(vec![
+      "(Q<'a, 'b>) -> 'a".to_string(),
+      "(Q<'a, 'b>) -> 'b".to_string(),
+      "(Q<'a, 'b>, Q<'c, 'a>) -> Q<'a, 'b>".to_string()
+    ]));
+    assert_eq!(infer("
+      struct P { x }; struct Q { x, y };
+      def f(p) p.x;
+      var p in p.x = 1;
+      def g(q: Q) q.x; g(P { x: 1 });
+      (1).x + g(Q { x: 1, y: 2 }).x
+    ").unwrap_err(), vec![
+      "`.x` may be a field of `P` or `Q`; annotate the type",
+      "`.x` may be a field of `P` or `Q`; annotate the type",
+      "expected `Q<'a, 'b>`, found `P<number>`",
+      "`number` is not a struct, so it has no field `x`",
+      "`number` is not a struct, so it has no field `x`"
+    ]);
+
+    // Functions are values, generic like any other
+    assert_eq!(infer("
+      def apply(f x) f(x);
+      def twice(f x) f(f(x));
+      def integrate(f a b n) var total, dx = (b - a) / n in { for i = 0, i < n in total = total + f(a + i * dx) * dx; total };
+      def square(x) x * x;
+      def negate(x) -x;
+      def pick(c) if c then square else negate;
+      integrate(square, 0, 1, 100) + pick(1)(2) + twice(apply(pick, 0), 3)
+    "), Ok(vec![
+      "(('a) -> 'b, 'a) -> 'b".to_string(),
+      "(('a) -> 'a, 'a) -> 'a".to_string(),
+      "((number) -> number, number, number, number) -> number".to_string(),
+      "(number) -> number".to_string(),
+      "(number) -> number".to_string(),
+      "(number) -> (number) -> number".to_string()
+    ]));
+    assert_eq!(infer("def apply(f x) f(x); apply(1, 2); def g(f) f(\"s\") + f(1, 2); apply(apply, 1)").unwrap_err(), vec![
+      "expected `('a) -> 'b`, found `number`",
+      "expected `('a, 'b) -> 'c`, found `(str) -> number`",
+      "expected `('a) -> 'b`, found `(('c) -> 'd, 'c) -> 'd`"
+    ]);
+
+    // Lambdas capture variables of any type
+    assert_eq!(infer("
+      def adder(k) \\x -> x + k;
+      def compose(f g) \\x -> f(g(x));
+      def greeter(greeting) \\name -> { greeting; name };
+      def apply(f x) f(x);
+      compose(adder(1), \\x -> x * 2)(3) + apply(\\s -> 1, \"s\") + (\\ -> 2)()
+    "), Ok(vec![
+      "(number) -> (number) -> number".to_string(),
+      "(('a) -> 'b, ('c) -> 'a) -> ('c) -> 'b".to_string(),
+      "('a) -> ('b) -> 'b".to_string(),
+      "(('a) -> 'b, 'a) -> 'b".to_string()
+    ]));
+    assert_eq!(infer("var f = \\x -> x in { f(1); f(\"s\") }").unwrap_err(), vec!["expected `number`, found `str`"]);
+
+    // A definition must agree with an earlier `extern`
+    assert_eq!(infer("extern later(x); def f() later(1) + 1; def later(x) \"s\"").unwrap_err(),
+               vec!["expected `number`, found `str`"]);
+
+    // Host functions take and return numbers unless annotated, and runtime functions taking strings
+    // only take strings
+    assert_eq!(infer("extern printstr(s); printstr(\"s\"); extern printd(x); extern strlen(s: str); strlen(\"s\")"), Ok(vec![
+      "(str) -> number".to_string(),
+      "(number) -> number".to_string(),
+      "(str) -> number".to_string()
+    ]));
+    assert_eq!(infer("extern printstr(s); printstr(1)").unwrap_err(), vec!["expected `str`, found `number`"]);
+    assert_eq!(infer("extern printd(x); printd(\"s\")").unwrap_err(), vec!["expected `number`, found `str`"]);
+
+    // Annotations fix the types of parameters and return values, leaving structs not given the
+    // types of their fields generic
+    assert_eq!(infer("
+      struct Pair { a, b };
+      def f(x: str, y) -> [str] [x];
+      def fst(p: Pair) p.a;
+      def g(p: Pair<number, str>, h: (number) -> str) h(p.a)
+    "), Ok(vec![
+      "(str, 'a) -> [str]".to_string(),
+      "(Pair<'a, 'b>) -> 'a".to_string(),
+      "(Pair<number, str>, (number) -> str) -> str".to_string()
+    ]));
+    assert_eq!(infer("def f(x: str) x + 1").unwrap_err(), vec!["expected `number`, found `str`"]);
+    assert_eq!(infer("def f(x) -> str x; f(1) + 1").unwrap_err(), vec!["expected `str`, found `number`", "expected `number`, found `str`"]);
+
+    // Whole numbers may be ints, and comparisons bools, but are numbers unless annotated otherwise
+    assert_eq!(infer("
+      def inc(n: int) n + 1;
+      def less(a b) a < b;
+      def test(a: int, b: int) -> bool a < b && !(a == 0);
+      def count(n: int) var c = 0 in { for i = 0, i < n in c = inc(c); c };
+      def pick(c: bool, x) if c then x else -x;
+      inc(2) < 3
+    "), Ok(vec![
+      "(int) -> int".to_string(),
+      "(number, number) -> number".to_string(),
+      "(int, int) -> bool".to_string(),
+      "(int) -> int".to_string(),
+      "(bool, number) -> number".to_string()
+    ]));
+    assert_eq!(infer("def f(n: int) n + 0.5; def g(b: bool) b + 1; def h(b: bool) b < b").unwrap_err(),
+               vec!["expected `int`, found `number`", "expected `number`, found `bool`", "expected `number`, found `bool`",
+                    "expected `number`, found `bool`"]);
+  }
+
+  #[test]
+  fn infer_errors_test() {
+    let program = parse_program("
+      def num(s) s + 0;
+      num(\"abc\");
+      def pick(c) if c then \"yes\" else 0;
+      var s in { s = \"x\"; s }
+    ").unwrap();
+
+    let errors = infer_program(&program, &HashMap::new(), &HashSet::new(), &Structs::default()).unwrap_err();
+    let messages: Vec<String> = errors.iter().map(|err| err.to_string()).collect();
+
+    assert_eq!(messages, vec![
+      "expected `number`, found `str`",
+      "expected `str`, found `number`",
+      "`s` has type `str`, so it must be given an initial value"
+    ]);
+    assert_eq!(errors.iter().map(|err| err.span().map(|span| span.start)).collect::<Vec<_>>(),
+               vec![Some(35), Some(82), Some(91)]);
+
+    // The type of every expression is kept, in terms of its function's type parameters
+    let program = parse_program("def first(a b) a; first(\"s\", [1])").unwrap();
+    let types = infer_program(&program, &HashMap::new(), &HashSet::new(), &Structs::default()).unwrap();
+
+    assert_eq!(types.exprs, vec![
+      vec![Type::Var(0)],
+      vec![Type::Str, Type::Str, Type::Array(Box::new(Type::Number)), Type::Number]
+    ]);
+
+    // Codegen compiles the instance of a generic function that each call needs
+    let first = &types.signatures[0];
+    assert_eq!(first.instance(&[Type::Str, Type::Number], &Type::Str), Some(vec![Type::Str, Type::Number]));
+    assert_eq!(first.instance(&[Type::Str, Type::Number], &Type::Number), None);
+    assert_eq!(first.instantiate(&[Type::Number, Type::Str]), (vec![Type::Number, Type::Str], Type::Number));
+
+    // Signatures from earlier programs are generic too
+    let declared = vec![("id".to_string(), Signature { vars: vec![0], params: vec![Type::Var(0)], ret: Type::Var(0) })]
+      .into_iter().collect();
+    let defined = vec!["id".to_string()].into_iter().collect();
+    let program = parse_program("id(1) + 1; id(\"s\"); id(\"s\") * 2").unwrap();
+
+    assert_eq!(infer_program(&program, &declared, &defined, &Structs::default()).unwrap_err().len(), 1);
+  }
+}
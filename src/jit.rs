@@ -0,0 +1,175 @@
+use std::fmt;
+use std::rc::Rc;
+
+use inkwell::execution_engine::JitFunction;
+use inkwell::values::BasicValueEnum;
+use inkwell::AddressSpace;
+
+use crate::ast::Name;
+use crate::codegen::{CodeGen, RuntimeError, Trap};
+use crate::host::HostFn;
+
+/**
+ * Errors raised while looking up or calling a compiled function from Rust.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub enum CallError {
+  UnknownFunction(Name),
+  ArityMismatch { name: Name, expected: usize, found: usize },
+  // The function takes or returns something other than numbers, which handles can't pass
+  NotNumeric { name: Name, signature: String },
+  // The execution engine couldn't produce code for the function
+  Jit(String),
+  // The function was stopped by a runtime error
+  Runtime(RuntimeError)
+}
+
+impl fmt::Display for CallError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      CallError::UnknownFunction(name) => write!(f, "unknown function `{}`", name),
+      CallError::ArityMismatch { name, expected, found } =>
+        write!(f, "`{}` takes {} argument(s) but {} were given", name, expected, found),
+      CallError::NotNumeric { name, signature } =>
+        write!(f, "`{}` has type `{}`, but only functions of numbers can be called from Rust", name, signature),
+      CallError::Jit(message) => write!(f, "JIT error: {}", message),
+      CallError::Runtime(err) => write!(f, "{}", err)
+    }
+  }
+}
+
+impl std::error::Error for CallError {}
+
+/**
+ * Handles to compiled functions, as returned by `CodeGen::get_fn`. The execution engine stays alive
+ * as long as a handle does, and so do the host functions the compiled code may call through it.
+ */
+pub trait JitFn<'ctx>: Sized {
+  // `symbol` is the function compiled for `name` taking and returning numbers, and `arity` the
+  // number of parameters it was declared with.
+  fn lookup(codegen: &mut CodeGen<'ctx>, name: &str, symbol: &str, arity: usize) -> Result<Self, CallError>;
+}
+
+macro_rules! f64_for {
+  ($arg:ident) => { f64 }
+}
+
+macro_rules! jit_fn {
+  ($func:ident, $($arg:ident),*) => {
+    /**
+     * A compiled function taking exactly as many `f64`s as `call` does.
+     */
+    pub struct $func<'ctx> {
+      function: JitFunction<'ctx, unsafe extern "C" fn($(f64_for!($arg)),*) -> f64>,
+      // kept alive for the thunks that call them
+      _host_fns: Vec<Rc<HostFn>>,
+      // where the function records the runtime error that stopped it
+      trap: Rc<Trap>
+    }
+
+    impl<'ctx> $func<'ctx> {
+      pub fn call(&self, $($arg: f64),*) -> Result<f64, CallError> {
+        // The function takes and returns `f64`s, and the arity was checked on lookup
+        self.trap.finish(unsafe { self.function.call($($arg),*) }).map_err(CallError::Runtime)
+      }
+    }
+
+    impl<'ctx> JitFn<'ctx> for $func<'ctx> {
+      fn lookup(codegen: &mut CodeGen<'ctx>, name: &str, symbol: &str, arity: usize) -> Result<Self, CallError> {
+        let handle_arity = <[&str]>::len(&[$(stringify!($arg)),*]);
+        if arity != handle_arity {
+          return Err(CallError::ArityMismatch { name: name.to_owned(), expected: arity, found: handle_arity });
+        }
+
+        let function = unsafe { codegen.execution_engine.get_function(symbol) }
+          .map_err(|err| CallError::Jit(format!("{:?}", err)))?;
+
+        Ok($func { function, _host_fns: codegen.host_fns(), trap: codegen.trap() })
+      }
+    }
+  }
+}
+
+jit_fn!(Func0,);
+jit_fn!(Func1, a);
+jit_fn!(Func2, a, b);
+jit_fn!(Func3, a, b, c);
+jit_fn!(Func4, a, b, c, d);
+
+/**
+ * A compiled function of any arity, taking its arguments as a slice. Calls go through a wrapper
+ * compiled on lookup, which loads the arguments from the slice.
+ */
+pub struct FuncN<'ctx> {
+  name: Name,
+  arity: usize,
+  function: JitFunction<'ctx, unsafe extern "C" fn(*const f64) -> f64>,
+  // kept alive for the thunks that call them
+  _host_fns: Vec<Rc<HostFn>>,
+  // where the function records the runtime error that stopped it
+  trap: Rc<Trap>
+}
+
+impl<'ctx> FuncN<'ctx> {
+  pub fn arity(&self) -> usize {
+    self.arity
+  }
+
+  pub fn call(&self, args: &[f64]) -> Result<f64, CallError> {
+    if args.len() != self.arity {
+      return Err(CallError::ArityMismatch { name: self.name.clone(), expected: self.arity, found: args.len() });
+    }
+
+    // The wrapper reads exactly `arity` arguments
+    self.trap.finish(unsafe { self.function.call(args.as_ptr()) }).map_err(CallError::Runtime)
+  }
+}
+
+impl<'ctx> JitFn<'ctx> for FuncN<'ctx> {
+  fn lookup(codegen: &mut CodeGen<'ctx>, name: &str, symbol: &str, arity: usize) -> Result<Self, CallError> {
+    // named after the symbol, so a function redefined since gets a new wrapper
+    let wrapper_name = format!("__call_{}", symbol);
+
+    if codegen.execution_engine.get_function_address(&wrapper_name).is_err() {
+      compile_call_wrapper(codegen, name, &wrapper_name, arity)?;
+    }
+
+    let function = unsafe { codegen.execution_engine.get_function(&wrapper_name) }
+      .map_err(|err| CallError::Jit(format!("{:?}", err)))?;
+
+    Ok(FuncN { name: name.to_owned(), arity, function, _host_fns: codegen.host_fns(), trap: codegen.trap() })
+  }
+}
+
+// Compiles `double wrapper_name(double *args)`, calling `name` with `args[0]` to `args[arity - 1]`.
+// It goes into a module of its own, since the one holding `name` may already have been finalized.
+fn compile_call_wrapper(codegen: &mut CodeGen, name: &str, wrapper_name: &str, arity: usize) -> Result<(), CallError> {
+  codegen.start_module().map_err(|err| CallError::Jit(err.to_string()))?;
+
+  let callee = codegen.numeric_function(name)?;
+
+  let f64_type = codegen.context.f64_type();
+  let i64_type = codegen.context.i64_type();
+  let fn_type = f64_type.fn_type(&[f64_type.ptr_type(AddressSpace::Generic).into()], false);
+  let wrapper = codegen.module.add_function(wrapper_name, fn_type, None);
+  let entry = codegen.context.append_basic_block(wrapper, "entry");
+
+  codegen.builder.position_at_end(entry);
+
+  let args_ptr = wrapper.get_first_param().expect("wrapper takes an argument").into_pointer_value();
+  let args = (0..arity)
+    .map(|i| {
+      let arg_ptr = unsafe { codegen.builder.build_gep(args_ptr, &[i64_type.const_int(i as u64, false)], "argptr") };
+      codegen.builder.build_load(arg_ptr, "arg")
+    })
+    .collect::<Vec<BasicValueEnum>>();
+
+  let value = codegen.builder.build_call(callee, &args, "tmp")
+    .try_as_basic_value()
+    .left()
+    .ok_or_else(|| CallError::Jit("invalid call produced".to_string()))?;
+
+  codegen.builder.build_return(Some(&value));
+
+  Ok(())
+}
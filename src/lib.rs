@@ -0,0 +1,24 @@
+//! A parser and LLVM JIT compiler for the Kaleidoscope toy language.
+//!
+//! `Engine` is the simplest entry point: it compiles and evaluates source text, keeping earlier
+//! definitions around. `parse_program` and `CodeGen` give access to the individual stages.
+
+pub mod ast;
+pub mod codegen;
+pub mod diagnostic;
+pub mod engine;
+pub mod host;
+pub mod infer;
+pub mod jit;
+pub mod link;
+pub mod optimize;
+pub mod parser;
+pub mod sema;
+
+pub use codegen::CodeGen;
+pub use engine::{Engine, Error};
+pub use host::HostFn;
+pub use infer::{Signature, Type};
+pub use jit::{CallError, Func0, Func1, Func2, Func3, Func4, FuncN};
+pub use optimize::{OptConfig, Pass};
+pub use parser::parse_program;
@@ -0,0 +1,32 @@
+use std::path::Path;
+use std::process::Command;
+
+// Runtime providing `putchard`, `printd` and `printstr` to native executables, along with the
+// functions compiled code calls to report out-of-bounds indexing, integer division by zero and
+// failed allocations.
+const RUNTIME_SOURCE: &str = include_str!("../runtime/runtime.c");
+
+/**
+ * Links an object file produced by `CodeGen::write_object_file` with the runtime into a native
+ * executable. Uses the C compiler named by `$CC`, or `cc`.
+ */
+pub fn link_executable(object: &Path, output: &Path) -> Result<(), String> {
+  let runtime = std::env::temp_dir().join(format!("kaleidoscope-runtime-{}.c", std::process::id()));
+  std::fs::write(&runtime, RUNTIME_SOURCE).map_err(|e| format!("Unable to write runtime: {}", e))?;
+
+  let cc = std::env::var("CC").unwrap_or_else(|_| "cc".to_string());
+  let status = Command::new(&cc)
+    .arg(object)
+    .arg(&runtime)
+    .arg("-o")
+    .arg(output)
+    .status();
+
+  let _ = std::fs::remove_file(&runtime);
+
+  match status {
+    Ok(status) if status.success() => Ok(()),
+    Ok(status) => Err(format!("`{}` failed with {}", cc, status)),
+    Err(e) => Err(format!("Unable to run `{}`: {}", cc, e))
+  }
+}
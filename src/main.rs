@@ -1,110 +1,299 @@
-mod parser;
-mod codegen;
-mod ast;
+mod repl;
 
+use std::error::Error;
 use std::fs::File;
 use std::io::prelude::*;
-use std::error::Error;
+use std::path::{Path, PathBuf};
 
 use inkwell::context::Context;
-use inkwell::passes::PassManager;
+use inkwell::targets::FileType;
+use inkwell::OptimizationLevel;
+
+use kaleidoscope::jit::CallError;
+use kaleidoscope::optimize::OptConfig;
+use kaleidoscope::{ast, codegen, link, parser};
+
+const USAGE: &str = "\
+Usage: kaleidoscope [command] [options] <file>
+
+Commands:
+  run        compile <file> with the JIT and run it (the default)
+  check      parse and compile <file>, only reporting errors
+  build      compile <file> into a native executable, linked with the runtime
+  emit-ast   print the syntax tree
+  emit-llvm  print the LLVM IR
+  emit-bc    write LLVM bitcode, to <file>.bc by default
+  emit-asm   print native assembly
+  emit-obj   write a native object file, to <file>.o by default
+  repl       start an interactive session
+
+Options:
+  -O0 .. -O3   optimization level, -O2 by default
+  --passes <passes>
+               run these comma-separated passes instead of those of the -O level,
+               e.g. `mem2reg,instcombine,gvn,inline`
+  -o <file>    write the output to <file> rather than the default
+  -c           with `build`, stop at the object file
+  --check-loops-last
+               check the condition of `for` loops after each run of the body, as in
+               the LLVM tutorial, rather than before it
+  --quiet      with `run`, don't print the value of each top-level expression
+  --dump-ast   with `run`, print the syntax tree to stderr first
+  --dump-llvm  with `run`, print the LLVM IR to stderr before running
+  -h, --help   show this message
+
+<file> may be `-` to read the program from standard input.";
+
+const COMMANDS: [&str; 9] = ["run", "check", "build", "emit-ast", "emit-llvm", "emit-bc", "emit-asm", "emit-obj", "repl"];
 
 /**
- * main
+ * Options given on the command line.
  */
-fn main() -> Result<(), Box<dyn Error>> {
-  let args: Vec<String> = env::args().collect();
+struct Options {
+  command: String,
+  input: Option<String>,
+  output: Option<PathBuf>,
+  opt_level: OptimizationLevel,
+  passes: Option<String>,
+  emit_executable: bool,
+  check_loops_last: bool,
+  quiet: bool,
+  dump_ast: bool,
+  dump_llvm: bool
+}
+
+impl Options {
+  // Parses the arguments following the program name. The command may be left out, for `run`.
+  fn parse(args: &[String]) -> Result<Options, String> {
+    let mut options = Options {
+      command: "run".to_string(),
+      input: None,
+      output: None,
+      opt_level: OptimizationLevel::Default,
+      passes: None,
+      emit_executable: true,
+      check_loops_last: false,
+      quiet: false,
+      dump_ast: false,
+      dump_llvm: false
+    };
+
+    let mut args = args.iter().peekable();
+    if let Some(command) = args.peek().filter(|arg| COMMANDS.contains(&arg.as_str())) {
+      options.command = command.to_string();
+      args.next();
+    }
+
+    while let Some(arg) = args.next() {
+      match arg.as_str() {
+        "-O0" => options.opt_level = OptimizationLevel::None,
+        "-O1" => options.opt_level = OptimizationLevel::Less,
+        "-O2" => options.opt_level = OptimizationLevel::Default,
+        "-O3" => options.opt_level = OptimizationLevel::Aggressive,
+        "--passes" => options.passes = Some(args.next().ok_or("--passes needs a list of passes")?.clone()),
+        "-o" => options.output = Some(PathBuf::from(args.next().ok_or("-o needs a file name")?)),
+        "-c" => options.emit_executable = false,
+        "--check-loops-last" => options.check_loops_last = true,
+        "--quiet" => options.quiet = true,
+        "--dump-ast" => options.dump_ast = true,
+        "--dump-llvm" => options.dump_llvm = true,
+        "-" => options.input = Some(arg.clone()),
+        flag if flag.starts_with('-') => return Err(format!("unknown option `{}`", flag)),
+        _ if options.input.is_some() => return Err(format!("unexpected argument `{}`", arg)),
+        _ => options.input = Some(arg.clone())
+      }
+    }
 
-  let filename = std::env::args().nth(1).expect("no filename given");
+    Ok(options)
+  }
 
-  let mut file = File::open(filename)?;
+  // The optimization preset for the `-O` level, with its passes replaced by `--passes` if given.
+  fn opt_config(&self) -> Result<OptConfig, String> {
+    let mut opt = OptConfig::preset(self.opt_level);
+    if let Some(passes) = &self.passes {
+      opt.passes = OptConfig::parse_passes(passes)?;
+    }
+
+    Ok(opt)
+  }
+
+  // The output path, or the input's with the extension `ext` if none was given.
+  fn output_or_default(&self, input: &str, ext: &str) -> PathBuf {
+    match &self.output {
+      Some(output) => output.clone(),
+      None if input == "-" => Path::new("a").with_extension(ext),
+      None => Path::new(input).with_extension(ext)
+    }
+  }
+}
+
+// Reads the source of a program, from standard input if `input` is `-`. Returns the name to report
+// errors against along with the source.
+fn read_source(input: &str) -> Result<(String, String), Box<dyn Error>> {
   let mut contents = String::new();
-  file.read_to_string(&mut contents)?;
 
-  let parser_res = parser::parse_program(&contents).unwrap().1;
-  println!("Parsed: {:?}", parser_res);
+  if input == "-" {
+    std::io::stdin().read_to_string(&mut contents)?;
+    Ok(("<stdin>".to_string(), contents))
+  } else {
+    File::open(input)?.read_to_string(&mut contents)?;
+    Ok((input.to_string(), contents))
+  }
+}
 
-  // Create codegen
-  let context = Context::create();
-  let module = Box::new(context.create_module("tmp")); // could be repl, tmp, etc
-  let fpm = PassManager::create(&*module);
-  fpm.add_instruction_combining_pass();
-  fpm.add_reassociate_pass();
-  fpm.add_gvn_pass();
-  fpm.add_cfg_simplification_pass();
-  fpm.add_basic_alias_analysis_pass();
-  fpm.add_promote_memory_to_register_pass();
-  fpm.add_instruction_combining_pass();
-  fpm.add_reassociate_pass();
-  fpm.initialize();
-
-  let mut codegen = codegen::CodeGen::mk_compiler(&context, &fpm, module)?;
-  codegen.compile_program(&parser_res)?;
-
-  let main_fn = codegen.jit_compile_main().ok_or("Unable to JIT compile `main`")?;
-
-  // Execute the main fn of the JOT-compiled program
-  unsafe { main_fn.call() };
+// Parses a source file, reporting every syntax error and exiting if there are any.
+fn load_program(filename: &str, source: &str) -> ast::Program {
+  match parser::parse_program(source) {
+    Ok(program) => program,
+    Err(errors) => {
+      for err in errors {
+        eprintln!("{}\n", err.with_file(filename));
+      }
+      std::process::exit(1);
+    }
+  }
+}
+
+// Checks and compiles a parsed program, reporting every error in context and exiting if there are
+// any. Returns the anonymous functions compiled for top-level expressions.
+fn compile(codegen: &mut codegen::CodeGen, program: &ast::Program, filename: &str, source: &str) -> Vec<String> {
+  let checked = match codegen.check_program(program) {
+    Ok(checked) => checked,
+    Err(errors) => {
+      for err in errors {
+        eprintln!("{}\n", err.render(filename, source));
+      }
+      std::process::exit(1);
+    }
+  };
+
+  match codegen.compile_program(checked) {
+    Ok(anon_exprs) => anon_exprs,
+    Err(err) => {
+      eprintln!("{}", err.render(filename, source));
+      std::process::exit(1);
+    }
+  }
+}
+
+// Writes text or binary output to `path`, or to standard output if there is none.
+fn write_output(path: Option<&Path>, bytes: &[u8]) -> Result<(), Box<dyn Error>> {
+  match path {
+    Some(path) => File::create(path)?.write_all(bytes)?,
+    None => std::io::stdout().write_all(bytes)?
+  }
+
+  Ok(())
+}
+
+/**
+ * run: evaluates the top-level expressions of a file in order, printing their values, then runs its
+ * `main` function if there is one.
+ */
+fn run(codegen: &mut codegen::CodeGen, anon_exprs: Vec<String>, options: &Options) -> Result<(), Box<dyn Error>> {
+  if options.dump_llvm {
+    codegen.module.print_to_stderr();
+  }
+
+  let main_fn = codegen.jit_compile_main().map_err(|err| match err {
+    CallError::ArityMismatch { expected, .. } =>
+      format!("`main` must take no parameters, but takes {}", expected),
+    CallError::NotNumeric { signature, .. } =>
+      format!("`main` must have type `() -> number`, but has type `{}`", signature),
+    err => err.to_string()
+  })?;
+  if main_fn.is_none() && anon_exprs.is_empty() {
+    return Err("Unable to JIT compile `main`".into());
+  }
+
+  for anon_expr in anon_exprs {
+    let value = codegen.jit_eval_anon_expr(&anon_expr)?;
+    if !options.quiet {
+      println!("Evaluated to {}", value);
+    }
+  }
+
+  if let Some(main_fn) = main_fn {
+    main_fn.call()?;
+  }
 
   Ok(())
 }
 
-#[test]
-fn parse_expr_test() {
-  // Parse basic numbers and var references
-  assert_eq!(parser::parse_expr("1.1"), Ok(("", Expr::Float(1.1))));
-  assert_eq!(parser::parse_expr("2.7"), Ok(("", Expr::Float(2.7))));
-  assert_eq!(parser::parse_expr("hello"), Ok(("", Expr::Var("hello".to_string()))));
-  assert_eq!(parser::parse_expr("foobar"), Ok(("", Expr::Var("foobar".to_string()))));
-
-  // Parse a basic "Program"
-  assert_eq!(parser::parse_program("foobar;1.3"), Ok(("", vec![Expr::Var("foobar".to_string()), Expr::Float(1.3)])));
-
-  // Errors with basic parsing
-  assert_eq!(parser::parse_program("1five"), Err(nom::Err::Error(nom::error::Error::new(&"five"[..], nom::error::ErrorKind::Eof))));
-  assert_eq!(parser::parse_program("five 1"), Err(nom::Err::Error(nom::error::Error::new(&" 1"[..], nom::error::ErrorKind::Eof))));
-
-  // Parse basic arithmetic
-  assert_eq!(parser::parse_program("five+1.4"), Ok(("", vec![Expr::BinOp(Op::Plus, Box::new(Expr::Var("five".to_string())), Box::new(Expr::Float(1.4)))])));
-  assert_eq!(parser::parse_program("five + 1.4"), Ok(("", vec![Expr::BinOp(Op::Plus, Box::new(Expr::Var("five".to_string())), Box::new(Expr::Float(1.4)))])));
-  assert_eq!(parser::parse_program("6 * 7"), Ok(("", vec![Expr::BinOp(Op::Multiply, Box::new(Expr::Float(6.0)), Box::new(Expr::Float(7.0)))])));
-
-  // Parse arithmetic with precedence
-  assert_eq!(parser::parse_program("5 + 6 * 7"), Ok(("", vec![Expr::BinOp(Op::Plus, Box::new(Expr::Float(5.0)), Box::new(Expr::BinOp(Op::Multiply, Box::new(Expr::Float(6.0)), Box::new(Expr::Float(7.0)))))])));
-
-  // Parse arithmetic with parenthetical
-  assert_eq!(parser::parse_program("5 * (6 + 7)"), Ok(("", vec![Expr::BinOp(Op::Multiply, Box::new(Expr::Float(5.0)), Box::new(Expr::BinOp(Op::Plus, Box::new(Expr::Float(6.0)), Box::new(Expr::Float(7.0)))))])));
-
-  // Parse call
-  assert_eq!(parser::parse_program("foobar()"), Ok(("", vec![Expr::Call("foobar".to_string(), vec![])])));
-  assert_eq!(parser::parse_program("foobar(1, 2)"), Ok(("", vec![Expr::Call("foobar".to_string(), vec![Expr::Float(1.0), Expr::Float(2.0)])])));
-  assert_eq!(parser::parse_program("foobar(1, 2, 3+4)"), Ok(("", vec![Expr::Call("foobar".to_string(), vec![Expr::Float(1.0), Expr::Float(2.0), Expr::BinOp(Op::Plus, Box::new(Expr::Float(3.0)), Box::new(Expr::Float(4.0)))])])));
-  assert_eq!(parser::parse_program("foobar(1, 2, 3+4, baz() )"), Ok(("", vec![Expr::Call("foobar".to_string(), vec![Expr::Float(1.0), Expr::Float(2.0), Expr::BinOp(Op::Plus, Box::new(Expr::Float(3.0)), Box::new(Expr::Float(4.0))), Expr::Call("baz".to_string(), vec![])])])));
-
-  // Parse function definitions
-  assert_eq!(parser::parse_program("def foobar(term1 term2 term3) { baz(term1 + term2 + term3) }"), Ok(("",
-    vec![
-      Expr::Function("foobar".to_string(),
-        vec!["term1".to_string(), "term2".to_string(), "term3".to_string()],
-        vec![Expr::Call(
-          "baz".to_string(),
-          vec![
-            Expr::BinOp(Op::Plus,
-                        Box::new(Expr::BinOp(Op::Plus,
-                                             Box::new(Expr::Var("term1".to_string())),
-                                             Box::new(Expr::Var("term2".to_string()))
-                                             )),
-                        Box::new(Expr::Var("term3".to_string()))
-                       )
-          ]
-        )
-      ])
-    ]
-  )));
-
-  // extern
-  assert_eq!(parser::parse_program("extern foobar(param1 param2 param3)"), Ok(("", vec![Expr::Extern("foobar".to_string(), vec!["param1".to_string(), "param2".to_string(), "param3".to_string()])])));
-
-  // This looks correct
-  //assert_eq!(parser::parse_program("extern foobar(param1 param2 param3); def foo(item1) { foobar(item1 + 2); baz(17) }"), Ok(("", vec![Expr::Extern("foobar".to_string(), vec!["param1".to_string(), "param2".to_string(), "param3".to_string()])])));
+/**
+ * build: compiles a file ahead of time into `<output>.o`, then links that with the runtime into the
+ * executable `<output>` unless `-c` is given. The output defaults to the source file's name.
+ */
+fn build(codegen: &mut codegen::CodeGen, anon_exprs: Vec<String>, input: &str, options: &Options) -> Result<(), Box<dyn Error>> {
+  let output = options.output_or_default(input, "");
+  let object = output.with_extension("o");
+
+  codegen.build_entry_point(&anon_exprs)?;
+  codegen.write_object_file(&object)?;
+
+  if options.emit_executable {
+    link::link_executable(&object, &output)?;
+  }
+
+  Ok(())
+}
+
+/**
+ * main
+ */
+fn main() -> Result<(), Box<dyn Error>> {
+  let args: Vec<String> = std::env::args().skip(1).collect();
+
+  if args.is_empty() || args.iter().any(|arg| arg == "-h" || arg == "--help") {
+    println!("{}", USAGE);
+    return Ok(());
+  }
+
+  let options = Options::parse(&args).map_err(|err| format!("{}\n\n{}", err, USAGE))?;
+  let opt = options.opt_config()?;
+
+  if options.command == "repl" {
+    return repl::run(opt);
+  }
+
+  let input = options.input.as_deref().ok_or_else(|| format!("no filename given\n\n{}", USAGE))?;
+  let (filename, source) = read_source(input)?;
+  let program = load_program(&filename, &source);
+
+  if options.command == "emit-ast" {
+    return write_output(options.output.as_deref(), format!("{:#?}\n", program).as_bytes());
+  }
+  if options.dump_ast {
+    eprintln!("Parsed: {:?}", program);
+  }
+
+  let context = Context::create();
+  let mut codegen = codegen::CodeGen::mk_compiler_with(&context, &filename, opt)?;
+  if options.check_loops_last {
+    codegen.set_loop_check(codegen::LoopCheck::AfterBody);
+  }
+  // Only the JIT returns runtime errors; native code leaves them to the runtime
+  codegen.trap_runtime_errors(options.command == "run");
+  let anon_exprs = compile(&mut codegen, &program, &filename, &source);
+
+  match options.command.as_str() {
+    "run" => run(&mut codegen, anon_exprs, &options),
+    "check" => Ok(()),
+    "build" => build(&mut codegen, anon_exprs, input, &options),
+    "emit-llvm" => write_output(options.output.as_deref(), codegen.module.print_to_string().to_bytes()),
+    "emit-bc" => {
+      let output = options.output_or_default(input, "bc");
+      write_output(Some(&output), codegen.module.write_bitcode_to_memory().as_slice())
+    },
+    "emit-asm" => {
+      let asm = codegen.emit_machine_code(FileType::Assembly)?;
+      write_output(options.output.as_deref(), asm.as_slice())
+    },
+    "emit-obj" => {
+      let output = options.output_or_default(input, "o");
+      let object = codegen.emit_machine_code(FileType::Object)?;
+      write_output(Some(&output), object.as_slice())
+    },
+    command => unreachable!("unhandled command `{}`", command)
+  }
 }
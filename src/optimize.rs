@@ -0,0 +1,192 @@
+use std::fmt;
+use std::str::FromStr;
+
+use inkwell::module::Module;
+use inkwell::passes::{PassManager, PassManagerSubType};
+use inkwell::values::FunctionValue;
+use inkwell::OptimizationLevel;
+
+/**
+ * Optimization passes that can be run over compiled code, named as in `opt`.
+ */
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Pass {
+  // Function passes, run on each function as soon as it is compiled
+  BasicAliasAnalysis,
+  PromoteMemoryToRegister,
+  InstructionCombining,
+  Reassociate,
+  Gvn,
+  CfgSimplification,
+  TailCallElimination,
+  DeadStoreElimination,
+  Licm,
+  LoopUnroll,
+  AggressiveDce,
+  // Module passes, run on a whole module once everything in it is compiled
+  FunctionInlining,
+  GlobalDce,
+  GlobalOptimizer,
+  Ipsccp
+}
+
+const PASS_NAMES: [(Pass, &str); 15] = [
+  (Pass::BasicAliasAnalysis, "basic-aa"),
+  (Pass::PromoteMemoryToRegister, "mem2reg"),
+  (Pass::InstructionCombining, "instcombine"),
+  (Pass::Reassociate, "reassociate"),
+  (Pass::Gvn, "gvn"),
+  (Pass::CfgSimplification, "simplifycfg"),
+  (Pass::TailCallElimination, "tailcallelim"),
+  (Pass::DeadStoreElimination, "dse"),
+  (Pass::Licm, "licm"),
+  (Pass::LoopUnroll, "loop-unroll"),
+  (Pass::AggressiveDce, "adce"),
+  (Pass::FunctionInlining, "inline"),
+  (Pass::GlobalDce, "globaldce"),
+  (Pass::GlobalOptimizer, "globalopt"),
+  (Pass::Ipsccp, "ipsccp")
+];
+
+impl Pass {
+  pub fn is_module_pass(self) -> bool {
+    matches!(self, Pass::FunctionInlining | Pass::GlobalDce | Pass::GlobalOptimizer | Pass::Ipsccp)
+  }
+
+  fn add_to<T: PassManagerSubType>(self, pass_manager: &PassManager<T>) {
+    match self {
+      Pass::BasicAliasAnalysis => pass_manager.add_basic_alias_analysis_pass(),
+      Pass::PromoteMemoryToRegister => pass_manager.add_promote_memory_to_register_pass(),
+      Pass::InstructionCombining => pass_manager.add_instruction_combining_pass(),
+      Pass::Reassociate => pass_manager.add_reassociate_pass(),
+      Pass::Gvn => pass_manager.add_gvn_pass(),
+      Pass::CfgSimplification => pass_manager.add_cfg_simplification_pass(),
+      Pass::TailCallElimination => pass_manager.add_tail_call_elimination_pass(),
+      Pass::DeadStoreElimination => pass_manager.add_dead_store_elimination_pass(),
+      Pass::Licm => pass_manager.add_licm_pass(),
+      Pass::LoopUnroll => pass_manager.add_loop_unroll_pass(),
+      Pass::AggressiveDce => pass_manager.add_aggressive_dce_pass(),
+      Pass::FunctionInlining => pass_manager.add_function_inlining_pass(),
+      Pass::GlobalDce => pass_manager.add_global_dce_pass(),
+      Pass::GlobalOptimizer => pass_manager.add_global_optimizer_pass(),
+      Pass::Ipsccp => pass_manager.add_ipsccp_pass()
+    }
+  }
+}
+
+impl fmt::Display for Pass {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let (_, name) = PASS_NAMES.iter().find(|(pass, _)| pass == self).expect("every pass has a name");
+    write!(f, "{}", name)
+  }
+}
+
+impl FromStr for Pass {
+  type Err = String;
+
+  fn from_str(name: &str) -> Result<Pass, String> {
+    PASS_NAMES.iter()
+      .find(|(_, pass_name)| *pass_name == name)
+      .map(|(pass, _)| *pass)
+      .ok_or_else(|| format!("unknown pass `{}`", name))
+  }
+}
+
+/**
+ * Which passes to run over compiled code, and how hard the backend (the JIT, or the target machine
+ * for native output) should optimize the machine code it generates.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct OptConfig {
+  pub level: OptimizationLevel,
+  pub passes: Vec<Pass>
+}
+
+impl OptConfig {
+  // The passes `-O0` to `-O3` run. Each level runs the passes of the one below and then some.
+  pub fn preset(level: OptimizationLevel) -> OptConfig {
+    let mut passes = Vec::new();
+
+    if level != OptimizationLevel::None {
+      passes.extend(&[Pass::PromoteMemoryToRegister, Pass::InstructionCombining, Pass::CfgSimplification]);
+    }
+    if level == OptimizationLevel::Default || level == OptimizationLevel::Aggressive {
+      passes.extend(&[
+        Pass::BasicAliasAnalysis,
+        Pass::Reassociate,
+        Pass::Gvn,
+        Pass::TailCallElimination,
+        Pass::InstructionCombining,
+        Pass::CfgSimplification,
+        Pass::FunctionInlining,
+        Pass::GlobalDce
+      ]);
+    }
+    if level == OptimizationLevel::Aggressive {
+      passes.extend(&[
+        Pass::Licm,
+        Pass::LoopUnroll,
+        Pass::DeadStoreElimination,
+        Pass::AggressiveDce,
+        Pass::Ipsccp,
+        Pass::GlobalOptimizer
+      ]);
+    }
+
+    OptConfig { level, passes }
+  }
+
+  // Parses a comma-separated list of pass names, such as `mem2reg,gvn,inline`.
+  pub fn parse_passes(list: &str) -> Result<Vec<Pass>, String> {
+    list.split(',').map(|name| name.trim().parse()).collect()
+  }
+
+  // Creates the pass manager run on each function compiled into a module.
+  pub fn function_pass_manager<'ctx>(&self, module: &Module<'ctx>) -> PassManager<FunctionValue<'ctx>> {
+    let pass_manager = PassManager::create(module);
+
+    for pass in self.passes.iter().filter(|pass| !pass.is_module_pass()) {
+      pass.add_to(&pass_manager);
+    }
+    pass_manager.initialize();
+
+    pass_manager
+  }
+
+  // Creates the pass manager run on a whole module, or `None` if there are no module passes.
+  pub fn module_pass_manager<'ctx>(&self) -> Option<PassManager<Module<'ctx>>> {
+    let module_passes: Vec<&Pass> = self.passes.iter().filter(|pass| pass.is_module_pass()).collect();
+    if module_passes.is_empty() {
+      return None;
+    }
+
+    let pass_manager = PassManager::create(());
+    for pass in module_passes {
+      pass.add_to(&pass_manager);
+    }
+
+    Some(pass_manager)
+  }
+}
+
+impl Default for OptConfig {
+  fn default() -> OptConfig {
+    OptConfig::preset(OptimizationLevel::Default)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parse_passes_test() {
+    assert_eq!(OptConfig::parse_passes("mem2reg, gvn,inline"),
+               Ok(vec![Pass::PromoteMemoryToRegister, Pass::Gvn, Pass::FunctionInlining]));
+    assert_eq!(OptConfig::parse_passes("gvn,nope"), Err("unknown pass `nope`".to_string()));
+    assert_eq!(Pass::TailCallElimination.to_string(), "tailcallelim");
+
+    assert!(OptConfig::preset(OptimizationLevel::None).passes.is_empty());
+    assert!(OptConfig::preset(OptimizationLevel::Aggressive).passes.contains(&Pass::FunctionInlining));
+  }
+}
@@ -1,102 +1,390 @@
 extern crate nom;
 
-use crate::ast::{Expr, Op, Program};
+use crate::ast::{Annotations, Expr, ExprKind, Name, Op, Program, Span, TypeExpr, UnaryOp};
+use crate::diagnostic;
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 
 use nom::{
   branch::alt,
-  bytes::complete::{is_a, tag, take_while, take_until},
-  character::complete::{char, digit1, one_of, none_of, multispace0, multispace1},
-  combinator::{map, recognize},
-  sequence::{pair, preceded, terminated},
+  bytes::complete::{tag, take_while},
+  character::complete::{digit1, one_of, none_of, multispace0, multispace1},
+  combinator::{eof, map_res, opt, peek, recognize},
+  sequence::{preceded, terminated},
   multi::{fold_many0, many0, separated_list0, separated_list1},
-  number::streaming,
+  number::complete::double,
   IResult,
 };
 
-fn parse_bin_op4(s: &str) -> IResult<&str, Expr> {
-  // Parse left/first expr
-  let (s, init) = parse_term(s)?;
+struct ParserState {
+  // Every operator the parser knows about. Borrowed from the `Operators` given for the duration of
+  // a parse, and given back at the end of it.
+  operators: Operators,
 
-  // fold expressions
-  fold_many0(
-    pair(
-      preceded(multispace0, terminated(alt((
-        map(char('*'), |_| Op::Multiply),
-        map(char('/'), |_| Op::Divide)
-      )), multispace0)),
-      parse_term
-    ),
-    init,
-    |acc, (op, val)| Expr::BinOp(op, Box::new(acc), Box::new(val))
-  )(s)
+  // Length of the whole source text. Every slice the parsers see is a suffix of it, so this is
+  // enough to turn a slice back into a byte offset.
+  source_len: usize,
+
+  // Furthest offset at which a parser failed, and everything that was expected there.
+  error_offset: usize,
+  expected: Vec<String>
 }
 
-fn parse_bin_op3(s: &str) -> IResult<&str, Expr> {
-  // Parse left/first expr
-  let (s, init) = parse_bin_op4(s)?;
+thread_local! {
+  static STATE: RefCell<ParserState> = RefCell::new(ParserState {
+    operators: Operators { precedence: HashMap::new(), unary: HashSet::new() },
+    source_len: 0,
+    error_offset: 0,
+    expected: Vec::new()
+  });
+}
 
-  // fold expressions
-  fold_many0(
-    pair(
-      preceded(multispace0, terminated(alt((
-        map(char('+'), |_| Op::Plus),
-        map(char('-'), |_| Op::Minus)
-      )), multispace0)),
-      parse_bin_op4
-    ),
-    init,
-    |acc, (op, val)| Expr::BinOp(op, Box::new(acc), Box::new(val))
-  )(s)
+/**
+ * The operators known to the parser: the binary operators, keyed by their symbol, with their
+ * precedence, and the unary operators defined by `def unary<op>`. The built-in binary operators are
+ * always present; parsing `def binary<op> <prec>` adds an entry as soon as the definition is parsed,
+ * so the operator is usable by everything that follows it. A `def unary!` or `def unary-` takes the
+ * place of the built-in operator in everything that follows it, as in the tutorial.
+ */
+#[derive(Clone, Debug)]
+pub struct Operators {
+  precedence: HashMap<String, u32>,
+  unary: HashSet<char>
 }
 
-fn parse_bin_op2(s: &str) -> IResult<&str, Expr> {
-  // Parse left/first expr
-  let (s, init) = parse_bin_op3(s)?;
+impl Default for Operators {
+  fn default() -> Operators {
+    Operators { precedence: builtin_binop_precedence(), unary: HashSet::new() }
+  }
+}
 
-  // fold expressions
-  fold_many0(
-    pair(
-      preceded(multispace0, terminated(alt((
-        map(char('<'), |_| Op::LessThan),
-        map(char('>'), |_| Op::GreaterThan)
-      )), multispace0)),
-      parse_bin_op3
-    ),
-    init,
-    |acc, (op, val)| Expr::BinOp(op, Box::new(acc), Box::new(val))
-  )(s)
+/**
+ * A syntax error, located at the furthest point the parser managed to reach.
+ */
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseError {
+  pub file: Option<String>,
+  pub span: Span,
+  pub line: usize,
+  pub column: usize,
+  pub expected: Vec<String>,
+  pub excerpt: String
 }
 
-fn parse_bin_op1(s: &str) -> IResult<&str, Expr> {
-  let reserved_symbols = "_abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789{}();,";
+impl ParseError {
+  fn new(source: &str, offset: usize, expected: Vec<String>) -> ParseError {
+    let span = Span::new(offset, offset + 1);
+    let (line, column) = diagnostic::line_col(source, offset);
 
-  // Parse left/first expr
-  let (s, init) = parse_bin_op2(s)?;
+    ParseError {
+      file: None,
+      span,
+      line,
+      column,
+      expected,
+      excerpt: diagnostic::excerpt(source, span)
+    }
+  }
 
-  // fold expressions
-  fold_many0(
-    pair(
-      preceded(multispace0, terminated(none_of(reserved_symbols), multispace0)),
-      parse_bin_op2
-    ),
-    init,
-    |acc, (custom_bin_op, val)| {
+  pub fn with_file(mut self, file: &str) -> ParseError {
+    self.file = Some(file.to_string());
+    self
+  }
+}
+
+impl fmt::Display for ParseError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    if let Some(file) = &self.file {
+      write!(f, "{}:", file)?;
+    }
+    write!(f, "{}:{}: syntax error: ", self.line, self.column)?;
+
+    match self.expected.split_last() {
+      None => write!(f, "unexpected input")?,
+      Some((last, [])) => write!(f, "expected {}", last)?,
+      Some((last, rest)) => write!(f, "expected {} or {}", rest.join(", "), last)?
+    }
+
+    write!(f, "\n{}", self.excerpt)
+  }
+}
+
+impl std::error::Error for ParseError {}
+
+// Precedence given to `def binary<op>` definitions that don't specify one, as in the LLVM tutorial.
+const DEFAULT_BINOP_PRECEDENCE: u32 = 30;
+
+fn builtin_binop_precedence() -> HashMap<String, u32> {
+  let mut table = HashMap::new();
+  table.insert("=".to_string(), 2);
+  table.insert("||".to_string(), 6);
+  table.insert("&&".to_string(), 8);
+  table.insert("==".to_string(), 9);
+  table.insert("!=".to_string(), 9);
+  table.insert("<".to_string(), 10);
+  table.insert("<=".to_string(), 10);
+  table.insert(">".to_string(), 10);
+  table.insert(">=".to_string(), 10);
+  table.insert("+".to_string(), 20);
+  table.insert("-".to_string(), 20);
+  table.insert("*".to_string(), 40);
+  table.insert("/".to_string(), 40);
+  table.insert("%".to_string(), 40);
+  table
+}
+
+fn builtin_binop(symbol: &str) -> Option<Op> {
+  match symbol {
+    "=" => Some(Op::Assign),
+    "||" => Some(Op::Or),
+    "&&" => Some(Op::And),
+    "==" => Some(Op::Equal),
+    "!=" => Some(Op::NotEqual),
+    "<" => Some(Op::LessThan),
+    "<=" => Some(Op::LessEqual),
+    ">" => Some(Op::GreaterThan),
+    ">=" => Some(Op::GreaterEqual),
+    "+" => Some(Op::Plus),
+    "-" => Some(Op::Minus),
+    "*" => Some(Op::Multiply),
+    "/" => Some(Op::Divide),
+    "%" => Some(Op::Remainder),
+    _ => None
+  }
+}
+
+fn builtin_unary_op(symbol: char) -> Option<UnaryOp> {
+  match symbol {
+    '!' => Some(UnaryOp::Not),
+    '-' => Some(UnaryOp::Negate),
+    _ => None
+  }
+}
+
+fn register_binop(symbol: &str, precedence: u32) {
+  STATE.with(|state| {
+    state.borrow_mut().operators.precedence.insert(symbol.to_string(), precedence);
+  });
+}
+
+fn register_unary_op(symbol: char) {
+  STATE.with(|state| {
+    state.borrow_mut().operators.unary.insert(symbol);
+  });
+}
+
+fn is_defined_unary_op(symbol: char) -> bool {
+  STATE.with(|state| state.borrow().operators.unary.contains(&symbol))
+}
+
+// The operators as they stand, to go back to if an item defining one fails to parse.
+fn known_operators() -> Operators {
+  STATE.with(|state| state.borrow().operators.clone())
+}
+
+fn restore_operators(operators: Operators) {
+  STATE.with(|state| state.borrow_mut().operators = operators);
+}
+
+// Runs `parse` with the operators of `operators`, and keeps those it defines in there.
+fn with_operators<T>(operators: &mut Operators, parse: impl FnOnce() -> T) -> T {
+  let swap = |operators: &mut Operators| STATE.with(|state| {
+    std::mem::swap(&mut state.borrow_mut().operators, operators);
+  });
+
+  swap(operators);
+  let res = parse();
+  swap(operators);
+  res
+}
+
+// Prepares the parser state for parsing `source`.
+fn begin(source: &str) {
+  STATE.with(|state| {
+    state.borrow_mut().source_len = source.len();
+  });
+  clear_expected();
+}
+
+// Forgets the expectations noted so far, before starting on a new top-level item.
+fn clear_expected() {
+  STATE.with(|state| {
+    let mut state = state.borrow_mut();
+    state.error_offset = 0;
+    state.expected.clear();
+  });
+}
+
+// Byte offset of the remaining input `s` within the source being parsed.
+fn offset(s: &str) -> usize {
+  STATE.with(|state| state.borrow().source_len - s.len())
+}
+
+// Span of the text consumed between `input` and `rest`, without surrounding whitespace.
+fn span_between(input: &str, rest: &str) -> Span {
+  let consumed = &input[..input.len() - rest.len()];
+  let start = offset(input) + (consumed.len() - consumed.trim_start().len());
+
+  Span::new(start, start + consumed.trim().len())
+}
+
+// Remembers that `what` was expected at `s`, keeping only the expectations at the furthest offset.
+fn note_expected(s: &str, what: String) {
+  let at = offset(s.trim_start());
+
+  STATE.with(|state| {
+    let mut state = state.borrow_mut();
+    if at > state.error_offset {
+      state.error_offset = at;
+      state.expected.clear();
+    }
+    if at == state.error_offset && !state.expected.contains(&what) {
+      state.expected.push(what);
+    }
+  });
+}
+
+// Runs `parser`, noting `what` as expected if it fails.
+fn expect<'a, O, F>(what: &'static str, mut parser: F) -> impl FnMut(&'a str) -> IResult<&'a str, O>
+where F: FnMut(&'a str) -> IResult<&'a str, O> {
+  move |s| {
+    let res = parser(s);
+    if let Err(nom::Err::Error(_)) = res {
+      note_expected(s, what.to_string());
+    }
+    res
+  }
+}
+
+// Parses the literal `token`, surrounded by optional whitespace.
+fn symbol<'a>(token: &'static str) -> impl FnMut(&'a str) -> IResult<&'a str, &'a str> {
+  move |s| {
+    let res = preceded(multispace0, terminated(tag(token), multispace0))(s);
+    if let Err(nom::Err::Error(_)) = res {
+      note_expected(s, format!("`{}`", token));
+    }
+    res
+  }
+}
+
+// Parses the longest known binary operator at the start of the input, returning its symbol and
+// precedence.
+fn parse_binop_symbol(s: &str) -> IResult<&str, (String, u32)> {
+  let (rest, _) = multispace0(s)?;
+
+  let found = STATE.with(|state| {
+    state.borrow().operators.precedence.iter()
+      .filter(|(symbol, _)| rest.starts_with(symbol.as_str()))
+      .max_by_key(|(symbol, _)| symbol.len())
+      .map(|(symbol, precedence)| (symbol.clone(), *precedence))
+  });
+
+  match found {
+    Some((symbol, precedence)) => {
+      let (rest, _) = multispace0(&rest[symbol.len()..])?;
+      Ok((rest, (symbol, precedence)))
+    },
+    None => Err(nom::Err::Error(nom::error::Error::new(s, nom::error::ErrorKind::OneOf)))
+  }
+}
+
+fn mk_bin_op(symbol: &str, lhs: Expr, rhs: Expr) -> Expr {
+  let span = lhs.span.to(rhs.span);
+
+  match builtin_binop(symbol) {
+    Some(op) => Expr::new(ExprKind::BinOp(op, Box::new(lhs), Box::new(rhs)), span),
+    None => {
       let mut fn_name: String = "binary".to_owned();
-      fn_name.push_str(&custom_bin_op.to_string());
+      fn_name.push_str(symbol);
+
+      Expr::new(ExprKind::Call(fn_name, vec![lhs, rhs]), span)
+    }
+  }
+}
+
+// Precedence climbing: folds every operator binding at least as tightly as `min_precedence` into
+// `lhs`. Operators of equal precedence associate to the left, except `=`, so that `a = b = 2`
+// assigns 2 to `b`, then to `a`.
+fn parse_bin_op_rhs(s: &str, min_precedence: u32, lhs: Expr) -> IResult<&str, Expr> {
+  let mut s = s;
+  let mut lhs = lhs;
 
-      let mut args = Vec::new();
-      args.push(acc);
-      args.push(val);
+  loop {
+    let (after_op, (symbol, precedence)) = match parse_binop_symbol(s) {
+      Ok((after_op, (symbol, precedence))) if precedence >= min_precedence => (after_op, (symbol, precedence)),
+      _ => return Ok((s, lhs))
+    };
 
-      Expr::Call(fn_name, args)
+    let (after_rhs, mut rhs) = parse_term(after_op)?;
+    s = after_rhs;
+
+    // If the next operator binds more tightly, or as tightly after `=`, it takes `rhs` as its own
+    // left operand first
+    loop {
+      match parse_binop_symbol(s) {
+        Ok((_, (_, next_precedence))) if next_precedence > precedence => {
+          let (after_rhs, new_rhs) = parse_bin_op_rhs(s, precedence + 1, rhs)?;
+          s = after_rhs;
+          rhs = new_rhs;
+        },
+        Ok((_, (_, next_precedence))) if next_precedence == precedence && symbol == "=" => {
+          let (after_rhs, new_rhs) = parse_bin_op_rhs(s, precedence, rhs)?;
+          s = after_rhs;
+          rhs = new_rhs;
+        },
+        _ => break
+      }
     }
-  )(s)
+
+    lhs = mk_bin_op(&symbol, lhs, rhs);
+  }
+}
+
+fn parse_bin_op(s: &str) -> IResult<&str, Expr> {
+  let (s, lhs) = parse_term(s)?;
+  parse_bin_op_rhs(s, 0, lhs)
 }
 
 fn parse_float(s: &str) -> IResult<&str, Expr> {
-  let (s, num) = streaming::double(s)?;
+  let (rest, num) = preceded(multispace0, double)(s)?;
 
-  Ok((s, Expr::Float(num)))
+  Ok((rest, Expr::new(ExprKind::Float(num), span_between(s, rest))))
+}
+
+// Parses a string literal in double quotes, in which `\n`, `\t`, `\\` and `\"` stand for a newline, a
+// tab, a backslash and a quote.
+fn parse_string(input: &str) -> IResult<&str, Expr> {
+  let (s, _) = preceded(multispace0, tag("\""))(input)?;
+  let mut value = String::new();
+  let mut chars = s.char_indices();
+
+  while let Some((i, c)) = chars.next() {
+    match c {
+      '"' => {
+        let rest = &s[i + 1..];
+        return Ok((rest, Expr::new(ExprKind::Str(value), span_between(input, rest))));
+      },
+      '\\' => match chars.next() {
+        Some((_, 'n')) => value.push('\n'),
+        Some((_, 't')) => value.push('\t'),
+        Some((_, '\\')) => value.push('\\'),
+        Some((_, '"')) => value.push('"'),
+        _ => {
+          let at = &s[i..];
+          note_expected(at, "escape sequence".to_string());
+          return Err(nom::Err::Error(nom::error::Error::new(at, nom::error::ErrorKind::Escaped)));
+        }
+      },
+      _ => value.push(c)
+    }
+  }
+
+  // unterminated, so the closing quote is expected at the end of the input
+  let end = &s[s.len()..];
+  note_expected(end, "`\"`".to_string());
+  Err(nom::Err::Error(nom::error::Error::new(end, nom::error::ErrorKind::Tag)))
 }
 
 fn parse_ident(s: &str) -> IResult<&str, String> {
@@ -120,114 +408,332 @@ fn parse_ident(s: &str) -> IResult<&str, String> {
 }
 
 fn parse_var(s: &str) -> IResult<&str, Expr> {
-  let (s, ident) = parse_ident(s)?;
-  Ok((s, Expr::Var(ident)))
+  let (rest, ident) = preceded(multispace0, parse_ident)(s)?;
+  Ok((rest, Expr::new(ExprKind::Var(ident), span_between(s, rest))))
 }
 
-fn parse_call(s: &str) -> IResult<&str, Expr> {
-  let (s, ident) = parse_ident(s)?;
-  let (s, _) = preceded(multispace0, terminated(tag("("), multispace0))(s)?;
-  let (s, expr_list) = separated_list0(preceded(multispace0, terminated(tag(","), multispace0)), parse_inner_expr)(s)?;
-  let (s, _) = preceded(multispace0, terminated(tag(")"), multispace0))(s)?;
-  Ok((s, Expr::Call(ident, expr_list)))
+fn parse_call(input: &str) -> IResult<&str, Expr> {
+  let (s, ident) = preceded(multispace0, parse_ident)(input)?;
+  let (s, _) = symbol("(")(s)?;
+  let (s, expr_list) = separated_list0(symbol(","), parse_inner_expr)(s)?;
+  let (s, _) = symbol(")")(s)?;
+  Ok((s, Expr::new(ExprKind::Call(ident, expr_list), span_between(input, s))))
+}
+
+// Parses the symbol of a `def binary<op>`, which can't be one of the built-in operators.
+fn parse_custom_binop(s: &str) -> IResult<&str, char> {
+  let reserved_symbols = "_abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789{}[]();,\"\\";
+  let (rest, op) = expect("operator", preceded(multispace0, terminated(none_of(reserved_symbols), multispace0)))(s)?;
+  if builtin_binop(&op.to_string()).is_some() {
+    note_expected(s, "an operator that isn't built in".to_string());
+    return Err(nom::Err::Error(nom::error::Error::new(s, nom::error::ErrorKind::Verify)));
+  }
+  Ok((rest, op))
 }
 
-fn parse_fn_def(s: &str) -> IResult<&str, Expr> {
-  let reserved_symbols = "_abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789{}();,";
+fn parse_fn_def(input: &str) -> IResult<&str, Expr> {
+  let reserved_symbols = "_abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789{}[]();,\"\\";
 
-  let (s, _) = preceded(multispace0, terminated(tag("def "), multispace0))(s)?;
-  let (s, name) = parse_ident(s)?;
-  let (s, symbol) = if name == "binary" {
+  let (s, _) = preceded(multispace0, terminated(tag("def "), multispace0))(input)?;
+  let (s, name) = expect("function name", parse_ident)(s)?;
+  let (s, fn_name, unary_symbol) = if name == "binary" {
     // Fetch the next non-whitespace symbol
-    let mut symbol = name.to_owned();
-    let (s, binary_symbol) = preceded(multispace0, terminated(none_of(reserved_symbols), multispace0))(s)?;
-    symbol.push_str(&binary_symbol.to_string());
-    // This is the precedence of the symbol, which we're just ignoring for now.
-    let (s, _) = preceded(multispace0, terminated(digit1, multispace0))(s)?;
-    (s, symbol)
+    let mut fn_name = name.to_owned();
+    let (s, binary_symbol) = parse_custom_binop(s)?;
+    fn_name.push(binary_symbol);
+    let (s, precedence) = opt(preceded(multispace0, terminated(map_res(digit1, |digits: &str| digits.parse::<u32>()), multispace0)))(s)?;
+    register_binop(&binary_symbol.to_string(), precedence.unwrap_or(DEFAULT_BINOP_PRECEDENCE));
+    (s, fn_name, None)
   } else if name == "unary" {
     // Fetch the next non-whitespace symbol
-    let mut symbol = name.to_owned();
-    let (s, unary_symbol) = preceded(multispace0, terminated(none_of(reserved_symbols), multispace0))(s)?;
-    symbol.push_str(&unary_symbol.to_string());
-    (s, symbol)
+    let mut fn_name = name.to_owned();
+    let (s, unary_symbol) = expect("operator", preceded(multispace0, terminated(none_of(reserved_symbols), multispace0)))(s)?;
+    fn_name.push(unary_symbol);
+    (s, fn_name, Some(unary_symbol))
   } else {
-    (s, name.to_owned())
+    (s, name.to_owned(), None)
   };
-  let (s, _) = preceded(multispace0, terminated(is_a("("), multispace0))(s)?;
-  let (s, ident_list) = separated_list0(multispace1, parse_ident)(s)?;
-  let (s, _) = preceded(multispace0, terminated(is_a(")"), multispace0))(s)?;
+  let (s, (ident_list, annotations)) = parse_params(s)?;
 
   // The body of the function is comprised of a single expression
   let (s, body) = parse_inner_expr(s)?;
 
-  Ok((s, Expr::Function(symbol, ident_list, Box::new(body))))
+  // Unlike a binary operator, a unary one is only used once its definition is parsed, so that
+  // `def unary-` can still use the built-in `-` it replaces
+  if let Some(unary_symbol) = unary_symbol {
+    register_unary_op(unary_symbol);
+  }
+
+  Ok((s, Expr::new(ExprKind::Function(fn_name, ident_list, annotations, Box::new(body)), span_between(input, s))))
 }
 
-fn parse_if_stmt(s: &str) -> IResult<&str, Expr> {
-  let (s, _) = preceded(multispace0, terminated(tag("if "), multispace0))(s)?;
-  let (s, condition) = parse_bin_op1(s)?;
-  let (s, _) = preceded(multispace0, terminated(tag("then"), multispace0))(s)?;
-  let (s, if_body) = parse_bin_op1(s)?;
-  let (s, _) = preceded(multispace0, terminated(tag("else"), multispace0))(s)?;
+// Parses the parenthesized parameters of a definition or `extern`, separated by whitespace or
+// commas, then its return type if given. Each parameter may be followed by its type, as in
+// `(x: number, y) -> str`.
+fn parse_params(s: &str) -> IResult<&str, (Vec<Name>, Annotations)> {
+  let (s, _) = symbol("(")(s)?;
+  let (s, params) = many0(terminated(
+    |s| {
+      let (s, name) = preceded(multispace0, parse_ident)(s)?;
+      let (s, ty) = opt(preceded(symbol(":"), parse_type))(s)?;
+      Ok((s, (name, ty)))
+    },
+    opt(symbol(","))
+  ))(s)?;
+  let (s, _) = symbol(")")(s)?;
+  let (s, ret) = opt(preceded(symbol("->"), parse_type))(s)?;
+
+  let (names, types): (Vec<Name>, Vec<Option<TypeExpr>>) = params.into_iter().unzip();
+  let annotations = Annotations {
+    params: if types.iter().any(Option::is_some) { types } else { Vec::new() },
+    ret
+  };
+
+  Ok((s, (names, annotations)))
+}
+
+// Parses a type: `[T]` for arrays, `(T, U) -> R` for functions, and otherwise a name, followed by
+// type arguments in angle brackets if it is a struct's.
+fn parse_type(s: &str) -> IResult<&str, TypeExpr> {
+  let (s, _) = multispace0(s)?;
+
+  let array = |s| {
+    let (s, _) = symbol("[")(s)?;
+    let (s, element) = parse_type(s)?;
+    let (s, _) = symbol("]")(s)?;
+    Ok((s, TypeExpr::Array(Box::new(element))))
+  };
+  let function = |s| {
+    let (s, _) = symbol("(")(s)?;
+    let (s, params) = separated_list0(symbol(","), parse_type)(s)?;
+    let (s, _) = symbol(")")(s)?;
+    let (s, _) = symbol("->")(s)?;
+    let (s, ret) = parse_type(s)?;
+    Ok((s, TypeExpr::Fn(params, Box::new(ret))))
+  };
+  let named = |s| {
+    let (s, name) = parse_ident(s)?;
+    let (s, args) = opt(|s| {
+      let (s, _) = symbol("<")(s)?;
+      let (s, args) = separated_list1(symbol(","), parse_type)(s)?;
+      let (s, _) = symbol(">")(s)?;
+      Ok((s, args))
+    })(s)?;
+    Ok((s, TypeExpr::Named(name, args.unwrap_or_default())))
+  };
+
+  let (s, ty) = expect("type", alt((array, function, named)))(s)?;
+  let (s, _) = multispace0(s)?;
+
+  Ok((s, ty))
+}
+
+fn parse_if_stmt(input: &str) -> IResult<&str, Expr> {
+  let (s, _) = preceded(multispace0, terminated(tag("if "), multispace0))(input)?;
+  let (s, condition) = parse_bin_op(s)?;
+  let (s, _) = symbol("then")(s)?;
+  let (s, if_body) = parse_bin_op(s)?;
+  let (s, _) = symbol("else")(s)?;
   let (s, else_body) = parse_inner_expr(s)?;
 
-  Ok((s, Expr::IfExpr(Box::new(condition), Box::new(if_body), Box::new(else_body))))
+  Ok((s, Expr::new(ExprKind::IfExpr(Box::new(condition), Box::new(if_body), Box::new(else_body)), span_between(input, s))))
 }
 
-fn parse_for_in_stmt(s: &str) -> IResult<&str, Expr> {
-  let (s, _) = preceded(multispace0, terminated(tag("for "), multispace0))(s)?;
-  let (s, bound_varname) = parse_ident(s)?;
-  let (s, _) = preceded(multispace0, terminated(tag("="), multispace0))(s)?;
+fn parse_for_in_stmt(input: &str) -> IResult<&str, Expr> {
+  let (s, _) = preceded(multispace0, terminated(tag("for "), multispace0))(input)?;
+  let (s, bound_varname) = expect("identifier", parse_ident)(s)?;
+  let (s, _) = symbol("=")(s)?;
   let (s, initial) = parse_inner_expr(s)?;
-  let (s, _) = preceded(multispace0, terminated(tag(","), multispace0))(s)?;
+  let (s, _) = symbol(",")(s)?;
   let (s, condition) = parse_inner_expr(s)?;
-  let (s, _) = preceded(multispace0, terminated(tag(","), multispace0))(s)?;
-  let (s, step) = parse_inner_expr(s)?;
-  let (s, _) = preceded(multispace0, terminated(tag("in"), multispace0))(s)?;
+  let (s, step) = opt(preceded(symbol(","), parse_inner_expr))(s)?;
+  let (s, _) = symbol("in")(s)?;
+  let (s, body) = parse_inner_expr(s)?;
+
+  Ok((s, Expr::new(ExprKind::ForInExpr(bound_varname, Box::new(initial), Box::new(condition), step.map(Box::new), Box::new(body)), span_between(input, s))))
+}
+
+fn parse_while_stmt(input: &str) -> IResult<&str, Expr> {
+  let (s, _) = preceded(multispace0, terminated(tag("while "), multispace0))(input)?;
+  let (s, condition) = parse_inner_expr(s)?;
+  let (s, _) = symbol("do")(s)?;
+  let (s, body) = parse_inner_expr(s)?;
+
+  Ok((s, Expr::new(ExprKind::While(Box::new(condition), Box::new(body)), span_between(input, s))))
+}
+
+// Parses a single `name` or `name = initializer` binding of a `var ... in` expression.
+fn parse_var_binding(s: &str) -> IResult<&str, (Name, Option<Expr>)> {
+  let (s, name) = preceded(multispace0, expect("identifier", parse_ident))(s)?;
+  let (s, initializer) = opt(preceded(symbol("="), parse_inner_expr))(s)?;
+
+  Ok((s, (name, initializer)))
+}
+
+fn parse_var_in_stmt(input: &str) -> IResult<&str, Expr> {
+  let (s, _) = preceded(multispace0, terminated(tag("var "), multispace0))(input)?;
+  let (s, bindings) = separated_list1(symbol(","), parse_var_binding)(s)?;
+  let (s, _) = symbol("in")(s)?;
   let (s, body) = parse_inner_expr(s)?;
 
-  Ok((s, Expr::ForInExpr(bound_varname, Box::new(initial), Box::new(condition), Box::new(step), Box::new(body))))
+  Ok((s, Expr::new(ExprKind::VarIn(bindings, Box::new(body)), span_between(input, s))))
+}
+
+fn parse_extern_decl(input: &str) -> IResult<&str, Expr> {
+  let (s, _) = preceded(multispace0, terminated(tag("extern "), multispace0))(input)?;
+  let (s, name) = expect("function name", parse_ident)(s)?;
+  let (s, (ident_list, annotations)) = parse_params(s)?;
+
+  Ok((s, Expr::new(ExprKind::Extern(name, ident_list, annotations), span_between(input, s))))
 }
 
-fn parse_extern_decl(s: &str) -> IResult<&str, Expr> {
-  let (s, _) = preceded(multispace0, terminated(tag("extern "), multispace0))(s)?;
-  let (s, name) = parse_ident(s)?;
-  let (s, _) = preceded(multispace0, terminated(is_a("("), multispace0))(s)?;
-  let (s, ident_list) = separated_list0(tag(" "), parse_ident)(s)?;
-  let (s, _) = preceded(multispace0, terminated(is_a(")"), multispace0))(s)?;
+// Parses `struct Name { field1, field2, ... }`.
+fn parse_struct_decl(input: &str) -> IResult<&str, Expr> {
+  let (s, _) = preceded(multispace0, terminated(tag("struct "), multispace0))(input)?;
+  let (s, name) = expect("struct name", parse_ident)(s)?;
+  let (s, _) = symbol("{")(s)?;
+  let (s, fields) = separated_list0(symbol(","), expect("field name", parse_ident))(s)?;
+  let (s, _) = symbol("}")(s)?;
 
-  Ok((s, Expr::Extern(name, ident_list)))
+  Ok((s, Expr::new(ExprKind::Struct(name, fields), span_between(input, s))))
 }
 
-fn parse_unary_operation(s: &str) -> IResult<&str, Expr> {
-  let reserved_symbols = "_abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789{}();,";
-  let (s, unary_symbol) = preceded(multispace0, terminated(none_of(reserved_symbols), multispace0))(s)?;
+fn parse_unary_operation(input: &str) -> IResult<&str, Expr> {
+  let reserved_symbols = "_abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789{}[]();,\"\\";
+  let (s, unary_symbol) = preceded(multispace0, terminated(none_of(reserved_symbols), multispace0))(input)?;
   let (s, term) = parse_term(s)?;
 
-  let mut fn_name: String = "unary".to_owned();
-  fn_name.push_str(&unary_symbol.to_string());
+  if let Some(op) = builtin_unary_op(unary_symbol).filter(|_| !is_defined_unary_op(unary_symbol)) {
+    return Ok((s, Expr::new(ExprKind::UnaryOp(op, Box::new(term)), span_between(input, s))));
+  }
 
-  let mut args = Vec::new();
-  args.push(term);
+  let mut fn_name: String = "unary".to_owned();
+  fn_name.push(unary_symbol);
 
-  Ok((s, Expr::Call(fn_name, args)))
+  Ok((s, Expr::new(ExprKind::Call(fn_name, vec![term]), span_between(input, s))))
 }
 
 fn parse_term(s: &str) -> IResult<&str, Expr> {
-  return alt((parse_call, parse_float, parse_var, parse_parenthetical_term, parse_unary_operation))(s);
+  // identifiers come before numbers, since nom's `double` also reads `inf` and `nan`, and would
+  // take them from the start of `info` or `nanos`
+  let (s, term) = expect("expression", alt((
+    parse_builtin_call, parse_call, parse_construct, parse_var, parse_float, parse_string, parse_parenthetical_term,
+    parse_block, parse_array, parse_lambda, parse_unary_operation
+  )))(s)?;
+
+  parse_postfix(s, term)
+}
+
+// Parses any number of `[index]`, `.field` and `(args)` following `term`, as in `a[i][j]`,
+// `a[i].re` or `f(x)(y)`.
+fn parse_postfix(s: &str, term: Expr) -> IResult<&str, Expr> {
+  let mut s = s;
+  let mut term = term;
+
+  loop {
+    // none of `[`, `.` and `(` is noted as expected, since they may follow any term
+    if let Ok((after, _)) = preceded(multispace0, tag::<_, _, nom::error::Error<&str>>("["))(s) {
+      let (after, index) = parse_inner_expr(after)?;
+      let (after, _) = symbol("]")(after)?;
+
+      let span = term.span.to(span_between(s, after));
+      term = Expr::new(ExprKind::Index(Box::new(term), Box::new(index)), span);
+      s = after;
+    } else if let Ok((after, field)) = preceded(preceded(multispace0, tag(".")), preceded(multispace0, parse_ident))(s) {
+      let span = term.span.to(span_between(s, after));
+      term = Expr::new(ExprKind::Field(Box::new(term), field), span);
+      s = after;
+    } else if let Ok((after, _)) = preceded(multispace0, tag::<_, _, nom::error::Error<&str>>("("))(s) {
+      let (after, args) = separated_list0(symbol(","), parse_inner_expr)(after)?;
+      let (after, _) = symbol(")")(after)?;
+
+      let span = term.span.to(span_between(s, after));
+      term = Expr::new(ExprKind::Apply(Box::new(term), args), span);
+      s = after;
+    } else {
+      return Ok((s, term));
+    }
+  }
+}
+
+// Parses a struct constructor, `Name { field1: e1, field2: e2, ... }`, giving each field a value.
+fn parse_construct(input: &str) -> IResult<&str, Expr> {
+  let (s, name) = preceded(multispace0, parse_ident)(input)?;
+
+  // only a `{` followed by a field or a `}` starts a constructor, and it isn't noted as expected
+  // after every identifier
+  let (s, _) = preceded(multispace0, tag("{"))(s)?;
+  peek(preceded(multispace0, alt((tag("}"), recognize(preceded(parse_ident, preceded(multispace0, tag(":"))))))))(s)?;
+
+  let (s, fields) = separated_list0(symbol(","), parse_field_init)(s)?;
+  let (s, _) = symbol("}")(s)?;
+
+  Ok((s, Expr::new(ExprKind::Construct(name, fields), span_between(input, s))))
+}
+
+// Parses the `field: value` of a struct constructor.
+fn parse_field_init(s: &str) -> IResult<&str, (Name, Expr)> {
+  let (s, field) = preceded(multispace0, expect("field name", parse_ident))(s)?;
+  let (s, _) = symbol(":")(s)?;
+  let (s, value) = parse_inner_expr(s)?;
+
+  Ok((s, (field, value)))
+}
+
+// Parses `alloc(n)` or `len(a)`, which look like calls but are built into the language.
+fn parse_builtin_call(input: &str) -> IResult<&str, Expr> {
+  let (s, name) = preceded(multispace0, parse_ident)(input)?;
+  if name != "alloc" && name != "len" {
+    return Err(nom::Err::Error(nom::error::Error::new(input, nom::error::ErrorKind::Tag)));
+  }
+
+  let (s, _) = symbol("(")(s)?;
+  let (s, arg) = parse_inner_expr(s)?;
+  let (s, _) = symbol(")")(s)?;
+
+  let kind = if name == "alloc" { ExprKind::Alloc(Box::new(arg)) } else { ExprKind::Len(Box::new(arg)) };
+  Ok((s, Expr::new(kind, span_between(input, s))))
+}
+
+// Parses a lambda, `\x y -> body`, whose body extends as far as it can, like a function's.
+fn parse_lambda(input: &str) -> IResult<&str, Expr> {
+  let (s, _) = preceded(multispace0, terminated(tag("\\"), multispace0))(input)?;
+  let (s, params) = separated_list0(multispace1, parse_ident)(s)?;
+  let (s, _) = symbol("->")(s)?;
+  let (s, body) = parse_inner_expr(s)?;
+
+  Ok((s, Expr::new(ExprKind::Lambda(params, Box::new(body)), span_between(input, s))))
+}
+
+// Parses an array literal, `[e1, e2, ..., en]`.
+fn parse_array(input: &str) -> IResult<&str, Expr> {
+  let (s, _) = preceded(multispace0, terminated(tag("["), multispace0))(input)?;
+  let (s, elements) = separated_list0(symbol(","), parse_inner_expr)(s)?;
+  let (s, _) = symbol("]")(s)?;
+
+  Ok((s, Expr::new(ExprKind::Array(elements), span_between(input, s))))
+}
+
+// Parses `{ e1; e2; ...; en }`, with at least one expression and an optional trailing `;`.
+fn parse_block(input: &str) -> IResult<&str, Expr> {
+  let (s, _) = preceded(multispace0, terminated(tag("{"), multispace0))(input)?;
+  let (s, exprs) = separated_list1(symbol(";"), parse_inner_expr)(s)?;
+  let (s, _) = opt(symbol(";"))(s)?;
+  let (s, _) = symbol("}")(s)?;
+
+  Ok((s, Expr::new(ExprKind::Block(exprs), span_between(input, s))))
 }
 
 fn parse_parenthetical_term(s: &str) -> IResult<&str, Expr> {
-  let (s, _) = preceded(multispace0, terminated(is_a("("), multispace0))(s)?;
+  let (s, _) = preceded(multispace0, terminated(tag("("), multispace0))(s)?;
   let (s, res) = parse_inner_expr(s)?;
-  let (s, _) = preceded(multispace0, terminated(is_a(")"), multispace0))(s)?;
+  let (s, _) = symbol(")")(s)?;
   Ok((s, res))
 }
 
 fn comment(s: &str) -> IResult<&str, ()> {
   let (s, _) = tag("#")(s)?;
-  let (s, _) = take_until("\n")(s)?;
+  let (s, _) = take_while(|c| c != '\n')(s)?;
   let (s, _) = multispace0(s)?;
 
   Ok((s, ()))
@@ -242,25 +748,548 @@ fn multicomment0(s: &str) -> IResult<&str, ()> {
 }
 
 fn parse_inner_expr(s: &str) -> IResult<&str, Expr> {
-  alt((parse_if_stmt, parse_for_in_stmt, parse_bin_op1))(s)
+  alt((parse_if_stmt, parse_for_in_stmt, parse_while_stmt, parse_var_in_stmt, parse_bin_op))(s)
 }
 
 fn parse_outer_expr(s: &str) -> IResult<&str, Expr> {
-  return preceded(multicomment0, terminated(alt((parse_extern_decl, parse_fn_def, parse_inner_expr)), multicomment0))(s);
+  return preceded(multicomment0, terminated(alt((parse_extern_decl, parse_struct_decl, parse_fn_def, parse_inner_expr)), multicomment0))(s);
 }
 
-fn parse_program_partial(s: &str) -> IResult<&str, Program> {
-  let (s, program) = separated_list1(preceded(multicomment0, terminated(preceded(multispace0, terminated(tag(";"), multispace0)), multicomment0)), parse_outer_expr)(s)?;
+// Skips whitespace, comments and stray `;` between top-level items.
+fn skip_separators(s: &str) -> &str {
+  let mut s = s;
 
-  // Consume trailing semicolon if any
-  let (s, _) = take_while(|c| c == ';')(s)?;
+  loop {
+    let rest = s.trim_start().trim_start_matches(';');
+    let rest = multicomment0(rest).map(|(rest, _)| rest).unwrap_or(rest);
 
-  // Consume trailing whitespace
-  let (s, _) = multispace0(s)?;
+    if rest.len() == s.len() {
+      return s;
+    }
+    s = rest;
+  }
+}
+
+// Finds where parsing can resume after a syntax error: just past the next `;` from byte `from`,
+// or at the next `def`, `extern` or `struct` keyword, whichever comes first.
+fn resynchronise(source: &str, from: usize) -> usize {
+  let is_ident_char = |c: char| c == '_' || c.is_ascii_alphanumeric();
+  let mut in_comment = false;
+  let mut in_string = false;
+  let mut escaped = false;
+
+  for (i, c) in source[from..].char_indices() {
+    let at = from + i;
+
+    if in_comment {
+      in_comment = c != '\n';
+      continue;
+    }
+    if in_string {
+      in_string = escaped || c != '"';
+      escaped = !escaped && c == '\\';
+      continue;
+    }
 
-  Ok((s, program))
+    match c {
+      '#' => in_comment = true,
+      '"' => in_string = true,
+      ';' => return at + 1,
+      _ => {
+        let starts_word = !source[..at].chars().next_back().is_some_and(is_ident_char);
+        let rest = &source[at..];
+        if starts_word && (rest.starts_with("def ") || rest.starts_with("extern ") || rest.starts_with("struct ")) {
+          return at;
+        }
+      }
+    }
+  }
+
+  source.len()
+}
+
+// Parses every top-level item of `s`. After a syntax error, parsing resynchronises at the next `;`,
+// `def`, `extern` or `struct` and carries on, so a single pass reports every error. Returns the
+// items that parsed successfully along with all of the errors.
+pub fn parse_program_recovering(s: &str) -> (Program, Vec<ParseError>) {
+  with_operators(&mut Operators::default(), || parse_items(s))
+}
+
+fn parse_items(s: &str) -> (Program, Vec<ParseError>) {
+  begin(s);
+
+  let mut program = Vec::new();
+  let mut errors = Vec::new();
+  let mut rest = skip_separators(s);
+
+  while !rest.is_empty() {
+    let item_start = offset(rest);
+    let known = known_operators();
+    clear_expected();
+
+    // Each item must be followed by a `;` or the end of the input
+    let parsed = match parse_outer_expr(rest) {
+      Ok((after, expr)) => match preceded(multispace0, alt((symbol(";"), eof)))(after) {
+        Ok((after, _)) => Ok((after, expr)),
+        Err(_) => Err(after)
+      },
+      Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => Err(e.input),
+      Err(nom::Err::Incomplete(_)) => Err(&rest[rest.len()..])
+    };
+
+    match parsed {
+      Ok((after, expr)) => {
+        program.push(expr);
+        rest = after;
+      },
+      Err(stopped_at) => {
+        let error = furthest_error(s, stopped_at);
+
+        // A `def binary` that failed to parse defines no operator, even though its body could use it
+        restore_operators(known);
+
+        // Always move past the start of the failed item, so that we make progress
+        let min_resume = item_start + rest.chars().next().map_or(1, char::len_utf8);
+        rest = &s[resynchronise(s, error.span.start.max(min_resume))..];
+
+        errors.push(error);
+      }
+    }
+
+    rest = skip_separators(rest);
+  }
+
+  (program, errors)
+}
+
+pub fn parse_program(s: &str) -> Result<Program, Vec<ParseError>> {
+  let (program, errors) = parse_program_recovering(s);
+
+  if errors.is_empty() {
+    Ok(program)
+  } else {
+    Err(errors)
+  }
+}
+
+// Parses `s` as a continuation of earlier input, knowing the operators in `operators` and adding
+// those `s` defines. Used by the REPL and `Engine`, where every entry is parsed on its own.
+pub fn parse_program_continued(s: &str, operators: &mut Operators) -> Result<Program, Vec<ParseError>> {
+  // input with errors is rejected as a whole, and so are the operators it defines
+  let mut defined = operators.clone();
+  let (program, errors) = with_operators(&mut defined, || parse_items(s));
+
+  if errors.is_empty() {
+    *operators = defined;
+    Ok(program)
+  } else {
+    Err(errors)
+  }
+}
+
+// Whether `s` only fails to parse because it stops too early, like an unclosed call or a `def`
+// still missing its body, so that an interactive prompt should ask for another line. Operators
+// defined by `s` are not added to `operators`.
+pub fn is_incomplete(s: &str, operators: &Operators) -> bool {
+  let (_, errors) = with_operators(&mut operators.clone(), || parse_items(s));
+
+  errors.last().is_some_and(|err| err.span.start >= s.trim_end().len())
+}
+
+// Builds the error for a failed parse of `source`, reported at whichever is further along: the
+// furthest failure any parser noted, or the input `rest` the parse stopped at.
+fn furthest_error(source: &str, rest: &str) -> ParseError {
+  let stopped_at = offset(rest.trim_start());
+
+  STATE.with(|state| {
+    let state = state.borrow();
+    if state.error_offset >= stopped_at {
+      ParseError::new(source, state.error_offset, state.expected.clone())
+    } else {
+      ParseError::new(source, stopped_at, Vec::new())
+    }
+  })
 }
 
-pub fn parse_program(s: &str) -> IResult<&str, Program> {
-  return nom::combinator::all_consuming(parse_program_partial)(s);
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn float(nb: f64) -> Expr {
+    ExprKind::Float(nb).into()
+  }
+
+  fn var(name: &str) -> Expr {
+    ExprKind::Var(name.to_string()).into()
+  }
+
+  fn bin_op(op: Op, lhs: Expr, rhs: Expr) -> Expr {
+    ExprKind::BinOp(op, Box::new(lhs), Box::new(rhs)).into()
+  }
+
+  fn call(name: &str, args: Vec<Expr>) -> Expr {
+    ExprKind::Call(name.to_string(), args).into()
+  }
+
+  #[test]
+  fn parse_expr_test() {
+    // Parse basic numbers and var references
+    assert_eq!(parse_program("1.1"), Ok(vec![float(1.1)]));
+    assert_eq!(parse_program("2.7"), Ok(vec![float(2.7)]));
+    assert_eq!(parse_program("hello"), Ok(vec![var("hello")]));
+    assert_eq!(parse_program("foobar"), Ok(vec![var("foobar")]));
+    assert_eq!(parse_program("info + Inf * nanos"), Ok(vec![bin_op(Op::Plus, var("info"), bin_op(Op::Multiply, var("Inf"), var("nanos")))]));
+
+    // Parse a basic "Program"
+    assert_eq!(parse_program("foobar;1.3"), Ok(vec![var("foobar"), float(1.3)]));
+
+    // Errors with basic parsing
+    assert_eq!(parse_program("1five").map_err(|errs| errs[0].column), Err(2));
+    assert_eq!(parse_program("five 1").map_err(|errs| errs[0].column), Err(6));
+
+    // Parse basic arithmetic
+    assert_eq!(parse_program("five+1.4"), Ok(vec![bin_op(Op::Plus, var("five"), float(1.4))]));
+    assert_eq!(parse_program("five + 1.4"), Ok(vec![bin_op(Op::Plus, var("five"), float(1.4))]));
+    assert_eq!(parse_program("6 * 7"), Ok(vec![bin_op(Op::Multiply, float(6.0), float(7.0))]));
+
+    // Parse arithmetic with precedence
+    assert_eq!(parse_program("5 + 6 * 7"), Ok(vec![bin_op(Op::Plus, float(5.0), bin_op(Op::Multiply, float(6.0), float(7.0)))]));
+
+    // Parse arithmetic with parenthetical
+    assert_eq!(parse_program("5 * (6 + 7)"), Ok(vec![bin_op(Op::Multiply, float(5.0), bin_op(Op::Plus, float(6.0), float(7.0)))]));
+    assert_eq!(parse_program("((5 + 6)) * 7"), Ok(vec![bin_op(Op::Multiply, bin_op(Op::Plus, float(5.0), float(6.0)), float(7.0))]));
+
+    // Parse call
+    assert_eq!(parse_program("foobar()"), Ok(vec![call("foobar", vec![])]));
+    assert_eq!(parse_program("foobar(1, 2)"), Ok(vec![call("foobar", vec![float(1.0), float(2.0)])]));
+    assert_eq!(parse_program("foobar(1, 2, 3+4)"), Ok(vec![call("foobar", vec![float(1.0), float(2.0), bin_op(Op::Plus, float(3.0), float(4.0))])]));
+    assert_eq!(parse_program("foobar(1, 2, 3+4, baz() )"), Ok(vec![call("foobar", vec![float(1.0), float(2.0), bin_op(Op::Plus, float(3.0), float(4.0)), call("baz", vec![])])]));
+
+    // Parse function definitions
+    assert_eq!(parse_program("def foobar(term1 term2 term3) baz(term1 + term2 + term3)"), Ok(
+      vec![
+        ExprKind::Function("foobar".to_string(),
+          vec!["term1".to_string(), "term2".to_string(), "term3".to_string()],
+          Annotations::default(),
+          Box::new(call("baz", vec![bin_op(Op::Plus, bin_op(Op::Plus, var("term1"), var("term2")), var("term3"))]))
+        ).into()
+      ]
+    ));
+
+    // extern
+    assert_eq!(parse_program("extern foobar(param1 param2 param3)"), Ok(vec![ExprKind::Extern("foobar".to_string(), vec!["param1".to_string(), "param2".to_string(), "param3".to_string()], Annotations::default()).into()]));
+
+    // type annotations, with parameters separated by commas or whitespace
+    let named = |name: &str| TypeExpr::Named(name.to_string(), vec![]);
+    assert_eq!(parse_program("def f(x: number, y, a : [str]) -> Pair<number, (str) -> number> x; extern g(x y: str)"), Ok(vec![
+      ExprKind::Function("f".to_string(), vec!["x".to_string(), "y".to_string(), "a".to_string()], Annotations {
+        params: vec![Some(named("number")), None, Some(TypeExpr::Array(Box::new(named("str"))))],
+        ret: Some(TypeExpr::Named("Pair".to_string(), vec![named("number"), TypeExpr::Fn(vec![named("str")], Box::new(named("number")))]))
+      }, Box::new(var("x"))).into(),
+      ExprKind::Extern("g".to_string(), vec!["x".to_string(), "y".to_string()], Annotations {
+        params: vec![None, Some(named("str"))],
+        ret: None
+      }).into()
+    ]));
+    assert_eq!(parse_program("def neg(x, y) -x"), Ok(vec![
+      ExprKind::Function("neg".to_string(), vec!["x".to_string(), "y".to_string()], Annotations::default(), Box::new(
+        ExprKind::UnaryOp(UnaryOp::Negate, Box::new(var("x"))).into()
+      )).into()
+    ]));
+    assert!(parse_program("def f(x:) x").is_err());
+    assert!(parse_program("def f(x: [number) x").is_err());
+
+    // blocks
+    assert_eq!(parse_program("extern foobar(param1 param2 param3); def foo(item1) { foobar(item1 + 2); baz(17) }"), Ok(vec![
+      ExprKind::Extern("foobar".to_string(), vec!["param1".to_string(), "param2".to_string(), "param3".to_string()], Annotations::default()).into(),
+      ExprKind::Function("foo".to_string(), vec!["item1".to_string()], Annotations::default(), Box::new(
+        ExprKind::Block(vec![call("foobar", vec![bin_op(Op::Plus, var("item1"), float(2.0))]), call("baz", vec![float(17.0)])]).into()
+      )).into()
+    ]));
+    assert_eq!(parse_program("{ x = 1; }  + {2}"), Ok(vec![
+      bin_op(Op::Plus, ExprKind::Block(vec![bin_op(Op::Assign, var("x"), float(1.0))]).into(), ExprKind::Block(vec![float(2.0)]).into())
+    ]));
+    assert!(parse_program("{}").is_err());
+    assert!(parse_program("{ 1; 2").is_err());
+  }
+
+  #[test]
+  fn parse_custom_precedence_test() {
+    let program = parse_program("def binary| 5 (l r) l; def binary& 6 (l r) r; def binary: 1 (x y) y; a < b | c & d : e * f").unwrap();
+
+    // `:` binds loosest, then `|`, then `&`, and all of them looser than the built-in operators
+    assert_eq!(program[3], call("binary:", vec![
+      call("binary|", vec![
+        bin_op(Op::LessThan, var("a"), var("b")),
+        call("binary&", vec![var("c"), var("d")])
+      ]),
+      bin_op(Op::Multiply, var("e"), var("f"))
+    ]));
+
+    // Operators of equal precedence associate to the left
+    assert_eq!(parse_program("a - b + c"), Ok(vec![
+      bin_op(Op::Plus, bin_op(Op::Minus, var("a"), var("b")), var("c"))
+    ]));
+
+    // A custom operator may bind more tightly than the built-ins; without a precedence it gets 30
+    let program = parse_program("def binary^ 50 (x y) x; def binary@ (x y) x; a * b ^ c; a + b @ c").unwrap();
+    assert_eq!(program[2], bin_op(Op::Multiply, var("a"), call("binary^", vec![var("b"), var("c")])));
+    assert_eq!(program[3], bin_op(Op::Plus, var("a"), call("binary@", vec![var("b"), var("c")])));
+
+    // Operators are only known after their definition has been parsed
+    assert!(parse_program("a | b").is_err());
+  }
+
+  #[test]
+  fn parse_logical_ops_test() {
+    let not = |operand: Expr| -> Expr { ExprKind::UnaryOp(UnaryOp::Not, Box::new(operand)).into() };
+
+    // `||` binds looser than `&&`, and both looser than comparisons
+    assert_eq!(parse_program("a < b && !c || d > e && f"), Ok(vec![
+      bin_op(Op::Or,
+        bin_op(Op::And, bin_op(Op::LessThan, var("a"), var("b")), not(var("c"))),
+        bin_op(Op::And, bin_op(Op::GreaterThan, var("d"), var("e")), var("f")))
+    ]));
+
+    // The built-in operators win over single-character custom ones sharing a prefix
+    let program = parse_program("def binary| 5 (l r) l; a || b | c").unwrap();
+    assert_eq!(program[1], call("binary|", vec![bin_op(Op::Or, var("a"), var("b")), var("c")]));
+
+    // and can't be redefined
+    let errors = parse_program("def binary% 1 (x y) x").unwrap_err();
+    assert_eq!(errors[0].expected, vec!["an operator that isn't built in".to_string()]);
+    assert_eq!(errors[0].column, 11);
+
+    // The built-in unary operators can, as in the tutorial: a definition takes their place from
+    // then on, but not in its own body
+    let program = parse_program("!a; def unary!(v) !v; def unary-(v) 0-v; !a - -b").unwrap();
+    assert_eq!(program[0], not(var("a")));
+    assert_eq!(program[1], ExprKind::Function("unary!".to_string(), vec!["v".to_string()], Default::default(), Box::new(not(var("v")))).into());
+    assert_eq!(program[3], bin_op(Op::Minus, call("unary!", vec![var("a")]), call("unary-", vec![var("b")])));
+  }
+
+  #[test]
+  fn parse_comparison_ops_test() {
+    // Equality binds looser than ordering, and both looser than arithmetic
+    assert_eq!(parse_program("a <= b == c >= d + e; a != b % c"), Ok(vec![
+      bin_op(Op::Equal,
+        bin_op(Op::LessEqual, var("a"), var("b")),
+        bin_op(Op::GreaterEqual, var("c"), bin_op(Op::Plus, var("d"), var("e")))),
+      bin_op(Op::NotEqual, var("a"), bin_op(Op::Remainder, var("b"), var("c")))
+    ]));
+
+    // `=` and `==` are told apart
+    assert_eq!(parse_program("x = y == z"), Ok(vec![
+      bin_op(Op::Assign, var("x"), bin_op(Op::Equal, var("y"), var("z")))
+    ]));
+  }
+
+  #[test]
+  fn parse_spans_test() {
+    let program = parse_program("extern f(x);\n\ndef g(y)\n  f(y) + 2.5").unwrap();
+
+    assert_eq!((program[0].span.start, program[0].span.end), (0, 11));
+    assert_eq!((program[1].span.start, program[1].span.end), (14, 35));
+
+    match &program[1].kind {
+      ExprKind::Function(_, _, _, body) => {
+        assert_eq!((body.span.start, body.span.end), (25, 35));
+        match &body.kind {
+          ExprKind::BinOp(_, lhs, rhs) => {
+            assert_eq!((lhs.span.start, lhs.span.end), (25, 29));
+            assert_eq!((rhs.span.start, rhs.span.end), (32, 35));
+          },
+          kind => panic!("expected a binary operation, got {:?}", kind)
+        }
+      },
+      kind => panic!("expected a function, got {:?}", kind)
+    }
+  }
+
+  #[test]
+  fn parse_error_test() {
+    let err = parse_program("def f(x)\n  g(x 1)").unwrap_err().remove(0);
+    assert_eq!((err.line, err.column), (2, 7));
+    assert_eq!(err.expected, vec!["`(`".to_string(), "`,`".to_string(), "`)`".to_string()]);
+    assert_eq!(err.with_file("test.ks").to_string(), "test.ks:2:7: syntax error: expected `(`, `,` or `)`\n  |\n2 |   g(x 1)\n  |       ^");
+
+    let err = parse_program("def f(x) if x then 1").unwrap_err().remove(0);
+    assert_eq!((err.line, err.column), (1, 21));
+    assert_eq!(err.expected, vec!["`else`".to_string()]);
+  }
+
+  #[test]
+  fn parse_error_recovery_test() {
+    let (program, errors) = parse_program_recovering("def f(x) x +;\ndef g(y) y;\nextern h(;\ng(1) 2;\n# def in a comment\ndef k() 1 def m() 2");
+
+    // Every broken item is reported, and everything else still parses
+    assert_eq!(errors.iter().map(|e| (e.line, e.column)).collect::<Vec<_>>(), vec![(1, 13), (3, 10), (4, 6), (6, 11)]);
+    assert_eq!(program.iter().map(|expr| match &expr.kind {
+      ExprKind::Function(name, _, _, _) => name.as_str(),
+      _ => "<expr>"
+    }).collect::<Vec<_>>(), vec!["g", "m"]);
+
+    assert_eq!(parse_program("def f(x) x +; f(1)").map_err(|errs| errs.len()), Err(1));
+
+    // A broken `def binary` leaves its operator undefined
+    let (program, errors) = parse_program_recovering("def binary| 5 (l r) l |; a | b; def binary| 5 (l r) l; a | b");
+    assert_eq!(errors.iter().map(|e| e.column).collect::<Vec<_>>(), vec![24, 28]);
+    assert_eq!(program[1], call("binary|", vec![var("a"), var("b")]));
+  }
+
+  #[test]
+  fn parse_mutable_vars_test() {
+    assert_eq!(parse_program("var a = 1, b in a = b = 2"), Ok(vec![
+      ExprKind::VarIn(
+        vec![("a".to_string(), Some(float(1.0))), ("b".to_string(), None)],
+        // `=` is right-associative, unlike every other operator
+        Box::new(bin_op(Op::Assign, var("a"), bin_op(Op::Assign, var("b"), float(2.0))))
+      ).into()
+    ]));
+
+    // Assignment binds more loosely than everything except user operators of precedence 1
+    let program = parse_program("def binary: 1 (x y) y; x = x + 1 : x").unwrap();
+    assert_eq!(program[1], call("binary:", vec![bin_op(Op::Assign, var("x"), bin_op(Op::Plus, var("x"), float(1.0))), var("x")]));
+
+    // `for` still reads its own `=`
+    assert!(parse_program("for i = 1, i < 10, 1 in x = i").is_ok());
+  }
+
+  #[test]
+  fn parse_loops_test() {
+    let for_in = |step: Option<Expr>| -> Expr {
+      ExprKind::ForInExpr("i".to_string(), Box::new(float(0.0)), Box::new(bin_op(Op::LessThan, var("i"), var("n"))),
+        step.map(Box::new), Box::new(call("f", vec![var("i")]))).into()
+    };
+
+    assert_eq!(parse_program("for i = 0, i < n, 2 in f(i)"), Ok(vec![for_in(Some(float(2.0)))]));
+    assert_eq!(parse_program("for i = 0, i < n in f(i)"), Ok(vec![for_in(None)]));
+
+    assert_eq!(parse_program("while x > 0 do x = x - 1"), Ok(vec![
+      ExprKind::While(
+        Box::new(bin_op(Op::GreaterThan, var("x"), float(0.0))),
+        Box::new(bin_op(Op::Assign, var("x"), bin_op(Op::Minus, var("x"), float(1.0))))
+      ).into()
+    ]));
+    assert!(parse_program("while x > 0 x = x - 1").is_err());
+  }
+
+  #[test]
+  fn parse_strings_test() {
+    let string = |value: &str| -> Expr { ExprKind::Str(value.to_string()).into() };
+
+    assert_eq!(parse_program("printstr(\"Hello, world!\\n\")"), Ok(vec![call("printstr", vec![string("Hello, world!\n")])]));
+    assert_eq!(parse_program("f(\"a;b # c\", \"\\t\\\\\\\"\")"), Ok(vec![call("f", vec![string("a;b # c"), string("\t\\\"")])]));
+    let span = parse_program(" \"\" ").unwrap()[0].span;
+    assert_eq!((span.start, span.end), (1, 3));
+
+    assert!(parse_program("\"\\q\"").is_err());
+    assert!(is_incomplete("printstr(\"unterminated", &Operators::default()));
+
+    // Recovery skips over a `;` inside a string
+    let (program, errors) = parse_program_recovering("f(1 \"x; y\"); g()");
+    assert_eq!((program, errors.len()), (vec![call("g", vec![])], 1));
+  }
+
+  #[test]
+  fn parse_arrays_test() {
+    let index = |array: Expr, i: Expr| -> Expr { ExprKind::Index(Box::new(array), Box::new(i)).into() };
+
+    assert_eq!(parse_program("[1, x]; []"), Ok(vec![
+      ExprKind::Array(vec![float(1.0), var("x")]).into(),
+      ExprKind::Array(vec![]).into()
+    ]));
+    assert_eq!(parse_program("a[i][j + 1] = len(a) * -b[0]"), Ok(vec![
+      bin_op(Op::Assign,
+        index(index(var("a"), var("i")), bin_op(Op::Plus, var("j"), float(1.0))),
+        bin_op(Op::Multiply, ExprKind::Len(Box::new(var("a"))).into(), ExprKind::UnaryOp(UnaryOp::Negate, Box::new(index(var("b"), float(0.0)))).into()))
+    ]));
+    assert_eq!(parse_program("alloc(n)[0]; f(1)[2]"), Ok(vec![
+      index(ExprKind::Alloc(Box::new(var("n"))).into(), float(0.0)),
+      index(call("f", vec![float(1.0)]), float(2.0))
+    ]));
+
+    let span = parse_program("  a[0][1] ").unwrap()[0].span;
+    assert_eq!((span.start, span.end), (2, 9));
+    assert!(parse_program("a[0").is_err());
+  }
+
+  #[test]
+  fn parse_structs_test() {
+    let field = |object: Expr, name: &str| -> Expr { ExprKind::Field(Box::new(object), name.to_string()).into() };
+
+    assert_eq!(parse_program("struct Complex { re, im }; struct Unit {}"), Ok(vec![
+      ExprKind::Struct("Complex".to_string(), vec!["re".to_string(), "im".to_string()]).into(),
+      ExprKind::Struct("Unit".to_string(), vec![]).into()
+    ]));
+    assert_eq!(parse_program("Complex { re: x + 1, im: 0 }.re; Unit {}"), Ok(vec![
+      field(ExprKind::Construct("Complex".to_string(), vec![
+        ("re".to_string(), bin_op(Op::Plus, var("x"), float(1.0))),
+        ("im".to_string(), float(0.0))
+      ]).into(), "re"),
+      ExprKind::Construct("Unit".to_string(), vec![]).into()
+    ]));
+    assert_eq!(parse_program("a[0].re.x = p . im"), Ok(vec![
+      bin_op(Op::Assign,
+        field(field(ExprKind::Index(Box::new(var("a")), Box::new(float(0.0))).into(), "re"), "x"),
+        field(var("p"), "im"))
+    ]));
+
+    let span = parse_program(" p.re ").unwrap()[0].span;
+    assert_eq!((span.start, span.end), (1, 5));
+    assert!(parse_program("struct P { x y }").is_err());
+    assert!(parse_program("P { x: 1").is_err());
+  }
+
+  #[test]
+  fn parse_function_values_test() {
+    let apply = |callee: Expr, args: Vec<Expr>| -> Expr { ExprKind::Apply(Box::new(callee), args).into() };
+
+    assert_eq!(parse_program("integrate(fib, 0, 1); f(1)(2, g)(); fs[0](x)"), Ok(vec![
+      call("integrate", vec![var("fib"), float(0.0), float(1.0)]),
+      apply(apply(call("f", vec![float(1.0)]), vec![float(2.0), var("g")]), vec![]),
+      apply(ExprKind::Index(Box::new(var("fs")), Box::new(float(0.0))).into(), vec![var("x")])
+    ]));
+    assert_eq!(parse_program("(f)(x) + 1"), Ok(vec![bin_op(Op::Plus, apply(var("f"), vec![var("x")]), float(1.0))]));
+
+    let span = parse_program(" f(1)(2) ").unwrap()[0].span;
+    assert_eq!((span.start, span.end), (1, 8));
+    assert!(is_incomplete("f(1)(2,", &Operators::default()));
+
+    let lambda = |params: &[&str], body: Expr| -> Expr {
+      ExprKind::Lambda(params.iter().map(|param| param.to_string()).collect(), Box::new(body)).into()
+    };
+
+    assert_eq!(parse_program("map(a, \\x -> x * k); \\ -> 1; (\\ a b -> \\c -> a)(1, 2)"), Ok(vec![
+      call("map", vec![var("a"), lambda(&["x"], bin_op(Op::Multiply, var("x"), var("k")))]),
+      lambda(&[], float(1.0)),
+      apply(lambda(&["a", "b"], lambda(&["c"], var("a"))), vec![float(1.0), float(2.0)])
+    ]));
+    assert!(parse_program("\\x x").is_err());
+    assert!(parse_program("def unary\\(v) v").is_err());
+  }
+
+  #[test]
+  fn parse_incremental_test() {
+    // Unfinished items ask for more input; finished or broken ones don't
+    let mut operators = Operators::default();
+    assert!(is_incomplete("def f(x)", &operators));
+    assert!(is_incomplete("foo(1,\n  2", &operators));
+    assert!(is_incomplete("1 +\n", &operators));
+    assert!(!is_incomplete("def f(x) x", &operators));
+    assert!(!is_incomplete("foo(1 2)", &operators));
+
+    // Operators defined by earlier input stay usable, but only through the same `Operators`
+    assert!(!is_incomplete("def binary| 5 (l r) l; a | b", &operators));
+    assert!(parse_program_continued("a | b", &mut operators).is_err());
+    assert!(parse_program_continued("def binary| 5 (l r) l", &mut operators).is_ok());
+    assert_eq!(parse_program_continued("a | b", &mut operators), Ok(vec![call("binary|", vec![var("a"), var("b")])]));
+    assert!(parse_program("a | b").is_err());
+    assert!(parse_program_continued("a | b", &mut Operators::default()).is_err());
+    assert!(parse_program_continued("a | b", &mut operators).is_ok());
+
+    // Input with errors defines no operators, even in the items that parsed
+    assert!(parse_program_continued("def binary^ 5 (l r) l; 1 +", &mut operators).is_err());
+    assert!(parse_program_continued("a ^ b", &mut operators).is_err());
+  }
 }
@@ -0,0 +1,136 @@
+use std::error::Error;
+
+use inkwell::context::Context;
+use rustyline::error::ReadlineError;
+use rustyline::Editor;
+
+use kaleidoscope::ast::Program;
+use kaleidoscope::codegen::CodeGen;
+use kaleidoscope::optimize::OptConfig;
+use kaleidoscope::parser::{self, Operators};
+
+const HELP: &str = "\
+Enter definitions, `extern` declarations or expressions; expressions are evaluated right away.
+Defining a function again replaces it for everything entered afterwards.
+Input continues onto further lines until it is complete, or until an empty line.
+
+Commands:
+  :ast    print the syntax tree of the last input
+  :ir     print the LLVM IR compiled from the last input
+  :type <name>
+          print the type inferred for a function
+  :reset  forget every definition and operator
+  :help   show this message
+  :quit   leave the REPL (or press Ctrl-D)";
+
+/**
+ * Interactive read-eval-print loop. Every input is compiled into its own module and handed to the
+ * JIT, so definitions from earlier inputs stay callable.
+ */
+pub fn run(opt: OptConfig) -> Result<(), Box<dyn Error>> {
+  let context = Context::create();
+  let mut codegen = CodeGen::mk_compiler_with(&context, "repl", opt.clone())?;
+  codegen.allow_redefinition(true);
+  let mut editor = Editor::<()>::new();
+
+  let mut last_ast: Program = Vec::new();
+  let mut last_ir = String::new();
+  let mut operators = Operators::default();
+
+  while let Some(input) = read_input(&mut editor, &operators)? {
+    match input.trim() {
+      "" => {},
+      ":help" => println!("{}", HELP),
+      ":quit" | ":q" => break,
+      ":ast" => println!("{:#?}", last_ast),
+      ":ir" => print!("{}", last_ir),
+      ":reset" => {
+        codegen = CodeGen::mk_compiler_with(&context, "repl", opt.clone())?;
+        codegen.allow_redefinition(true);
+        operators = Operators::default();
+        last_ast.clear();
+        last_ir.clear();
+      },
+      command if command.starts_with(":type ") => {
+        let name = command[":type ".len()..].trim();
+        match codegen.signature(name) {
+          Some(signature) => println!("{} : {}", name, signature),
+          None => eprintln!("Unknown function `{}`", name)
+        }
+      },
+      command if command.starts_with(':') => eprintln!("Unknown command `{}`, try :help", command),
+      _ => {
+        let program = match parser::parse_program_continued(&input, &mut operators) {
+          Ok(program) => program,
+          Err(errors) => {
+            for err in errors {
+              eprintln!("{}\n", err);
+            }
+            continue;
+          }
+        };
+
+        let checked = match codegen.check_program(&program) {
+          Ok(checked) => checked,
+          Err(errors) => {
+            for err in errors {
+              eprintln!("{}\n", err.render("<repl>", &input));
+            }
+            continue;
+          }
+        };
+
+        codegen.start_module()?;
+
+        match codegen.compile_program(checked) {
+          Ok(anon_exprs) => {
+            for anon_expr in anon_exprs {
+              match codegen.jit_eval_anon_expr(&anon_expr) {
+                Ok(value) => println!("Evaluated to {}", value),
+                Err(err) => {
+                  eprintln!("{}", err);
+                  break;
+                }
+              }
+            }
+          },
+          Err(err) => eprintln!("{}", err.render("<repl>", &input))
+        }
+
+        last_ir = codegen.module.print_to_string().to_string();
+        last_ast = program;
+      }
+    }
+  }
+
+  Ok(())
+}
+
+// Reads one entry, prompting for more lines while it is an unfinished item. Ctrl-C discards the
+// entry being typed. Returns `None` at the end of input.
+fn read_input(editor: &mut Editor<()>, operators: &Operators) -> Result<Option<String>, ReadlineError> {
+  let mut input = String::new();
+
+  loop {
+    let prompt = if input.is_empty() { "ready> " } else { "  ...> " };
+
+    match editor.readline(prompt) {
+      Ok(line) => {
+        let finished = line.trim().is_empty() || !parser::is_incomplete(&(input.clone() + &line), operators);
+
+        input.push_str(&line);
+        input.push('\n');
+
+        if finished || input.trim_start().starts_with(':') {
+          if !input.trim().is_empty() {
+            editor.add_history_entry(input.trim_end());
+          }
+          return Ok(Some(input));
+        }
+      },
+      Err(ReadlineError::Interrupted) => input.clear(),
+      Err(ReadlineError::Eof) => return Ok(None),
+      Err(err) => return Err(err)
+    }
+  }
+}
@@ -0,0 +1,538 @@
+use std::collections::HashMap;
+
+use crate::ast::{Expr, ExprKind, Name, Op, Program, Span, TypeExpr};
+use crate::codegen::CompileError;
+use crate::infer;
+
+/**
+ * The structs declared so far, with their fields in order.
+ */
+#[derive(Clone, Debug, Default)]
+pub struct Structs {
+  // Latest declaration last; a struct declared again moves to the end
+  declarations: Vec<(Name, Vec<Name>)>
+}
+
+impl Structs {
+  // Declares the struct `name`, replacing any earlier declaration of it.
+  pub fn declare(&mut self, name: &str, fields: &[Name]) {
+    self.declarations.retain(|(declared, _)| declared != name);
+    self.declarations.push((name.to_owned(), fields.to_vec()));
+  }
+
+  pub fn fields(&self, name: &str) -> Option<&Vec<Name>> {
+    self.declarations.iter().find(|(declared, _)| declared == name).map(|(_, fields)| fields)
+  }
+
+  // The structs with a field named `field`, in the order they were declared.
+  pub fn declaring(&self, field: &str) -> Vec<&Name> {
+    self.declarations.iter().filter(|(_, fields)| fields.iter().any(|f| f == field)).map(|(name, _)| name).collect()
+  }
+}
+
+/**
+ * Checks a program before any code is generated for it: every called function must be declared
+ * earlier, or be the function being defined, and be given as many arguments as it has parameters.
+ * Every variable must be bound by a parameter, a `for` loop or a `var`, or name a function, and
+ * lambdas can't assign to the variables they capture. Calls through variables are checked by type
+ * inference instead. Every struct must be declared before it is constructed, with a value for each
+ * of its fields, and every field accessed must belong to some struct. Type annotations must name
+ * built-in types or declared structs.
+ *
+ * `declared` holds the arity of each function declared by earlier programs, and `structs` the
+ * structs they declared. Returns every problem found, in source order.
+ */
+pub fn check_program(program: &Program, declared: &HashMap<Name, usize>, structs: &Structs) -> Result<(), Vec<CompileError>> {
+  let mut checker = Checker {
+    functions: declared.clone(),
+    structs: structs.clone(),
+    scope: Vec::new(),
+    captured_len: 0,
+    errors: Vec::new()
+  };
+
+  for item in program {
+    match &item.kind {
+      ExprKind::Struct(name, fields) => {
+        for (i, field) in fields.iter().enumerate() {
+          if fields[..i].contains(field) {
+            checker.errors.push(CompileError::DuplicateField { field: field.to_owned(), span: item.span });
+          }
+        }
+        checker.structs.declare(name, fields);
+      },
+      ExprKind::Function(name, params, annotations, body) => {
+        for ty in annotations.types() {
+          checker.check_type(ty, false, item.span);
+        }
+
+        // declared before its body is checked, so it may call itself
+        checker.functions.insert(name.to_owned(), params.len());
+        checker.scope = params.clone();
+        checker.captured_len = 0;
+        checker.check_expr(body);
+      },
+      ExprKind::Extern(name, params, annotations) => {
+        for ty in annotations.types() {
+          checker.check_type(ty, true, item.span);
+        }
+        checker.functions.insert(name.to_owned(), params.len());
+      },
+      _ => {
+        checker.scope.clear();
+        checker.captured_len = 0;
+        checker.check_expr(item);
+      }
+    }
+  }
+
+  if checker.errors.is_empty() {
+    Ok(())
+  } else {
+    Err(checker.errors)
+  }
+}
+
+struct Checker {
+  // Arity of every function declared so far
+  functions: HashMap<Name, usize>,
+  structs: Structs,
+  // Variables in scope, innermost binding last
+  scope: Vec<Name>,
+  // How many variables at the start of `scope` are bound outside the innermost lambda being
+  // checked, which captures them
+  captured_len: usize,
+  errors: Vec<CompileError>
+}
+
+impl Checker {
+  fn check_expr(&mut self, expr: &Expr) {
+    match &expr.kind {
+      ExprKind::Float(_) | ExprKind::Str(_) => {},
+
+      // a name not bound to a variable may stand for a function
+      ExprKind::Var(name) if self.functions.contains_key(name) => {},
+      ExprKind::Var(name) => self.check_var(name, expr),
+
+      ExprKind::BinOp(Op::Assign, target, value) => {
+        match &target.kind {
+          ExprKind::Var(name) => match self.scope.iter().rposition(|bound| bound == name) {
+            Some(i) if i < self.captured_len =>
+              self.errors.push(CompileError::CapturedAssignment { name: name.to_owned(), span: target.span }),
+            Some(_) => {},
+            None => self.errors.push(CompileError::UnknownVariable { name: name.to_owned(), span: target.span })
+          },
+          ExprKind::Index(..) | ExprKind::Field(..) => self.check_expr(target),
+          _ => self.errors.push(CompileError::InvalidAssignment { span: target.span })
+        }
+        self.check_expr(value);
+      },
+
+      ExprKind::BinOp(_, left, right) => {
+        self.check_expr(left);
+        self.check_expr(right);
+      },
+
+      ExprKind::UnaryOp(_, operand) => self.check_expr(operand),
+
+      ExprKind::Call(name, args) if self.scope.contains(name) => {
+        for arg in args {
+          self.check_expr(arg);
+        }
+      },
+
+      ExprKind::Call(name, args) => {
+        match self.functions.get(name) {
+          Some(&arity) if arity != args.len() => self.errors.push(CompileError::ArityMismatch {
+            name: name.to_owned(),
+            expected: arity,
+            found: args.len(),
+            span: expr.span
+          }),
+          Some(_) => {},
+          None => self.errors.push(CompileError::UnknownFunction { name: name.to_owned(), span: expr.span })
+        }
+
+        for arg in args {
+          self.check_expr(arg);
+        }
+      },
+
+      ExprKind::Apply(callee, args) => {
+        self.check_expr(callee);
+        for arg in args {
+          self.check_expr(arg);
+        }
+      },
+
+      ExprKind::Lambda(params, body) => {
+        let outer_len = self.scope.len();
+        let outer_captured_len = std::mem::replace(&mut self.captured_len, outer_len);
+
+        self.scope.extend(params.iter().cloned());
+        self.check_expr(body);
+
+        self.scope.truncate(outer_len);
+        self.captured_len = outer_captured_len;
+      },
+
+      ExprKind::IfExpr(cond, consequence, alternative) => {
+        self.check_expr(cond);
+        self.check_expr(consequence);
+        self.check_expr(alternative);
+      },
+
+      ExprKind::ForInExpr(var_name, initial_val, end_cond, step, body) => {
+        // the loop variable is bound in everything but its initial value
+        self.check_expr(initial_val);
+
+        self.scope.push(var_name.to_owned());
+        self.check_expr(end_cond);
+        if let Some(step) = step {
+          self.check_expr(step);
+        }
+        self.check_expr(body);
+        self.scope.pop();
+      },
+
+      ExprKind::While(cond, body) => {
+        self.check_expr(cond);
+        self.check_expr(body);
+      },
+
+      ExprKind::Alloc(operand) | ExprKind::Len(operand) => self.check_expr(operand),
+
+      ExprKind::Index(array, index) => {
+        self.check_expr(array);
+        self.check_expr(index);
+      },
+
+      ExprKind::Construct(name, values) => {
+        match self.structs.fields(name).cloned() {
+          Some(fields) => {
+            for (i, (field, _)) in values.iter().enumerate() {
+              if !fields.contains(field) {
+                self.errors.push(CompileError::UnknownField { struct_name: Some(name.to_owned()), field: field.to_owned(), span: expr.span });
+              } else if values[..i].iter().any(|(given, _)| given == field) {
+                self.errors.push(CompileError::DuplicateField { field: field.to_owned(), span: expr.span });
+              }
+            }
+            for field in fields.iter().filter(|field| !values.iter().any(|(given, _)| given == *field)) {
+              self.errors.push(CompileError::MissingField { struct_name: name.to_owned(), field: field.to_owned(), span: expr.span });
+            }
+          },
+          None => self.errors.push(CompileError::UnknownStruct { name: name.to_owned(), span: expr.span })
+        }
+
+        for (_, value) in values {
+          self.check_expr(value);
+        }
+      },
+
+      ExprKind::Field(object, field) => {
+        self.check_expr(object);
+        if self.structs.declaring(field).is_empty() {
+          self.errors.push(CompileError::UnknownField { struct_name: None, field: field.to_owned(), span: expr.span });
+        }
+      },
+
+      ExprKind::Block(exprs) | ExprKind::Array(exprs) => {
+        for expr in exprs {
+          self.check_expr(expr);
+        }
+      },
+
+      ExprKind::VarIn(bindings, body) => {
+        let outer_len = self.scope.len();
+
+        // each initializer sees the bindings before it, but not its own
+        for (var_name, initializer) in bindings {
+          if let Some(init) = initializer {
+            self.check_expr(init);
+          }
+          self.scope.push(var_name.to_owned());
+        }
+
+        self.check_expr(body);
+        self.scope.truncate(outer_len);
+      },
+
+      ExprKind::Function(..) | ExprKind::Extern(..) | ExprKind::Struct(..) =>
+        self.errors.push(CompileError::NestedDefinition { span: expr.span })
+    }
+  }
+
+  // Checks that the type annotation `ty` names built-in types and declared structs, giving structs
+  // as many type arguments as they have fields. Those may be left out, making a definition generic
+  // over them, unless `complete` is required, as by an `extern`.
+  fn check_type(&mut self, ty: &TypeExpr, complete: bool, span: Span) {
+    match ty {
+      TypeExpr::Named(name, args) => {
+        let expected = match (infer::builtin_type(name), self.structs.fields(name)) {
+          (Some(_), _) => Some(0),
+          (None, Some(_)) if args.is_empty() && !complete => None,
+          (None, Some(fields)) => Some(fields.len()),
+          (None, None) => return self.errors.push(CompileError::UnknownType { name: name.to_owned(), span })
+        };
+
+        match expected {
+          Some(expected) if expected != args.len() =>
+            self.errors.push(CompileError::TypeArityMismatch { name: name.to_owned(), expected, found: args.len(), span }),
+          _ => for arg in args {
+            self.check_type(arg, complete, span);
+          }
+        }
+      },
+      TypeExpr::Array(element) => self.check_type(element, complete, span),
+      TypeExpr::Fn(params, ret) => {
+        for param in params {
+          self.check_type(param, complete, span);
+        }
+        self.check_type(ret, complete, span);
+      }
+    }
+  }
+
+  fn check_var(&mut self, name: &Name, expr: &Expr) {
+    if !self.scope.contains(name) {
+      self.errors.push(CompileError::UnknownVariable { name: name.to_owned(), span: expr.span });
+    }
+  }
+}
+
+/**
+ * The variables that a lambda with `params` and `body` uses without binding them itself, in order of
+ * first use. Those bound in the enclosing function are the ones it captures; the others name
+ * functions.
+ */
+pub fn captured_variables(params: &[Name], body: &Expr) -> Vec<Name> {
+  let mut bound = params.to_vec();
+  let mut free = Vec::new();
+
+  free_variables(body, &mut bound, &mut free);
+  free
+}
+
+// Adds the variables `expr` uses that aren't in `bound` to `free`, unless already there.
+fn free_variables(expr: &Expr, bound: &mut Vec<Name>, free: &mut Vec<Name>) {
+  let mut note = |name: &Name, bound: &Vec<Name>| {
+    if !bound.contains(name) && !free.contains(name) {
+      free.push(name.to_owned());
+    }
+  };
+
+  match &expr.kind {
+    ExprKind::Float(_) | ExprKind::Str(_) | ExprKind::Function(..) | ExprKind::Extern(..) | ExprKind::Struct(..) => {},
+
+    ExprKind::Var(name) => note(name, bound),
+
+    ExprKind::Call(name, args) => {
+      note(name, bound);
+      for arg in args {
+        free_variables(arg, bound, free);
+      }
+    },
+
+    ExprKind::BinOp(_, left, right) | ExprKind::Index(left, right) | ExprKind::While(left, right) => {
+      free_variables(left, bound, free);
+      free_variables(right, bound, free);
+    },
+
+    ExprKind::UnaryOp(_, operand) | ExprKind::Alloc(operand) | ExprKind::Len(operand) | ExprKind::Field(operand, _) =>
+      free_variables(operand, bound, free),
+
+    ExprKind::Apply(callee, args) => {
+      free_variables(callee, bound, free);
+      for arg in args {
+        free_variables(arg, bound, free);
+      }
+    },
+
+    ExprKind::Block(exprs) | ExprKind::Array(exprs) => {
+      for expr in exprs {
+        free_variables(expr, bound, free);
+      }
+    },
+
+    ExprKind::Construct(_, values) => {
+      for (_, value) in values {
+        free_variables(value, bound, free);
+      }
+    },
+
+    ExprKind::IfExpr(cond, consequence, alternative) => {
+      free_variables(cond, bound, free);
+      free_variables(consequence, bound, free);
+      free_variables(alternative, bound, free);
+    },
+
+    ExprKind::ForInExpr(var_name, initial_val, end_cond, step, body) => {
+      free_variables(initial_val, bound, free);
+
+      bound.push(var_name.to_owned());
+      free_variables(end_cond, bound, free);
+      if let Some(step) = step {
+        free_variables(step, bound, free);
+      }
+      free_variables(body, bound, free);
+      bound.pop();
+    },
+
+    ExprKind::VarIn(bindings, body) => {
+      let outer_len = bound.len();
+
+      for (var_name, initializer) in bindings {
+        if let Some(init) = initializer {
+          free_variables(init, bound, free);
+        }
+        bound.push(var_name.to_owned());
+      }
+
+      free_variables(body, bound, free);
+      bound.truncate(outer_len);
+    },
+
+    ExprKind::Lambda(params, body) => {
+      let outer_len = bound.len();
+
+      bound.extend(params.iter().cloned());
+      free_variables(body, bound, free);
+      bound.truncate(outer_len);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::ast::Span;
+  use crate::parser::parse_program;
+
+  #[test]
+  fn check_program_test() {
+    let declared: HashMap<Name, usize> = vec![("putchard".to_string(), 1)].into_iter().collect();
+    let program = parse_program("
+      def fib(x) if x < 3 then 1 else fib(x - 1) + fib(x - 2);
+      def count(n) for i = 0, i < n, 1 in var total = i in putchard(total + i);
+      def broken(x) y + fib(x, 1) + nope(x);
+      putchard(x);
+      1 = 2
+    ").unwrap();
+
+    assert_eq!(check_program(&program[..2].to_vec(), &declared, &Structs::default()), Ok(()));
+
+    let errors = check_program(&program, &declared, &Structs::default()).unwrap_err();
+    let messages: Vec<String> = errors.iter().map(|err| err.to_string()).collect();
+
+    assert_eq!(messages, vec![
+      "unknown variable `y`",
+      "`fib` takes 1 argument(s) but 2 were given",
+      "unknown function `nope`",
+      "unknown variable `x`",
+      "destination of `=` must be a variable, an array element or a field"
+    ]);
+    assert!(errors.iter().all(|err| err.span().is_some_and(|span: Span| span.end > span.start)));
+  }
+
+  #[test]
+  fn check_function_values_test() {
+    let program = parse_program("
+      def apply(f x) f(x, 1) + (f)(x);
+      def twice(x) x * 2;
+      apply(twice, 1) + apply(nope, 2);
+      twice = 1
+    ").unwrap();
+
+    let errors = check_program(&program, &HashMap::new(), &Structs::default()).unwrap_err();
+    let messages: Vec<String> = errors.iter().map(|err| err.to_string()).collect();
+
+    assert_eq!(messages, vec!["unknown variable `nope`", "unknown variable `twice`"]);
+  }
+
+  #[test]
+  fn check_lambdas_test() {
+    let program = parse_program("
+      def scale(a k) map(a, \\x -> x * k);
+      def counter(n) var c in \\step -> { c = c + step; for i = 0, i < n in \\ -> i = 1 };
+      def adder(k) \\x -> \\y -> x + y + k + z;
+      def local(k) \\x -> var y = k in y = x
+    ").unwrap();
+    let declared = vec![("map".to_string(), 2)].into_iter().collect();
+
+    let errors = check_program(&program, &declared, &Structs::default()).unwrap_err();
+    let messages: Vec<String> = errors.iter().map(|err| err.to_string()).collect();
+
+    assert_eq!(messages, vec![
+      "cannot assign to `c`, which the lambda captured by value",
+      "cannot assign to `i`, which the lambda captured by value",
+      "unknown variable `z`"
+    ]);
+
+    let lambda = parse_program("\\x -> { f(x, k); var k = x in k + y; \\y -> y + w }").unwrap();
+    match &lambda[0].kind {
+      ExprKind::Lambda(params, body) => assert_eq!(captured_variables(params, body), vec!["f", "k", "y", "w"]),
+      kind => panic!("expected a lambda, got {:?}", kind)
+    }
+  }
+
+  #[test]
+  fn check_annotations_test() {
+    let program = parse_program("
+      struct Pair { a, b };
+      def f(x: number, p: Pair, q: Pair<str, [f64]>) -> (Pair<number, str>) -> str x;
+      extern g(x: str) -> [number];
+      def h(x: Point, p: Pair<number>) -> Pair<Pair, Vec> x;
+      extern k(p: Pair)
+    ").unwrap();
+
+    assert_eq!(check_program(&program[..3].to_vec(), &HashMap::new(), &Structs::default()), Ok(()));
+
+    let errors = check_program(&program, &HashMap::new(), &Structs::default()).unwrap_err();
+    let messages: Vec<String> = errors.iter().map(|err| err.to_string()).collect();
+
+    assert_eq!(messages, vec![
+      "unknown type `Point`",
+      "`Pair` takes 2 type argument(s) but 1 were given",
+      "unknown type `Vec`",
+      "`Pair` takes 2 type argument(s) but 0 were given"
+    ]);
+  }
+
+  #[test]
+  fn check_structs_test() {
+    let mut structs = Structs::default();
+    structs.declare("Point", &["x".to_string(), "y".to_string()]);
+
+    let program = parse_program("
+      struct Complex { re, im };
+      def norm(c) c.re * c.re + c.im * c.im;
+      def shift(p) p.x = p.y;
+      Point { x: 1, y: 2 }.x;
+      struct Pair { a, a };
+      Complex { re: 1, z: 2, re: 3 };
+      Vector { x: 1 }.w
+    ").unwrap();
+
+    assert_eq!(check_program(&program[..4].to_vec(), &HashMap::new(), &structs), Ok(()));
+
+    let errors = check_program(&program, &HashMap::new(), &structs).unwrap_err();
+    let messages: Vec<String> = errors.iter().map(|err| err.to_string()).collect();
+
+    assert_eq!(messages, vec![
+      "field `a` appears more than once",
+      "struct `Complex` has no field `z`",
+      "field `re` appears more than once",
+      "missing field `im` of struct `Complex`",
+      "unknown struct `Vector`",
+      "no struct has a field `w`"
+    ]);
+
+    // A field name may belong to several structs
+    structs.declare("Vector", &["y".to_string(), "x".to_string()]);
+    assert_eq!(structs.declaring("x"), vec!["Point", "Vector"]);
+    structs.declare("Point", &["x".to_string()]);
+    assert_eq!(structs.declaring("x"), vec!["Vector", "Point"]);
+    assert_eq!(structs.declaring("y"), vec!["Vector"]);
+    assert!(structs.declaring("w").is_empty());
+  }
+}